@@ -0,0 +1,34 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { DeciderVerdict } from "./DeciderVerdict";
+import type { NodeAllocation } from "./NodeAllocation";
+
+/**
+ * What `_cluster/allocation/explain` said about one shard copy.
+ */
+export type AllocationExplanation = { 
+/**
+ * e.g. `unassigned`, `initializing` or `relocating`
+ */
+current_state: string, 
+/**
+ * Why the copy became unassigned, e.g. `NODE_LEFT`
+ */
+unassigned_reason: string | null, unassigned_details: string | null, unassigned_at: string | null, 
+/**
+ * For unassigned copies, e.g. `yes`, `no`, `throttled` or
+ * `no_valid_shard_copy`
+ */
+can_allocate: string | null, 
+/**
+ * For assigned copies
+ */
+can_remain_on_current_node: string | null, explanation: string | null, 
+/**
+ * Deciders that don't let the copy stay on its current node
+ */
+can_remain_decisions: Array<DeciderVerdict>, nodes: Array<NodeAllocation>, 
+/**
+ * The `can_allocate` verdict, or the decider that said no on the
+ * most nodes, e.g. `same_shard` or `disk_threshold`
+ */
+root_cause: string, explained_at: string, };
@@ -1,3 +1,4 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Distribution } from "./Distribution";
 
-export type ClusterInfo = { cluster_name: string, status: string, timed_out: boolean, number_of_nodes: number, number_of_data_nodes: number, active_primary_shards: number, active_shards: number, relocating_shards: number, initializing_shards: number, unassigned_shards: number, delayed_unassigned_shards: number, number_of_pending_tasks: number, number_of_in_flight_fetch: number, task_max_waiting_in_queue_millis: bigint, active_shards_percent_as_number: number, };
+export type ClusterInfo = { cluster_name: string, status: string, timed_out: boolean, number_of_nodes: number, number_of_data_nodes: number, active_primary_shards: number, active_shards: number, relocating_shards: number, initializing_shards: number, unassigned_shards: number, delayed_unassigned_shards: number, number_of_pending_tasks: number, number_of_in_flight_fetch: number, task_max_waiting_in_queue_millis: number, active_shards_percent_as_number: number, distribution: Distribution | null, version: string | null, };
@@ -0,0 +1,15 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * Disk watermarks as configured: a share of the disk like `85%` or
+ * `0.85`, or the space to keep free like `500gb`.
+ */
+export type DiskWatermarks = { 
+/**
+ * `cluster.routing.allocation.disk.threshold_enabled`
+ */
+enabled: boolean, low: string, high: string, flood_stage: string, 
+/**
+ * Most free space a share watermark asks for, since 8.5
+ */
+low_max_headroom: string | null, high_max_headroom: string | null, flood_stage_max_headroom: string | null, };
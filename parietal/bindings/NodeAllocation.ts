@@ -0,0 +1,12 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { DeciderVerdict } from "./DeciderVerdict";
+
+export type NodeAllocation = { node_id: string, node_name: string, 
+/**
+ * e.g. `yes`, `no`, `throttled` or `worse_balance`
+ */
+decision: string, weight_ranking: number | null, 
+/**
+ * Only deciders that didn't say yes
+ */
+deciders: Array<DeciderVerdict>, };
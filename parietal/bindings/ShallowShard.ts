@@ -1,3 +1,8 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { AllocationExplanation } from "./AllocationExplanation";
 
-export type ShallowShard = { index: string, shard: string, prirep: string, state: string, docs: string | null, store: string | null, ip: string, node: string, };
+export type ShallowShard = { index: string, shard: string, prirep: string, state: string, docs: string | null, store: string | null, ip: string | null, node: string | null, 
+/**
+ * Why the copy is unassigned or stuck, when it has been explained
+ */
+allocation_explanation: AllocationExplanation | null, };
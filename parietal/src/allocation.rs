@@ -0,0 +1,306 @@
+use crate::config::AllocationExplainConfig;
+use crate::elastic::data::{AllocationExplanation, ShallowShard};
+use crate::elastic::source::ExplainRequest;
+use crate::history::seconds;
+
+use chrono::{DateTime, Utc};
+use juniper::GraphQLObject;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+
+#[derive(GraphQLObject, Serialize, Debug, Clone)]
+#[graphql(description = "Unassigned shard copies that share a root cause")]
+pub struct UnassignedCause {
+    /// Root cause of their explanations, `unexplained` until they have one
+    pub cause: String,
+    pub count: i32,
+    /// Explanation of one of the copies
+    pub explanation: Option<String>,
+    pub shards: Vec<ShallowShard>,
+}
+
+/// Groups the unassigned copies by root cause, most common first.
+pub fn unassigned_causes(shards: &[ShallowShard]) -> Vec<UnassignedCause> {
+    let mut groups: BTreeMap<&str, Vec<&ShallowShard>> = BTreeMap::new();
+    for shard in shards.iter().filter(|shard| shard.state == "UNASSIGNED") {
+        let cause = shard.allocation_explanation.as_ref().map_or("unexplained", |explanation| &explanation.root_cause);
+        groups.entry(cause).or_default().push(shard);
+    }
+
+    let mut causes: Vec<_> = groups.into_iter()
+        .map(|(cause, shards)| UnassignedCause {
+            cause: cause.to_string(),
+            count: i32::try_from(shards.len()).unwrap_or(i32::MAX),
+            explanation: shards.iter().find_map(|shard| shard.allocation_explanation.as_ref()?.explanation.clone()),
+            shards: shards.into_iter().cloned().collect(),
+        })
+        .collect();
+    causes.sort_by_key(|cause| std::cmp::Reverse(cause.count));
+    causes
+}
+
+/// A shard copy as `_cat/shards` describes it. Unassigned replicas of a
+/// shard can't be told apart, so they share one explanation.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CopyKey {
+    index: String,
+    shard: String,
+    prirep: String,
+    state: String,
+    node: Option<String>,
+}
+
+impl CopyKey {
+    fn of(shard: &ShallowShard) -> Self {
+        CopyKey {
+            index: shard.index.clone(),
+            shard: shard.shard.clone(),
+            prirep: shard.prirep.clone(),
+            state: shard.state.clone(),
+            node: shard.node.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Tracked {
+    /// When the copy was first seen in its current state.
+    since: DateTime<Utc>,
+    attempted_at: Option<DateTime<Utc>>,
+    explanation: Option<AllocationExplanation>,
+}
+
+/// Decides which shard copies to run allocation explain for, and keeps the
+/// answers while the copies stay the way they were explained.
+#[derive(Debug)]
+pub struct Explainer {
+    config: AllocationExplainConfig,
+    tracked: HashMap<CopyKey, Tracked>,
+}
+
+impl Explainer {
+    pub fn new(config: &AllocationExplainConfig) -> Self {
+        Explainer {
+            config: config.clone(),
+            tracked: HashMap::new(),
+        }
+    }
+
+    /// Time one pass over the due copies may take.
+    pub fn budget(&self) -> Duration {
+        Duration::from_millis(self.config.timeout_ms)
+    }
+
+    /// Copies to explain now: unassigned ones, and ones initializing or
+    /// relocating for longer than `stuck_after_secs`, that weren't tried in
+    /// the last `interval_secs`. The least recently tried go first, at most
+    /// `max_per_pass` of them. Copies no longer listed are forgotten.
+    pub fn due(&mut self, shards: &[ShallowShard]) -> Vec<(CopyKey, ExplainRequest)> {
+        if !self.config.enabled {
+            return Vec::new();
+        }
+
+        let now = Utc::now();
+        let stuck_after = seconds(self.config.stuck_after_secs);
+        let interval = seconds(self.config.interval_secs);
+
+        let mut present = HashSet::new();
+        let mut due = Vec::new();
+        for shard in shards.iter().filter(|shard| shard.state != "STARTED") {
+            let key = CopyKey::of(shard);
+            if !present.insert(key.clone()) {
+                continue;
+            }
+
+            let tracked = self.tracked.entry(key.clone())
+                .or_insert(Tracked { since: now, attempted_at: None, explanation: None });
+            let stuck = shard.state == "UNASSIGNED" || now - tracked.since >= stuck_after;
+            let recent = tracked.attempted_at.is_some_and(|at| now - at < interval);
+            if !stuck || recent {
+                continue;
+            }
+
+            let Ok(number) = shard.shard.parse() else {
+                continue;
+            };
+            let request = ExplainRequest {
+                index: shard.index.clone(),
+                shard: number,
+                primary: shard.prirep == "p",
+                current_node: (shard.state != "UNASSIGNED").then(|| shard.current_node().map(str::to_string)).flatten(),
+            };
+            due.push((tracked.attempted_at, key, request));
+        }
+        self.tracked.retain(|key, _| present.contains(key));
+
+        due.sort_by_key(|(attempted_at, _, _)| *attempted_at);
+        due.into_iter()
+            .take(self.config.max_per_pass)
+            .map(|(_, key, request)| (key, request))
+            .collect()
+    }
+
+    /// Notes an attempt to explain `key`. A failed attempt keeps the
+    /// previous explanation.
+    pub fn record(&mut self, key: &CopyKey, explanation: Option<AllocationExplanation>) {
+        if let Some(tracked) = self.tracked.get_mut(key) {
+            tracked.attempted_at = Some(Utc::now());
+            if explanation.is_some() {
+                tracked.explanation = explanation;
+            }
+        }
+    }
+
+    /// Sets the latest explanation on every copy that has one.
+    pub fn attach(&self, shards: &mut [ShallowShard]) {
+        for shard in shards.iter_mut().filter(|shard| shard.state != "STARTED") {
+            shard.allocation_explanation = self.tracked.get(&CopyKey::of(shard))
+                .and_then(|tracked| tracked.explanation.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elastic::data::ExplainResponse;
+    use serde_json::json;
+
+    /// What Elasticsearch 8.13 answers for the unassigned replica in the
+    /// fixtures, after its node left.
+    fn explain_response() -> serde_json::Value {
+        json!({
+            "index": "logs-2024.06.01",
+            "shard": 2,
+            "primary": false,
+            "current_state": "unassigned",
+            "unassigned_info": {
+                "reason": "NODE_LEFT",
+                "at": "2024-06-01T10:15:30.123Z",
+                "details": "node_left [Zr4kT0uWQ9iVn2bHc8dXyA]",
+                "last_allocation_status": "no_attempt"
+            },
+            "can_allocate": "no",
+            "allocate_explanation": "Elasticsearch isn't allowed to allocate this shard to any of the nodes in the cluster. Choose a node to which you expect this shard to be allocated, find this node in the node-by-node explanation, and address the reasons which prevent Elasticsearch from allocating this shard there.",
+            "node_allocation_decisions": [
+                {
+                    "node_id": "p3Nq8RfVTt2xGmC5yLk0Ew",
+                    "node_name": "es8-node-3",
+                    "transport_address": "10.0.1.13:9300",
+                    "node_attributes": { "xpack.installed": "true" },
+                    "roles": ["data", "ingest", "master"],
+                    "node_decision": "no",
+                    "weight_ranking": 1,
+                    "deciders": [{
+                        "decider": "same_shard",
+                        "decision": "NO",
+                        "explanation": "a copy of this shard is already allocated to this node [[logs-2024.06.01][2], node[p3Nq8RfVTt2xGmC5yLk0Ew], [P], s[STARTED], a[id=Yl3cB0sTQk2dW9pHxV4mOg], failed_attempts[0]]"
+                    }]
+                },
+                {
+                    "node_id": "Q1wB3cTvSX6sYJ1mV4fLzg",
+                    "node_name": "es8-node-1",
+                    "transport_address": "10.0.1.11:9300",
+                    "node_attributes": { "xpack.installed": "true" },
+                    "roles": ["data", "ingest", "master"],
+                    "node_decision": "no",
+                    "weight_ranking": 2,
+                    "deciders": [{
+                        "decider": "disk_threshold",
+                        "decision": "NO",
+                        "explanation": "the node is above the high watermark cluster setting [cluster.routing.allocation.disk.watermark.high=90%], having less than the minimum required [9.8gb] free space, actual free: [7.8gb], actual used: [92.0%]"
+                    }]
+                },
+                {
+                    "node_id": "hX0b2Yt7QmKz1LwP9sJd4A",
+                    "node_name": "es8-node-2",
+                    "transport_address": "10.0.1.12:9300",
+                    "node_attributes": { "xpack.installed": "true" },
+                    "roles": ["data", "ingest", "master"],
+                    "node_decision": "no",
+                    "weight_ranking": 3,
+                    "deciders": [
+                        {
+                            "decider": "disk_threshold",
+                            "decision": "NO",
+                            "explanation": "the node is above the high watermark cluster setting [cluster.routing.allocation.disk.watermark.high=90%], having less than the minimum required [9.8gb] free space, actual free: [6.1gb], actual used: [93.8%]"
+                        },
+                        {
+                            "decider": "throttling",
+                            "decision": "THROTTLE",
+                            "explanation": "reached the limit of incoming shard recoveries [2], cluster setting [cluster.routing.allocation.node_concurrent_incoming_recoveries=2] (can also be set via [cluster.routing.allocation.node_concurrent_recoveries])"
+                        }
+                    ]
+                }
+            ]
+        })
+    }
+
+    fn unassigned_replica() -> ShallowShard {
+        ShallowShard {
+            index: "logs-2024.06.01".to_string(),
+            shard: "2".to_string(),
+            prirep: "r".to_string(),
+            state: "UNASSIGNED".to_string(),
+            docs: None,
+            store: None,
+            ip: None,
+            node: None,
+            allocation_explanation: None,
+        }
+    }
+
+    #[test]
+    fn reads_an_allocation_explain_response() {
+        let response: ExplainResponse = serde_json::from_value(explain_response()).unwrap();
+        let explained_at = Utc::now();
+        let explanation = response.into_explanation(explained_at);
+
+        assert_eq!(explanation.current_state, "unassigned");
+        assert_eq!(explanation.unassigned_reason.as_deref(), Some("NODE_LEFT"));
+        assert_eq!(explanation.unassigned_details.as_deref(), Some("node_left [Zr4kT0uWQ9iVn2bHc8dXyA]"));
+        assert_eq!(explanation.unassigned_at.as_deref(), Some("2024-06-01T10:15:30.123Z"));
+        assert_eq!(explanation.can_allocate.as_deref(), Some("no"));
+        assert!(explanation.explanation.unwrap().starts_with("Elasticsearch isn't allowed to allocate"));
+        assert_eq!(explanation.explained_at, explained_at);
+
+        let nodes: Vec<_> = explanation.nodes.iter()
+            .map(|node| (node.node_name.as_str(), node.decision.as_str(), node.weight_ranking, node.deciders.len()))
+            .collect();
+        assert_eq!(nodes, [("es8-node-3", "no", Some(1), 1), ("es8-node-1", "no", Some(2), 1), ("es8-node-2", "no", Some(3), 2)]);
+        assert_eq!(explanation.nodes[2].deciders[1].decision, "THROTTLE");
+
+        // Refused by the disk on two nodes, by the copy already there on one
+        assert_eq!(explanation.root_cause, "disk_threshold");
+    }
+
+    #[test]
+    fn explanations_are_kept_until_the_interval_passes() {
+        let config: AllocationExplainConfig = serde_json::from_value(json!({ "interval_secs": 60 })).unwrap();
+        let mut explainer = Explainer::new(&config);
+        let mut shards = vec![unassigned_replica()];
+
+        let due = explainer.due(&shards);
+        assert_eq!(due.len(), 1);
+        let (key, request) = &due[0];
+        assert_eq!((request.index.as_str(), request.shard, request.primary), ("logs-2024.06.01", 2, false));
+
+        let response: ExplainResponse = serde_json::from_value(explain_response()).unwrap();
+        explainer.record(key, Some(response.into_explanation(Utc::now())));
+        assert!(explainer.due(&shards).is_empty(), "explained moments ago");
+
+        // A failed attempt doesn't lose the explanation
+        explainer.tracked.get_mut(key).unwrap().attempted_at = Some(Utc::now() - seconds(60));
+        assert_eq!(explainer.due(&shards).len(), 1);
+        explainer.record(key, None);
+        assert!(explainer.due(&shards).is_empty());
+
+        explainer.attach(&mut shards);
+        assert_eq!(shards[0].allocation_explanation.as_ref().unwrap().root_cause, "disk_threshold");
+
+        // Copies no longer listed are forgotten
+        assert!(explainer.due(&[]).is_empty());
+        assert!(explainer.tracked.is_empty());
+    }
+}
@@ -1,7 +1,11 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use juniper::GraphQLObject;
 use figment::{providers::{Format, Env, Json, Toml, Yaml}, Figment};
+use crate::elastic::version::Distribution;
 
 const DEFAULT_CONFIG: &str = include_str!("../.config/config.toml");
 
@@ -9,13 +13,524 @@ const DEFAULT_CONFIG: &str = include_str!("../.config/config.toml");
 pub struct Config {
     pub port: u16,
     pub demo: bool,
+    /// Fixture directory with the demo dataset, read at startup. The
+    /// embedded demo data is used when unset.
+    pub demo_data_dir: Option<PathBuf>,
+    /// Directory for state kept across restarts, such as the snapshot
+    /// history, with one subdirectory per cluster. Nothing is persisted
+    /// when unset.
+    pub data_dir: Option<PathBuf>,
     pub elastic: ElasticConfig,
+    /// Named clusters to monitor. When empty, `elastic` is used as the only
+    /// cluster, named "default".
+    #[serde(default)]
+    pub clusters: Vec<ClusterConfig>,
+    #[serde(default)]
+    pub mock: MockConfig,
     pub ui: UiConfig,
 }
 
+impl Config {
+    pub fn clusters(&self) -> Vec<ClusterConfig> {
+        match self.clusters.is_empty() {
+            true => vec![ClusterConfig {
+                name: "default".to_string(),
+                elastic: self.elastic.clone(),
+            }],
+            false => self.clusters.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ClusterConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub elastic: ElasticConfig,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ElasticConfig {
+    #[serde(default)]
     pub url: String,
+    /// Seed hosts to fail over between. Takes precedence over `url`.
+    #[serde(default)]
+    pub urls: Vec<String>,
+    /// Discover HTTP-enabled nodes from `_nodes/http` and add them to the
+    /// seed hosts.
+    #[serde(default)]
+    pub sniff: bool,
+    #[serde(default = "default_sniff_interval_secs")]
+    pub sniff_interval_secs: u64,
+    /// List hidden indices (e.g. `.security`) in `_cat/indices` too, on
+    /// versions that have them.
+    #[serde(default)]
+    pub include_hidden_indices: bool,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
+    pub polling: PollingConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    #[serde(default)]
+    pub events: EventsConfig,
+    #[serde(default)]
+    pub allocation_explain: AllocationExplainConfig,
+    /// Where the data comes from. The global `demo` flag overrides this.
+    #[serde(default)]
+    pub source: SourceKind,
+    /// Directory of response fixtures, for the `fixtures` source.
+    pub fixture_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub record: RecordConfig,
+    #[serde(default)]
+    pub replay: ReplayConfig,
+    #[serde(default)]
+    pub simulator: SimulatorConfig,
+}
+
+/// Captures every live response into `dir`, one subdirectory per refresh
+/// holding the resources fetched in it. Allocation explains run outside
+/// refreshes and are left out. A subdirectory from a refresh of every
+/// resource (e.g. the first) can be used as a `fixture_dir`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RecordConfig {
+    pub dir: Option<PathBuf>,
+    /// Replace hostnames, IP addresses, node names and the cluster name
+    /// with stable placeholders.
+    #[serde(default)]
+    pub scrub: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceKind {
+    #[default]
+    Live,
+    Demo,
+    Fixtures,
+    Replay,
+    Simulator,
+}
+
+impl ElasticConfig {
+    pub fn seeds(&self) -> Vec<String> {
+        match self.urls.is_empty() {
+            true => vec![self.url.clone()],
+            false => self.urls.clone(),
+        }
+    }
+}
+
+fn default_sniff_interval_secs() -> u64 {
+    300
+}
+
+/// Credentials for the Elasticsearch connection. At most one of basic auth,
+/// API key or bearer token may be configured. Every secret can also be read
+/// from a file, e.g. a mounted Kubernetes secret.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AuthConfig {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+    /// Either the base64 encoded `id:key` pair, or just the key when
+    /// `api_key_id` is set.
+    pub api_key: Option<String>,
+    pub api_key_id: Option<String>,
+    pub api_key_file: Option<PathBuf>,
+    pub bearer_token: Option<String>,
+    pub bearer_token_file: Option<PathBuf>,
+}
+
+/// TLS options for the Elasticsearch connection. When nothing is set the
+/// client trusts the bundled webpki roots, like before.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TlsConfig {
+    /// PEM bundle of additional trusted CA certificates.
+    pub ca_file: Option<PathBuf>,
+    /// PEM client certificate chain, used together with `client_key_file`.
+    pub client_cert_file: Option<PathBuf>,
+    pub client_key_file: Option<PathBuf>,
+    /// PKCS#12 archive holding the client certificate chain and key.
+    pub client_pkcs12_file: Option<PathBuf>,
+    pub client_pkcs12_password: Option<String>,
+    /// SHA-256 fingerprints (hex, colons optional). When set, the server
+    /// must still pass the usual verification against `ca_file` and the
+    /// bundled roots, and its leaf certificate must either match one of
+    /// these or be issued by a matching certificate, sent by the server or
+    /// found in `ca_file`.
+    #[serde(default)]
+    pub pinned_fingerprints: Vec<String>,
+    /// Disable certificate verification entirely. Never use in production.
+    #[serde(default)]
+    pub insecure: bool,
+}
+
+/// Plays back recorded snapshots for the `replay` source.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ReplayConfig {
+    /// A record directory, or a `.tar`/`.tar.gz` archive of one.
+    pub path: Option<PathBuf>,
+    /// Replay time per wall-clock time, e.g. 10.0 plays an hour in 6 minutes.
+    pub speed: f64,
+    /// Start over after the last snapshot instead of stopping there.
+    pub loop_playback: bool,
+    pub start_paused: bool,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        ReplayConfig {
+            path: None,
+            speed: 1.0,
+            loop_playback: true,
+            start_paused: false,
+        }
+    }
+}
+
+/// Shape and behaviour of the synthetic cluster generated by the
+/// `simulator` source. Every refresh advances the simulation by one tick.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SimulatorConfig {
+    pub cluster_name: String,
+    pub nodes: usize,
+    /// Node attributes and the values to spread across nodes, e.g.
+    /// `availability_zone = ["zone-a", "zone-b"]`.
+    pub attributes: BTreeMap<String, Vec<String>>,
+    /// Attribute that indices are pinned to, like a tiered setup using
+    /// `index.routing.allocation.require.storage_type`.
+    pub tier_attribute: Option<String>,
+    pub disk_per_node_gb: u64,
+    pub indices: usize,
+    pub shards_per_index: u32,
+    pub replicas: u32,
+    pub min_shard_size_mb: u64,
+    pub max_shard_size_mb: u64,
+    /// Rebalancing relocations started per tick.
+    pub relocations_per_tick: usize,
+    pub max_concurrent_recoveries: usize,
+    pub recovery_mb_per_tick: u64,
+    /// Chance per tick that a node drops out of the cluster.
+    pub node_failure_chance: f64,
+    /// How many ticks a failed node stays away.
+    pub node_down_ticks: u32,
+    /// Seed for reproducible runs.
+    pub seed: Option<u64>,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        SimulatorConfig {
+            cluster_name: "simulated-cluster".to_string(),
+            nodes: 30,
+            attributes: BTreeMap::from([
+                ("availability_zone".to_string(), vec!["zone-a".to_string(), "zone-b".to_string(), "zone-c".to_string()]),
+                ("storage_type".to_string(), vec!["hot".to_string(), "warm".to_string()]),
+            ]),
+            tier_attribute: Some("storage_type".to_string()),
+            disk_per_node_gb: 2_048,
+            indices: 100,
+            shards_per_index: 5,
+            replicas: 1,
+            min_shard_size_mb: 100,
+            max_shard_size_mb: 50_000,
+            relocations_per_tick: 2,
+            max_concurrent_recoveries: 20,
+            recovery_mb_per_tick: 2_000,
+            node_failure_chance: 0.02,
+            node_down_ticks: 12,
+            seed: None,
+        }
+    }
+}
+
+/// Built-in mock Elasticsearch server, for integration tests and offline
+/// development. Point a cluster's `url` at it to run the real client against
+/// fixture or simulator data.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MockConfig {
+    pub enabled: bool,
+    /// Address to listen on. Only reachable from this machine by default,
+    /// set e.g. `0.0.0.0` to expose it.
+    pub bind_address: IpAddr,
+    /// Port to listen on, any free one when 0.
+    pub port: u16,
+    pub source: MockSource,
+    /// Fixtures to serve. The embedded demo data is used when unset.
+    /// `_cat_allocation.json` and `_cluster_settings.json` are served too
+    /// when the directory has them, and derived from the nodes otherwise.
+    pub fixture_dir: Option<PathBuf>,
+    pub simulator: SimulatorConfig,
+    /// Seconds between simulator ticks.
+    pub tick_secs: u64,
+    /// Version and distribution reported by `GET /`.
+    pub version: String,
+    pub distribution: Distribution,
+    /// Credentials clients must send. Open when nothing is set.
+    pub auth: AuthConfig,
+    /// Delay added to every response, e.g. to exercise client timeouts.
+    pub latency_ms: u64,
+    /// Share of requests answered with `error_status`, from 0.0 to 1.0.
+    pub error_rate: f64,
+    pub error_status: u16,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        MockConfig {
+            enabled: false,
+            bind_address: Ipv4Addr::LOCALHOST.into(),
+            port: 9200,
+            source: MockSource::default(),
+            fixture_dir: None,
+            simulator: SimulatorConfig::default(),
+            tick_secs: 5,
+            version: "8.14.3".to_string(),
+            distribution: Distribution::Elasticsearch,
+            auth: AuthConfig::default(),
+            latency_ms: 0,
+            error_rate: 0.0,
+            error_status: 503,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MockSource {
+    #[default]
+    Fixtures,
+    Simulator,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    pub connect_ms: u64,
+    pub request_ms: u64,
+    /// Request timeout overrides keyed by resource: `health`, `indices`,
+    /// `recovery`, `shards` or `nodes`.
+    pub endpoints: HashMap<String, u64>,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            connect_ms: 5_000,
+            request_ms: 10_000,
+            endpoints: HashMap::new(),
+        }
+    }
+}
+
+/// How often each resource is refreshed. Intervals adapt to the cluster:
+/// faster while shards are moving, slower when nothing happens or nobody
+/// is looking, and backed off while the master is struggling.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PollingConfig {
+    pub interval_ms: u64,
+    /// Interval overrides keyed by resource: `health`, `indices`,
+    /// `recovery`, `shards` or `nodes`.
+    pub resources: HashMap<String, u64>,
+    /// Used for health, recovery and shards while shards are relocating,
+    /// initializing or unassigned.
+    pub active_interval_ms: u64,
+    /// Intervals are multiplied by this while the cluster is green and no
+    /// shards are moving.
+    pub idle_factor: f64,
+    /// Intervals are raised to at least `unwatched_interval_ms` when no
+    /// client has made a request for `unwatched_after_secs`. Open
+    /// subscriptions and streams always count as watching.
+    pub unwatched_after_secs: u64,
+    pub unwatched_interval_ms: u64,
+    /// Intervals are multiplied by `queue_backoff_factor` while the
+    /// cluster reports a `task_max_waiting_in_queue_millis` above this.
+    pub queue_backoff_threshold_ms: u64,
+    pub queue_backoff_factor: f64,
+    pub max_interval_ms: u64,
+    /// Minimum time between on-demand refreshes, see `Warehouse::request_refresh`.
+    pub min_refresh_interval_ms: u64,
+    /// Resources not fetched successfully for this long are reported as
+    /// stale.
+    pub stale_after_ms: u64,
+}
+
+impl Default for PollingConfig {
+    fn default() -> Self {
+        PollingConfig {
+            interval_ms: 5_000,
+            resources: HashMap::from([("nodes".to_string(), 15_000)]),
+            active_interval_ms: 1_000,
+            idle_factor: 2.0,
+            unwatched_after_secs: 120,
+            unwatched_interval_ms: 60_000,
+            queue_backoff_threshold_ms: 5_000,
+            queue_backoff_factor: 4.0,
+            max_interval_ms: 300_000,
+            min_refresh_interval_ms: 1_000,
+            stale_after_ms: 60_000,
+        }
+    }
+}
+
+/// Past snapshots kept in memory for time-travel queries. Resources that
+/// didn't change are shared between snapshots, but the shard list changes
+/// on nearly every poll of a cluster taking writes, as it includes doc
+/// counts and store sizes. Expect a full copy of `_cat/shards` for each of
+/// the `retention_secs / interval_secs` snapshots kept; raise
+/// `interval_secs` or lower `retention_secs` for clusters with many shards.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// How far back to keep snapshots, 0 to disable history.
+    pub retention_secs: u64,
+    /// Minimum time between kept snapshots.
+    pub interval_secs: u64,
+    /// History written to `data_dir`, see `PersistConfig`.
+    pub persist: PersistConfig,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            retention_secs: 86_400,
+            interval_secs: 60,
+            persist: PersistConfig::default(),
+        }
+    }
+}
+
+/// Change events derived from consecutive snapshots.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EventsConfig {
+    /// Number of events kept, the oldest are dropped first.
+    pub max_events: usize,
+    /// Disk usage levels that raise an event when a node crosses them,
+    /// the Elasticsearch watermark defaults unless set.
+    pub disk_thresholds_percent: Vec<f64>,
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        EventsConfig {
+            max_events: 10_000,
+            disk_thresholds_percent: vec![85.0, 90.0, 95.0],
+        }
+    }
+}
+
+/// Calls to `_cluster/allocation/explain` for shards that are unassigned
+/// or stuck. They run in the background after each published snapshot and
+/// never hold up a refresh; answers show up with the next shards fetched.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AllocationExplainConfig {
+    pub enabled: bool,
+    /// Most shards explained per pass, the rest wait for later ones.
+    pub max_per_pass: usize,
+    /// Time one pass may take in total. Shards not explained by then wait
+    /// for the next pass.
+    pub timeout_ms: u64,
+    /// Minimum time before the same shard is explained again.
+    pub interval_secs: u64,
+    /// How long a shard has to be initializing or relocating to count as
+    /// stuck.
+    pub stuck_after_secs: u64,
+}
+
+impl Default for AllocationExplainConfig {
+    fn default() -> Self {
+        AllocationExplainConfig {
+            enabled: true,
+            max_per_pass: 5,
+            timeout_ms: 10_000,
+            interval_secs: 60,
+            stuck_after_secs: 300,
+        }
+    }
+}
+
+/// Snapshots written to `data_dir`. Only resources that changed since the
+/// previous written snapshot are stored, and days older than
+/// `downsample_after_secs` are thinned out to one snapshot per
+/// `downsample_interval_secs`. Metrics derived from the snapshots are
+/// written every `metrics_interval_secs` and never thinned out.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PersistConfig {
+    /// How far back to keep snapshots on disk. Snapshots are stored in a
+    /// file per day, removed once the whole day is past retention.
+    pub retention_secs: u64,
+    /// Minimum time between written snapshots.
+    pub interval_secs: u64,
+    pub metrics_interval_secs: u64,
+    pub downsample_after_secs: u64,
+    pub downsample_interval_secs: u64,
+}
+
+impl Default for PersistConfig {
+    fn default() -> Self {
+        PersistConfig {
+            retention_secs: 30 * 86_400,
+            interval_secs: 300,
+            metrics_interval_secs: 15,
+            downsample_after_secs: 7 * 86_400,
+            downsample_interval_secs: 3_600,
+        }
+    }
+}
+
+/// Retries for failed GETs, with full-jitter exponential backoff.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    pub max_retries: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: 2,
+            initial_backoff_ms: 200,
+            max_backoff_ms: 2_000,
+        }
+    }
+}
+
+/// Stops sending requests after `failure_threshold` consecutive refreshes
+/// with failed requests, letting a single probe through once `open_secs`
+/// have passed. Requests failing together in one refresh count once.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    pub failure_threshold: u32,
+    pub open_secs: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failure_threshold: 5,
+            open_secs: 30,
+        }
+    }
 }
 
 #[derive(GraphQLObject, Clone, Debug, Deserialize, Serialize)]
@@ -47,8 +562,15 @@ pub fn read_config() -> Result<Config> {
         .merge(Json::file("config.json"))
         .merge(Yaml::file("config.yaml"))
         .merge(Toml::file("config.toml"))
-        .merge(Env::prefixed("CORTEX_"))
+        .merge(Env::prefixed("CORTEX_").split("__"))
         .extract()?;
 
+    let mut names = std::collections::HashSet::new();
+    for cluster in &config.clusters {
+        if !names.insert(&cluster.name) {
+            anyhow::bail!("Duplicate cluster name: {}", cluster.name);
+        }
+    }
+
     Ok(config)
 }
@@ -1,91 +1,946 @@
-use crate::elastic::data::{ClusterInfo, NodeOutput, Recovery, ShallowShard, IndexInfo};
+use crate::elastic::data::{ClusterInfo, DiskAllocation, NodeOutput, Recovery, ShallowShard, IndexInfo};
 use crate::elastic::client::ElasticsearchClient;
+use crate::elastic::fixture::FixtureSource;
+use crate::elastic::replay::ReplaySource;
+use crate::elastic::simulator::SimulatorSource;
+use crate::elastic::source::{ClusterSource, Resource};
+use crate::elastic::breaker::BreakerStatus;
+use crate::elastic::error::{ElasticError, ErrorInfo};
+use crate::allocation::Explainer;
 use crate::config;
-use anyhow::Result;
+use crate::events::{ClusterEvent, EventLog, EventType};
+use crate::history::{History, HistoryInfo, SnapshotMetrics};
+use crate::store::HistoryStore;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use juniper::GraphQLObject;
+use serde::Serialize;
 use log::debug;
 use tokio::sync::RwLock;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use once_cell::sync::OnceCell;
+use std::time::Duration;
+use once_cell::sync::{Lazy, OnceCell};
+use tokio::sync::{watch, Mutex, Notify};
 
-pub static WAREHOUSE: OnceCell<std::sync::Arc<tokio::sync::RwLock<Warehouse>>> = OnceCell::new();
+pub type SharedWarehouse = Arc<RwLock<Warehouse>>;
+
+/// One warehouse per configured cluster, in config order.
+pub static WAREHOUSES: OnceCell<Vec<(String, SharedWarehouse)>> = OnceCell::new();
 pub static CONFIG: OnceCell<config::Config> = OnceCell::new();
 
+/// When the last API request was made, to slow down polling when nobody
+/// is looking. Follows the paused clock in tests.
+static LAST_CLIENT_SEEN: Lazy<std::sync::Mutex<Option<tokio::time::Instant>>> = Lazy::new(Default::default);
+
+/// Wakes refresh loops that slowed down for lack of clients.
+static CLIENT_SEEN: Lazy<Notify> = Lazy::new(Notify::new);
+
+pub fn client_seen() {
+    *LAST_CLIENT_SEEN.lock().unwrap() = Some(tokio::time::Instant::now());
+    CLIENT_SEEN.notify_waiters();
+}
+
+/// Open subscriptions and streams, see `Watcher`.
+static WATCHERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Held by a subscription or stream for as long as it is open. Polling
+/// doesn't slow down while any is held, however rarely it receives.
+#[derive(Debug)]
+pub struct Watcher(());
+
+impl Watcher {
+    pub fn start() -> Self {
+        WATCHERS.fetch_add(1, Ordering::Relaxed);
+        client_seen();
+        Watcher(())
+    }
+}
+
+impl Drop for Watcher {
+    /// Counts as seen, so polling slows down `unwatched_after_secs` after
+    /// the last one closes rather than after it opened.
+    fn drop(&mut self) {
+        WATCHERS.fetch_sub(1, Ordering::Relaxed);
+        client_seen();
+    }
+}
+
+fn clients_idle_for() -> Duration {
+    if WATCHERS.load(Ordering::Relaxed) > 0 {
+        return Duration::ZERO;
+    }
+
+    match *LAST_CLIENT_SEEN.lock().unwrap() {
+        Some(seen) => tokio::time::Instant::now().saturating_duration_since(seen),
+        None => Duration::MAX,
+    }
+}
+
+/// Looks up a cluster's warehouse by name, or the first configured cluster
+/// when no name is given.
+pub fn warehouse(name: Option<&str>) -> Option<SharedWarehouse> {
+    let warehouses = WAREHOUSES.get()?;
+
+    match name {
+        Some(name) => warehouses.iter().find(|(n, _)| n == name).map(|(_, w)| w.clone()),
+        None => warehouses.first().map(|(_, w)| w.clone()),
+    }
+}
+
+#[derive(GraphQLObject, Serialize, Debug, Clone, Default)]
+#[graphql(description = "State of the connection between Cortex and the cluster")]
+pub struct ConnectionStatus {
+    pub connected: bool,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_error: Option<ErrorInfo>,
+    pub last_error_at: Option<DateTime<Utc>>,
+    pub consecutive_failures: i32,
+    pub circuit_breaker: BreakerStatus,
+}
+
+impl ConnectionStatus {
+    fn record_success(&mut self) {
+        self.connected = true;
+        self.last_success = Some(Utc::now());
+        self.consecutive_failures = 0;
+    }
+
+    fn record_failure(&mut self, error: &ElasticError) {
+        self.connected = false;
+        self.last_error = Some(error.info());
+        self.last_error_at = Some(Utc::now());
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+    }
+}
+
+/// The cluster state as of one refresh. It is published as a whole and
+/// never modified afterwards, so readers can't mix data from different
+/// polls. Resources that weren't due in a refresh are carried over from
+/// the previous snapshot.
+#[derive(Debug)]
+pub struct Snapshot {
+    /// Incremented on every successful refresh, 0 until the first one.
+    pub generation: u64,
+    /// When the fetches for this snapshot were issued.
+    pub fetched_at: Option<DateTime<Utc>>,
+    /// When each resource was last fetched, as resources are carried over
+    /// from earlier snapshots.
+    pub fetched: HashMap<Resource, DateTime<Utc>>,
+    pub cluster: Arc<ClusterInfo>,
+    pub indices: Arc<Vec<IndexInfo>>,
+    pub recovery: Arc<Recovery>,
+    pub shards: Arc<Vec<ShallowShard>>,
+    pub nodes: Arc<NodeOutput>,
+    /// Fetched with the nodes, when the source reports it.
+    pub disk: Arc<DiskAllocation>,
+}
+
+impl Snapshot {
+    pub(crate) fn empty() -> Self {
+        Snapshot {
+            generation: 0,
+            fetched_at: None,
+            fetched: HashMap::new(),
+            cluster: Arc::new(ClusterInfo::unknown()),
+            indices: Arc::default(),
+            recovery: Arc::default(),
+            shards: Arc::default(),
+            nodes: Arc::default(),
+            disk: Arc::default(),
+        }
+    }
+
+    pub fn info(&self) -> SnapshotInfo {
+        SnapshotInfo {
+            generation: i32::try_from(self.generation).unwrap_or(i32::MAX),
+            fetched_at: self.fetched_at,
+        }
+    }
+}
+
+#[derive(GraphQLObject, Serialize, Debug, Clone)]
+#[graphql(description = "Which refresh the served data comes from")]
+pub struct SnapshotInfo {
+    pub generation: i32,
+    pub fetched_at: Option<DateTime<Utc>>,
+}
+
+#[derive(GraphQLObject, Serialize, Debug, Clone)]
+#[graphql(description = "How current the served data of one resource is")]
+pub struct Freshness {
+    pub resource: Resource,
+    /// None until the resource has been fetched once
+    pub fetched_at: Option<DateTime<Utc>>,
+    pub age_seconds: Option<f64>,
+    /// Error of the last attempt to fetch the resource, cleared once it
+    /// succeeds again
+    pub last_error: Option<ErrorInfo>,
+    pub last_error_at: Option<DateTime<Utc>>,
+    /// Older than `polling.stale_after_ms`, or never fetched
+    pub is_stale: bool,
+}
+
+/// On-demand refresh requests. They are collected here and served by the
+/// refresh loop, so concurrent requests share a single refresh.
+#[derive(Debug)]
+struct RefreshTrigger {
+    pending: std::sync::Mutex<PendingRefresh>,
+    notify: Notify,
+    done: watch::Sender<RefreshOutcome>,
+}
+
+#[derive(Debug)]
+struct PendingRefresh {
+    resources: HashSet<Resource>,
+    /// The refresh run that will serve the pending resources.
+    run: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct RefreshOutcome {
+    run: u64,
+    error: Option<ErrorInfo>,
+}
+
+impl RefreshTrigger {
+    fn new() -> Self {
+        RefreshTrigger {
+            pending: std::sync::Mutex::new(PendingRefresh { resources: HashSet::new(), run: 1 }),
+            notify: Notify::new(),
+            done: watch::Sender::new(RefreshOutcome::default()),
+        }
+    }
+
+    /// Takes the pending resources, along with the number of the run
+    /// serving them.
+    fn take(&self) -> (HashSet<Resource>, u64) {
+        let mut pending = self.pending.lock().unwrap();
+        let run = pending.run;
+        pending.run += 1;
+        (std::mem::take(&mut pending.resources), run)
+    }
+}
+
 #[derive(Debug)]
 pub struct Warehouse {
-    pub client: ElasticsearchClient,
-    pub cluster: Arc<RwLock<ClusterInfo>>,
-    pub indices: Arc<RwLock<Vec<IndexInfo>>>,
-    pub recovery: Arc<RwLock<Recovery>>,
-    pub shards: Arc<RwLock<Vec<ShallowShard>>>,
-    pub nodes: Arc<RwLock<NodeOutput>>,
+    pub source: Box<dyn ClusterSource>,
+    pub snapshot: Arc<RwLock<Arc<Snapshot>>>,
+    /// Sent every published snapshot, for subscriptions.
+    published: watch::Sender<Arc<Snapshot>>,
+    pub status: Arc<RwLock<ConnectionStatus>>,
+    /// Last error per resource, for resources whose last fetch failed.
+    failures: RwLock<HashMap<Resource, (ErrorInfo, DateTime<Utc>)>>,
+    history: RwLock<History>,
+    store: Option<Arc<HistoryStore>>,
+    events: RwLock<EventLog>,
+    explainer: Mutex<Explainer>,
+    polling: config::PollingConfig,
+    trigger: RefreshTrigger,
 }
 
 impl Warehouse {
-    pub async fn new(base_url: &str) -> Self {
-        let client = ElasticsearchClient::new(base_url);
-        let nodes = client.nodes().await.unwrap();
-        let cluster = client.health().await.unwrap();
-        let recovery = client.recovery().await.unwrap();
-        let shards = client.shards().await.unwrap();
-        let indices = client.indices().await.unwrap();
+    /// Creates an empty warehouse. Nothing is fetched here, so a cluster that
+    /// is down at startup only shows up as a disconnected status.
+    pub fn new(name: &str, elastic: &config::ElasticConfig) -> Result<Self> {
+        let demo = CONFIG.get().map(|config| config.demo).unwrap_or(false);
+        let demo_data_dir = CONFIG.get().and_then(|config| config.demo_data_dir.as_deref());
+        let data_dir = CONFIG.get().and_then(|config| config.data_dir.as_deref());
+
+        let source: Box<dyn ClusterSource> = match (demo, elastic.source) {
+            (true, _) | (false, config::SourceKind::Demo) => Box::new(FixtureSource::demo(demo_data_dir)?),
+            (false, config::SourceKind::Fixtures) => {
+                let dir = elastic.fixture_dir.as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("elastic.fixture_dir is required for the fixtures source"))?;
+                Box::new(FixtureSource::from_dir(dir)?)
+            }
+            (false, config::SourceKind::Replay) => Box::new(ReplaySource::new(&elastic.replay)?),
+            (false, config::SourceKind::Simulator) => Box::new(SimulatorSource::new(&elastic.simulator)?),
+            (false, config::SourceKind::Live) => Box::new(ElasticsearchClient::new(elastic)?),
+        };
+
+        // Cluster names may contain anything, directory names may not
+        let directory: String = name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+            .collect();
+        let store = data_dir
+            .map(|dir| HistoryStore::open(&dir.join(directory), &elastic.history.persist))
+            .transpose()?;
+
+        Ok(Self::with_source(source, elastic, store))
+    }
+
+    pub fn with_source(source: Box<dyn ClusterSource>, elastic: &config::ElasticConfig, store: Option<Arc<HistoryStore>>) -> Self {
+        let snapshot = Arc::new(Snapshot::empty());
 
         Warehouse {
-            client,
-            cluster: Arc::new(RwLock::new(cluster)),
-            indices: Arc::new(RwLock::new(indices)),
-            recovery: Arc::new(RwLock::new(recovery)),
-            shards: Arc::new(RwLock::new(shards)),
-            nodes: Arc::new(RwLock::new(nodes)),
+            published: watch::Sender::new(snapshot.clone()),
+            store,
+            events: RwLock::new(EventLog::new(&elastic.events)),
+            explainer: Mutex::new(Explainer::new(&elastic.allocation_explain)),
+            source,
+            polling: elastic.polling.clone(),
+            trigger: RefreshTrigger::new(),
+            snapshot: Arc::new(RwLock::new(snapshot)),
+            status: Arc::new(RwLock::new(ConnectionStatus::default())),
+            failures: RwLock::new(HashMap::new()),
+            history: RwLock::new(History::new(&elastic.history)),
+        }
+    }
+
+    /// The latest published snapshot.
+    pub async fn snapshot(&self) -> Arc<Snapshot> {
+        self.snapshot.read().await.clone()
+    }
+
+    /// The snapshot that was being served at `at`: the latest one if it
+    /// was already fetched by then, otherwise one from the history, in
+    /// memory or on disk.
+    pub async fn snapshot_at(&self, at: DateTime<Utc>) -> Result<Arc<Snapshot>> {
+        let latest = self.snapshot().await;
+        if latest.fetched_at.is_some_and(|fetched_at| fetched_at <= at) {
+            return Ok(latest);
+        }
+
+        if let Some(snapshot) = self.history.read().await.at(at) {
+            return Ok(snapshot);
+        }
+        if let Some(store) = self.store.clone() {
+            if let Some(snapshot) = tokio::task::spawn_blocking(move || store.snapshot_at(at)).await?? {
+                return Ok(snapshot);
+            }
+        }
+
+        let info = self.history_info().await;
+        match info.persisted_oldest.or(info.oldest) {
+            Some(oldest) => bail!("No snapshot from {}, history goes back to {}", at, oldest),
+            None => bail!("No snapshot from {}, no history has been kept yet", at),
+        }
+    }
+
+    pub async fn history_info(&self) -> HistoryInfo {
+        let mut info = self.history.read().await.info();
+
+        if let Some(store) = self.store.clone() {
+            match tokio::task::spawn_blocking(move || store.oldest()).await {
+                Ok(Ok(oldest)) => info.persisted_oldest = oldest,
+                Ok(Err(e)) => log::warn!("Failed to read the persisted history: {:#}", e),
+                Err(e) => log::warn!("Failed to read the persisted history: {}", e),
+            }
+        }
+
+        info
+    }
+
+    pub async fn events(&self, since: Option<DateTime<Utc>>, types: Option<&[EventType]>) -> Vec<ClusterEvent> {
+        self.events.read().await.since(since, types)
+    }
+
+    /// See `EventLog::after`.
+    pub async fn events_after(&self, id: i32, types: Option<&[EventType]>) -> (Vec<ClusterEvent>, i32) {
+        self.events.read().await.after(id, types)
+    }
+
+    /// ID of the latest event, 0 before the first one.
+    pub async fn last_event_id(&self) -> i32 {
+        self.events.read().await.last_id()
+    }
+
+    /// Metrics between `from` and `to`, from disk when history is
+    /// persisted.
+    pub async fn metrics(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<SnapshotMetrics>> {
+        match self.store.clone() {
+            Some(store) => tokio::task::spawn_blocking(move || store.metrics(from, to)).await?,
+            None => Ok(self.history.read().await.metrics(from, to)),
+        }
+    }
+
+    /// Receives every snapshot published from now on.
+    pub fn subscribe(&self) -> watch::Receiver<Arc<Snapshot>> {
+        self.published.subscribe()
+    }
+
+    /// Connection status with the live circuit breaker state.
+    pub async fn connection_status(&self) -> ConnectionStatus {
+        let mut status = self.status.read().await.clone();
+        status.circuit_breaker = self.source.breaker_status();
+        status
+    }
+
+    /// How current `resource` is in `snapshot`, as of now.
+    pub async fn freshness(&self, snapshot: &Snapshot, resource: Resource) -> Freshness {
+        let fetched_at = snapshot.fetched.get(&resource).copied();
+        let age = fetched_at.map(|at| Utc::now().signed_duration_since(at).num_milliseconds().max(0) as u64);
+        let failure = self.failures.read().await.get(&resource).cloned();
+
+        Freshness {
+            resource,
+            fetched_at,
+            age_seconds: age.map(|age| age as f64 / 1000.0),
+            is_stale: match age {
+                Some(age) => age > self.polling.stale_after_ms,
+                None => true,
+            },
+            last_error_at: failure.as_ref().map(|(_, at)| *at),
+            last_error: failure.map(|(error, _)| error),
+        }
+    }
+
+    /// Asks the refresh loop to fetch `resources` right away and waits for
+    /// the result. Requests made while a refresh is pending are served by
+    /// the same refresh, and the loop runs at most one on-demand refresh per
+    /// `min_refresh_interval_ms`.
+    pub async fn request_refresh(&self, resources: &[Resource]) -> Result<SnapshotInfo, ErrorInfo> {
+        let mut done = self.trigger.done.subscribe();
+
+        let run = {
+            let mut pending = self.trigger.pending.lock().unwrap();
+            pending.resources.extend(resources);
+            pending.run
+        };
+        self.trigger.notify.notify_one();
+
+        let outcome = done.wait_for(|outcome| outcome.run >= run).await
+            .map(|outcome| outcome.clone())
+            .unwrap_or_default();
+
+        match outcome.error {
+            Some(error) => Err(error),
+            None => Ok(self.snapshot().await.info()),
+        }
+    }
+
+    /// Refreshes the given resources and publishes them as the next
+    /// snapshot. Fails with the first error when any of them failed.
+    /// Nothing is fetched or published without any resources.
+    pub async fn refresh(&self, resources: &[Resource]) -> Result<(), ElasticError> {
+        if resources.is_empty() {
+            return Ok(());
+        }
+
+        let result = self.fetch(resources).await;
+        let now = Utc::now();
+
+        let mut status = self.status.write().await;
+        let mut failures = self.failures.write().await;
+        let failed = match result {
+            Ok(failed) => failed,
+            // Failed before fetching anything, e.g. the cluster is unreachable
+            Err(e) => {
+                status.record_failure(&e);
+                self.source.disconnected();
+                let failure = (e.info(), now);
+                failures.extend(resources.iter().map(|resource| (*resource, failure.clone())));
+                return Err(e);
+            }
+        };
+
+        for resource in resources {
+            failures.remove(resource);
+        }
+        failures.extend(failed.iter().map(|(resource, e)| (*resource, (e.info(), now))));
+
+        // The cluster is reachable as long as some of it answered
+        match failed.first() {
+            Some((_, e)) if failed.len() == resources.len() => {
+                status.record_failure(e);
+                self.source.disconnected();
+            }
+            _ => status.record_success(),
         }
+
+        failed.into_iter().next().map_or(Ok(()), |(_, e)| Err(e))
     }
 
-    pub async fn refresh(&self) -> Result<()> {
-        {
-            let cluster_data = self.client.health().await?;
-            let mut cluster = self.cluster.write().await;
-            *cluster = cluster_data;
+    /// Fetches the resources concurrently and publishes the ones that were
+    /// fetched as one snapshot. A resource that fails keeps its previous
+    /// data, so a flaky endpoint doesn't hold back the others. Nothing is
+    /// published when every resource failed. Returns the failed resources.
+    async fn fetch(&self, resources: &[Resource]) -> Result<Vec<(Resource, ElasticError)>, ElasticError> {
+        self.source.prepare().await?;
+
+        let wanted = |resource| resources.contains(&resource);
+        let fetched_at = Utc::now();
+        let (cluster, indices, recovery, shards, nodes, disk) = tokio::join!(
+            fetch_if(wanted(Resource::Health), self.source.health()),
+            fetch_if(wanted(Resource::Indices), self.source.indices()),
+            fetch_if(wanted(Resource::Recovery), self.source.recovery()),
+            fetch_if(wanted(Resource::Shards), self.source.shards()),
+            fetch_if(wanted(Resource::Nodes), self.source.nodes()),
+            async { match wanted(Resource::Nodes) {
+                true => self.disk_allocation().await,
+                false => None,
+            } },
+        );
+
+        let mut failed = Vec::new();
+        let cluster = settle(Resource::Health, cluster, &mut failed);
+        let indices = settle(Resource::Indices, indices, &mut failed);
+        let recovery = settle(Resource::Recovery, recovery, &mut failed);
+        let shards = settle(Resource::Shards, shards, &mut failed);
+        let nodes = settle(Resource::Nodes, nodes, &mut failed);
+        // Disk allocation goes with the nodes it describes
+        let disk = disk.filter(|_| nodes.is_some());
+        if !failed.is_empty() && failed.len() == resources.len() {
+            return Ok(failed);
         }
 
-        {
-            let indices_data = self.client.indices().await?;
-            let mut indices = self.indices.write().await;
-            *indices = indices_data;
+        // Only what the explainer already knows, explaining happens in the background
+        let shards = match shards {
+            Some(mut shards) => {
+                self.explainer.lock().await.attach(&mut shards);
+                Some(shards)
+            }
+            None => None,
+        };
+
+        let (previous, published) = {
+            let mut snapshot = self.snapshot.write().await;
+            let previous = snapshot.clone();
+            let mut fetched = snapshot.fetched.clone();
+            fetched.extend(resources.iter()
+                .filter(|resource| !failed.iter().any(|(other, _)| other == *resource))
+                .map(|resource| (*resource, fetched_at)));
+            *snapshot = Arc::new(Snapshot {
+                generation: snapshot.generation + 1,
+                fetched_at: Some(fetched_at),
+                fetched,
+                cluster: reuse(cluster, &snapshot.cluster),
+                indices: reuse(indices, &snapshot.indices),
+                recovery: reuse(recovery, &snapshot.recovery),
+                shards: reuse(shards, &snapshot.shards),
+                nodes: reuse(nodes, &snapshot.nodes),
+                disk: reuse(disk, &snapshot.disk),
+            });
+            (previous, snapshot.clone())
+        };
+        self.history.write().await.record(&published);
+        self.events.write().await.record(&previous, &published);
+        self.published.send_replace(published.clone());
+
+        // Only queued here, the store writes on its own thread
+        if let Some(store) = &self.store {
+            store.record(&published);
         }
 
-        {
-            let recovery_data = self.client.recovery().await?;
-            let mut recovery = self.recovery.write().await;
-            *recovery = recovery_data;
+        Ok(failed)
+    }
+
+    /// Disk allocation only adds to the nodes, so failing to fetch it
+    /// doesn't fail the refresh. The previous one is kept instead.
+    async fn disk_allocation(&self) -> Option<DiskAllocation> {
+        match self.source.disk_allocation().await {
+            Ok(disk) => disk,
+            Err(e) => {
+                log::warn!("Failed to fetch disk allocation: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Runs allocation explain for the copies in `shards` that are due,
+    /// within the explainer's time budget. Explanations are kept by the
+    /// explainer, to be attached on the next refresh of the shards.
+    async fn explain(&self, shards: &[ShallowShard]) {
+        let (due, budget) = {
+            let mut explainer = self.explainer.lock().await;
+            (explainer.due(shards), explainer.budget())
+        };
+        let deadline = tokio::time::Instant::now() + budget;
+
+        for (key, request) in due {
+            match tokio::time::timeout_at(deadline, self.source.allocation_explain(&request)).await {
+                Ok(Ok(explanation)) => {
+                    let supported = explanation.is_some();
+                    self.explainer.lock().await.record(&key, explanation);
+                    if !supported {
+                        break;
+                    }
+                }
+                Ok(Err(e)) => {
+                    log::warn!("Failed to explain the allocation of {}[{}]: {}", request.index, request.shard, e);
+                    self.explainer.lock().await.record(&key, None);
+                }
+                Err(_) => {
+                    log::warn!("Allocation explain ran out of time after {:?}, continuing later", budget);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// How long to wait before fetching `resource` again, given the latest
+    /// snapshot.
+    fn interval(&self, resource: Resource, snapshot: &Snapshot) -> Duration {
+        let polling = &self.polling;
+        let cluster = &snapshot.cluster;
+        let mut interval = polling.resources.get(resource.name()).copied().unwrap_or(polling.interval_ms) as f64;
+
+        let active = cluster.has_moving_shards() || !snapshot.recovery.is_empty();
+        let tracks_movement = matches!(resource, Resource::Health | Resource::Recovery | Resource::Shards);
+        if active && tracks_movement {
+            interval = interval.min(polling.active_interval_ms as f64);
+        } else if !active && cluster.status() == "green" {
+            interval *= polling.idle_factor;
         }
 
-        {
-            let shards_data = self.client.shards().await?;
-            let mut shards = self.shards.write().await;
-            *shards = shards_data;
+        if cluster.task_max_waiting_in_queue_millis() as u64 > polling.queue_backoff_threshold_ms {
+            interval *= polling.queue_backoff_factor;
         }
 
-        {
-            let nodes_data = self.client.nodes().await?;
-            let mut nodes = self.nodes.write().await;
-            *nodes = nodes_data;
+        if self.is_unwatched() {
+            interval = interval.max(polling.unwatched_interval_ms as f64);
         }
 
-        Ok(())
+        Duration::from_millis(interval.clamp(0.0, polling.max_interval_ms as f64) as u64)
     }
 
-    pub async fn start_refresh(warehouse: Arc<RwLock<Warehouse>>) {
-    debug!("Spawning refresh loop...");
+    fn is_unwatched(&self) -> bool {
+        clients_idle_for() > Duration::from_secs(self.polling.unwatched_after_secs)
+    }
+
+    /// Explains unassigned and stuck shards after every published snapshot,
+    /// apart from the refresh loop so slow explains never delay a refresh.
+    pub async fn start_explainer(name: String, warehouse: SharedWarehouse) {
+        let mut published = warehouse.read().await.subscribe();
+
+        tokio::spawn(async move {
+            while published.changed().await.is_ok() {
+                let snapshot = published.borrow_and_update().clone();
+                debug!("[{}] Explaining allocation...", name);
+                warehouse.read().await.explain(&snapshot.shards).await;
+            }
+        });
+    }
+
+    pub async fn start_refresh(name: String, warehouse: SharedWarehouse) {
+    debug!("Spawning refresh loop for {}...", name);
     tokio::spawn(async move {
+        let mut due: HashMap<Resource, tokio::time::Instant> = Resource::ALL.into_iter()
+            .map(|resource| (resource, tokio::time::Instant::now()))
+            .collect();
+        let mut last_requested = None;
+
         loop {
-            debug!("Sleeping for 5 seconds...");
-            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-            debug!("Refreshing data...");
-            let warehouse = warehouse.read().await;
-            if let Err(e) = warehouse.refresh().await {
-                log::error!("Failed to refresh data: {:?}", e);
+            let now = tokio::time::Instant::now();
+
+            let unwatched = {
+                let warehouse = warehouse.read().await;
+
+                let (requested, run) = warehouse.trigger.take();
+                let resources: Vec<_> = Resource::ALL.into_iter()
+                    .filter(|resource| due[resource] <= now || requested.contains(resource))
+                    .collect();
+                debug!("[{}] Refreshing {:?}...", name, resources);
+
+                let result = warehouse.refresh(&resources).await;
+                match &result {
+                    Ok(()) => debug!("[{}] Data refreshed!", name),
+                    Err(e) => log::error!("[{}] Failed to refresh data: {}", name, e),
+                }
+                warehouse.trigger.done.send_replace(RefreshOutcome {
+                    run,
+                    error: result.err().map(|e| e.info()),
+                });
+
+                // Failed resources are retried on their regular schedule
+                let snapshot = warehouse.snapshot().await;
+                for resource in resources {
+                    due.insert(resource, now + warehouse.interval(resource, &snapshot));
+                }
+
+                warehouse.is_unwatched()
+            };
+
+            let next = due.values().min().copied().unwrap_or(now);
+            debug!("[{}] Sleeping for {:?}...", name, next.saturating_duration_since(tokio::time::Instant::now()));
+            let trigger = async {
+                warehouse.read().await.trigger.notify.notified().await;
+            };
+            tokio::select! {
+                _ = trigger => {
+                    // Requests arriving in the meantime join this refresh
+                    let min_interval = Duration::from_millis(warehouse.read().await.polling.min_refresh_interval_ms);
+                    if let Some(last) = last_requested {
+                        tokio::time::sleep_until(last + min_interval).await;
+                    }
+                    last_requested = Some(tokio::time::Instant::now());
+                }
+                _ = tokio::time::sleep_until(next) => {}
+                // A client came back, show it fresh data right away
+                _ = CLIENT_SEEN.notified(), if unwatched => {
+                    debug!("[{}] Client seen, refreshing everything", name);
+                    due.values_mut().for_each(|at| *at = tokio::time::Instant::now());
+                }
             }
-            debug!("Data refreshed!");
         }
     });
 }
 }
+
+/// Shares the previous value when a resource wasn't fetched or didn't
+/// change, so snapshots kept in the history share it too.
+fn reuse<T: PartialEq>(fetched: Option<T>, previous: &Arc<T>) -> Arc<T> {
+    match fetched {
+        Some(value) if value != **previous => Arc::new(value),
+        _ => previous.clone(),
+    }
+}
+
+/// Keeps a fetched value, or records why `resource` couldn't be fetched.
+fn settle<T>(
+    resource: Resource,
+    result: Result<Option<T>, ElasticError>,
+    failed: &mut Vec<(Resource, ElasticError)>,
+) -> Option<T> {
+    result.unwrap_or_else(|e| {
+        failed.push((resource, e));
+        None
+    })
+}
+
+async fn fetch_if<T>(
+    wanted: bool,
+    fetch: impl Future<Output = Result<T, ElasticError>>,
+) -> Result<Option<T>, ElasticError> {
+    match wanted {
+        true => fetch.await.map(Some),
+        false => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+
+    /// Serves the embedded fixtures, failing or holding back a resource on
+    /// request.
+    #[derive(Debug)]
+    struct StubSource {
+        fixtures: FixtureSource,
+        failing: Arc<std::sync::Mutex<Option<Resource>>>,
+        /// Notified when the held resource is being fetched, which then waits
+        /// for the second one.
+        held: Option<(Resource, Arc<Notify>, Arc<Notify>)>,
+    }
+
+    impl StubSource {
+        fn new() -> Self {
+            StubSource { fixtures: FixtureSource::embedded(), failing: Arc::default(), held: None }
+        }
+
+        async fn serve(&self, resource: Resource) -> Result<(), ElasticError> {
+            if let Some((held, reached, release)) = &self.held {
+                if *held == resource {
+                    reached.notify_one();
+                    release.notified().await;
+                }
+            }
+
+            match *self.failing.lock().unwrap() == Some(resource) {
+                true => Err(ElasticError::MissingFixture { origin: "stub".to_string(), resource: resource.name() }),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ClusterSource for StubSource {
+        async fn health(&self) -> Result<ClusterInfo, ElasticError> {
+            self.serve(Resource::Health).await?;
+            self.fixtures.health().await
+        }
+
+        async fn indices(&self) -> Result<Vec<IndexInfo>, ElasticError> {
+            self.serve(Resource::Indices).await?;
+            self.fixtures.indices().await
+        }
+
+        async fn recovery(&self) -> Result<Recovery, ElasticError> {
+            self.serve(Resource::Recovery).await?;
+            self.fixtures.recovery().await
+        }
+
+        async fn shards(&self) -> Result<Vec<ShallowShard>, ElasticError> {
+            self.serve(Resource::Shards).await?;
+            self.fixtures.shards().await
+        }
+
+        async fn nodes(&self) -> Result<NodeOutput, ElasticError> {
+            self.serve(Resource::Nodes).await?;
+            self.fixtures.nodes().await
+        }
+    }
+
+    fn warehouse(source: StubSource) -> Warehouse {
+        let elastic: config::ElasticConfig = serde_json::from_value(serde_json::json!({})).unwrap();
+        Warehouse::with_source(Box::new(source), &elastic, None)
+    }
+
+    #[tokio::test]
+    async fn every_refresh_publishes_one_generation() {
+        let warehouse = warehouse(StubSource::new());
+        let mut published = warehouse.subscribe();
+
+        for generation in 1..=3 {
+            warehouse.refresh(&Resource::ALL).await.unwrap();
+            assert!(published.has_changed().unwrap());
+            assert_eq!(published.borrow_and_update().generation, generation);
+            assert_eq!(warehouse.snapshot().await.generation, generation);
+        }
+
+        warehouse.refresh(&[Resource::Health]).await.unwrap();
+        assert_eq!(warehouse.snapshot().await.generation, 4);
+    }
+
+    #[tokio::test]
+    async fn refreshing_no_resources_publishes_nothing() {
+        let warehouse = warehouse(StubSource::new());
+        warehouse.refresh(&Resource::ALL).await.unwrap();
+        let published = warehouse.subscribe();
+
+        warehouse.refresh(&[]).await.unwrap();
+        assert_eq!(warehouse.snapshot().await.generation, 1);
+        assert!(!published.has_changed().unwrap());
+    }
+
+    #[tokio::test]
+    async fn a_failing_resource_keeps_its_previous_data() {
+        let source = StubSource::new();
+        let failing = source.failing.clone();
+        let warehouse = warehouse(source);
+        warehouse.refresh(&Resource::ALL).await.unwrap();
+        let previous = warehouse.snapshot().await;
+
+        *failing.lock().unwrap() = Some(Resource::Shards);
+        let error = warehouse.refresh(&Resource::ALL).await.unwrap_err();
+        assert_eq!(error.kind(), crate::elastic::error::ErrorKind::MissingFixture);
+
+        let snapshot = warehouse.snapshot().await;
+        assert_eq!(snapshot.generation, 2);
+        assert!(Arc::ptr_eq(&snapshot.shards, &previous.shards));
+        assert_eq!(snapshot.fetched[&Resource::Shards], previous.fetched[&Resource::Shards]);
+        for resource in [Resource::Health, Resource::Indices, Resource::Recovery, Resource::Nodes] {
+            assert_eq!(snapshot.fetched.get(&resource), snapshot.fetched_at.as_ref());
+        }
+    }
+
+    #[tokio::test]
+    async fn nothing_is_published_when_every_resource_fails() {
+        let source = StubSource::new();
+        let failing = source.failing.clone();
+        let warehouse = warehouse(source);
+        warehouse.refresh(&Resource::ALL).await.unwrap();
+        let published = warehouse.subscribe();
+
+        *failing.lock().unwrap() = Some(Resource::Shards);
+        assert!(warehouse.refresh(&[Resource::Shards]).await.is_err());
+        assert_eq!(warehouse.snapshot().await.generation, 1);
+        assert!(!published.has_changed().unwrap());
+    }
+
+    #[tokio::test]
+    async fn failures_are_kept_for_the_failing_resource_only() {
+        let source = StubSource::new();
+        let failing = source.failing.clone();
+        let warehouse = warehouse(source);
+
+        *failing.lock().unwrap() = Some(Resource::Nodes);
+        assert!(warehouse.refresh(&Resource::ALL).await.is_err());
+        let snapshot = warehouse.snapshot().await;
+        let nodes = warehouse.freshness(&snapshot, Resource::Nodes).await;
+        assert_eq!(nodes.last_error.unwrap().kind, crate::elastic::error::ErrorKind::MissingFixture);
+        assert!(nodes.is_stale);
+        let health = warehouse.freshness(&snapshot, Resource::Health).await;
+        assert!(health.last_error.is_none());
+        assert!(!health.is_stale);
+        assert!(warehouse.connection_status().await.connected);
+
+        *failing.lock().unwrap() = None;
+        warehouse.refresh(&[Resource::Nodes]).await.unwrap();
+        let snapshot = warehouse.snapshot().await;
+        assert!(warehouse.freshness(&snapshot, Resource::Nodes).await.last_error.is_none());
+    }
+
+    #[tokio::test]
+    async fn readers_never_see_a_partial_refresh() {
+        let (reached, release) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+        let source = StubSource { held: Some((Resource::Shards, reached.clone(), release.clone())), ..StubSource::new() };
+        let warehouse = warehouse(source);
+
+        let (result, ()) = tokio::join!(warehouse.refresh(&Resource::ALL), async {
+            // Everything but the shards has been fetched by now
+            reached.notified().await;
+            let snapshot = warehouse.snapshot().await;
+            assert_eq!(snapshot.generation, 0);
+            assert_eq!(snapshot.cluster.status(), "unknown");
+            assert!(snapshot.indices.is_empty());
+            assert!(snapshot.fetched.is_empty());
+            release.notify_one();
+        });
+        result.unwrap();
+
+        let snapshot = warehouse.snapshot().await;
+        assert_eq!(snapshot.generation, 1);
+        assert_ne!(snapshot.cluster.status(), "unknown");
+        assert!(!snapshot.indices.is_empty());
+        assert!(!snapshot.shards.is_empty());
+        assert!(Resource::ALL.iter().all(|resource| snapshot.fetched.get(resource) == snapshot.fetched_at.as_ref()));
+    }
+
+    /// Client activity is global, tests that depend on it take turns.
+    static CLIENTS: Mutex<()> = Mutex::const_new(());
+
+    #[test]
+    fn open_watchers_keep_clients_seen() {
+        let _clients = CLIENTS.blocking_lock();
+        let watcher = Watcher::start();
+        *LAST_CLIENT_SEEN.lock().unwrap() = None;
+        assert_eq!(clients_idle_for(), Duration::ZERO);
+
+        drop(watcher);
+        assert!(clients_idle_for() < Duration::from_secs(60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn polling_slows_down_once_the_last_watcher_is_gone() {
+        let _clients = CLIENTS.lock().await;
+        let elastic: config::ElasticConfig = serde_json::from_value(serde_json::json!({
+            "polling": {
+                "interval_ms": 1000,
+                "resources": {},
+                "idle_factor": 1.0,
+                "unwatched_after_secs": 10,
+                "unwatched_interval_ms": 60000,
+            },
+        })).unwrap();
+        let warehouse = Arc::new(RwLock::new(Warehouse::with_source(Box::new(StubSource::new()), &elastic, None)));
+        // Every resource is due at the same time, one generation per refresh
+        let generation = || async { warehouse.read().await.snapshot().await.generation };
+
+        let watcher = Watcher::start();
+        Warehouse::start_refresh("stub".to_string(), warehouse.clone()).await;
+        tokio::time::sleep(Duration::from_millis(30_500)).await;
+        // Refreshed at 0s, 1s, ..., 30s
+        assert_eq!(generation().await, 31);
+
+        drop(watcher);
+        // Still watched for 10s, then one more refresh sets the slower pace
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        let unwatched = generation().await;
+        assert!((40..=42).contains(&unwatched), "{}", unwatched);
+        tokio::time::sleep(Duration::from_secs(59)).await;
+        assert!(generation().await - unwatched <= 1);
+
+        // A client coming back is served fresh data right away
+        client_seen();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let seen = generation().await;
+        assert!(seen > unwatched);
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert!(generation().await >= seen + 4);
+    }
+}
@@ -0,0 +1,290 @@
+use crate::data::Snapshot;
+use crate::elastic::data::DiskWatermarks;
+
+use juniper::GraphQLObject;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(GraphQLObject, Serialize, Debug, Clone)]
+#[graphql(description = "Disk usage of the nodes against the disk watermarks")]
+pub struct DiskView {
+    /// None when the source doesn't report them
+    pub watermarks: Option<DiskWatermarks>,
+    /// Fullest first
+    pub nodes: Vec<NodeDisk>,
+    /// Names of the nodes past the low watermark
+    pub blocking_allocation: Vec<String>,
+    /// Shards `_cat/allocation` counts as unassigned
+    pub unassigned_shards: i32,
+}
+
+#[derive(GraphQLObject, Serialize, Debug, Clone)]
+#[graphql(description = "Disk usage of one node against the disk watermarks")]
+pub struct NodeDisk {
+    pub name: String,
+    pub shards: Option<i32>,
+    pub total_bytes: Option<f64>,
+    pub used_bytes: Option<f64>,
+    pub available_bytes: Option<f64>,
+    /// Used by shards, the rest of the used space holds other data
+    pub indices_bytes: Option<f64>,
+    pub used_percent: Option<f64>,
+    /// Bytes that can be written before reaching the watermark, negative
+    /// once past it
+    pub to_low_bytes: Option<f64>,
+    pub to_high_bytes: Option<f64>,
+    pub to_flood_stage_bytes: Option<f64>,
+    /// Highest watermark reached: `low`, `high` or `flood_stage`
+    pub watermark: Option<String>,
+    /// Past the low watermark, so no more replicas or relocating shards are
+    /// allocated to the node
+    pub blocks_allocation: bool,
+}
+
+/// Combines the nodes' file system stats with `_cat/allocation` and the
+/// watermarks. File system stats are preferred for the disk figures, as
+/// they are what the watermarks are checked against.
+pub fn disk_view(snapshot: &Snapshot) -> DiskView {
+    let parse = |value: &Option<String>| value.as_deref().and_then(|value| value.parse::<f64>().ok());
+    let watermarks = snapshot.disk.watermarks.clone().filter(|watermarks| watermarks.enabled);
+
+    let mut nodes: BTreeMap<&str, NodeDisk> = BTreeMap::new();
+    for node in &snapshot.nodes.nodes {
+        let disk = nodes.entry(node.name()).or_insert_with(|| NodeDisk::new(node.name()));
+        if let Some((total, available)) = node.disk_space() {
+            disk.total_bytes = Some(total);
+            disk.available_bytes = Some(available);
+        }
+    }
+
+    let mut unassigned_shards = 0;
+    for row in &snapshot.disk.nodes {
+        let shards = row.shards.as_deref().and_then(|shards| shards.parse().ok());
+        if row.node == "UNASSIGNED" {
+            unassigned_shards = shards.unwrap_or_default();
+            continue;
+        }
+
+        let disk = nodes.entry(&row.node).or_insert_with(|| NodeDisk::new(&row.node));
+        disk.shards = shards;
+        disk.indices_bytes = parse(&row.disk_indices);
+        if disk.total_bytes.is_none() {
+            disk.total_bytes = parse(&row.disk_total);
+            disk.available_bytes = parse(&row.disk_avail);
+        }
+    }
+
+    let mut nodes: Vec<_> = nodes.into_values()
+        .map(|mut disk| {
+            disk.measure(watermarks.as_ref());
+            disk
+        })
+        .collect();
+    nodes.sort_by(|a, b| b.used_percent.unwrap_or(-1.0).total_cmp(&a.used_percent.unwrap_or(-1.0)));
+
+    DiskView {
+        watermarks: snapshot.disk.watermarks.clone(),
+        blocking_allocation: nodes.iter().filter(|disk| disk.blocks_allocation).map(|disk| disk.name.clone()).collect(),
+        nodes,
+        unassigned_shards,
+    }
+}
+
+impl NodeDisk {
+    fn new(name: &str) -> Self {
+        NodeDisk {
+            name: name.to_string(),
+            shards: None,
+            total_bytes: None,
+            used_bytes: None,
+            available_bytes: None,
+            indices_bytes: None,
+            used_percent: None,
+            to_low_bytes: None,
+            to_high_bytes: None,
+            to_flood_stage_bytes: None,
+            watermark: None,
+            blocks_allocation: false,
+        }
+    }
+
+    /// Fills in the figures derived from total and available space.
+    fn measure(&mut self, watermarks: Option<&DiskWatermarks>) {
+        let (Some(total), Some(available)) = (self.total_bytes, self.available_bytes) else {
+            return;
+        };
+        let used = total - available;
+        self.used_bytes = Some(used);
+        self.used_percent = (total > 0.0).then(|| used / total * 100.0);
+
+        let Some(watermarks) = watermarks else {
+            return;
+        };
+        let to = |watermark: &str, max_headroom: &Option<String>| limit(watermark, max_headroom.as_deref(), total).map(|limit| limit - used);
+        self.to_low_bytes = to(&watermarks.low, &watermarks.low_max_headroom);
+        self.to_high_bytes = to(&watermarks.high, &watermarks.high_max_headroom);
+        self.to_flood_stage_bytes = to(&watermarks.flood_stage, &watermarks.flood_stage_max_headroom);
+
+        let reached = |to: Option<f64>| to.is_some_and(|to| to <= 0.0);
+        self.watermark = [
+            ("flood_stage", self.to_flood_stage_bytes),
+            ("high", self.to_high_bytes),
+            ("low", self.to_low_bytes),
+        ].into_iter().find(|(_, to)| reached(*to)).map(|(name, _)| name.to_string());
+        self.blocks_allocation = reached(self.to_low_bytes);
+    }
+}
+
+/// Bytes used at which a disk of `total` bytes reaches `watermark`. A
+/// share of the disk asks for at most `max_headroom` of free space.
+fn limit(watermark: &str, max_headroom: Option<&str>, total: f64) -> Option<f64> {
+    let watermark = watermark.trim();
+    let share = match watermark.strip_suffix('%') {
+        Some(percent) => Some(percent.trim().parse::<f64>().ok()? / 100.0),
+        None => watermark.parse::<f64>().ok(),
+    };
+
+    let free = match share {
+        Some(share) => {
+            let free = total * (1.0 - share);
+            max_headroom.and_then(parse_bytes).map_or(free, |headroom| free.min(headroom))
+        }
+        None => parse_bytes(watermark)?,
+    };
+    Some(total - free)
+}
+
+/// Parses a byte size setting like `500mb` or `1.5tb`.
+fn parse_bytes(value: &str) -> Option<f64> {
+    let value = value.trim().to_ascii_lowercase();
+    let (number, unit) = value.split_at(value.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(value.len()));
+    let factor: u64 = match unit {
+        "" | "b" => 1,
+        "kb" => 1 << 10,
+        "mb" => 1 << 20,
+        "gb" => 1 << 30,
+        "tb" => 1 << 40,
+        "pb" => 1 << 50,
+        _ => return None,
+    };
+
+    number.trim().parse::<f64>().ok().map(|number| number * factor as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elastic::data::{CatAllocation, DiskAllocation};
+    use std::sync::Arc;
+
+    const GB: f64 = (1u64 << 30) as f64;
+
+    fn watermarks(low: &str, high: &str, flood_stage: &str) -> DiskWatermarks {
+        DiskWatermarks {
+            enabled: true,
+            low: low.to_string(),
+            high: high.to_string(),
+            flood_stage: flood_stage.to_string(),
+            low_max_headroom: None,
+            high_max_headroom: None,
+            flood_stage_max_headroom: None,
+        }
+    }
+
+    fn row(node: &str, shards: u32, total_gb: u64, available_gb: u64) -> CatAllocation {
+        CatAllocation {
+            node: node.to_string(),
+            shards: Some(shards.to_string()),
+            disk_indices: None,
+            disk_used: None,
+            disk_avail: Some((available_gb << 30).to_string()),
+            disk_total: Some((total_gb << 30).to_string()),
+            host: None,
+            ip: None,
+        }
+    }
+
+    #[test]
+    fn watermarks_are_shares_or_free_space() {
+        assert_eq!(limit("85%", None, 1000.0), Some(850.0));
+        assert_eq!(limit(" 85 % ", None, 1000.0), Some(850.0));
+        assert_eq!(limit("0.85", None, 1000.0), Some(850.0));
+        assert_eq!(limit("100gb", None, 1000.0 * GB), Some(900.0 * GB));
+        // Free space watermarks aren't capped
+        assert_eq!(limit("100gb", Some("10gb"), 1000.0 * GB), Some(900.0 * GB));
+        assert_eq!(limit("85%", Some("oops"), 1000.0), Some(850.0));
+        assert_eq!(limit("85xb", None, 1000.0), None);
+        assert_eq!(limit("high", None, 1000.0), None);
+    }
+
+    #[test]
+    fn max_headroom_caps_the_free_space_of_big_disks() {
+        // 10% of 10tb would keep 1tb free, the headroom asks for 150gb
+        assert_eq!(limit("90%", Some("150gb"), 10_240.0 * GB), Some(10_090.0 * GB));
+        // Small disks keep their share free
+        assert_eq!(limit("90%", Some("150gb"), 100.0 * GB), Some(90.0 * GB));
+    }
+
+    #[test]
+    fn parses_byte_sizes() {
+        assert_eq!(parse_bytes("500"), Some(500.0));
+        assert_eq!(parse_bytes("500b"), Some(500.0));
+        assert_eq!(parse_bytes("2kb"), Some(2048.0));
+        assert_eq!(parse_bytes("500MB"), Some(500.0 * 1024.0 * 1024.0));
+        assert_eq!(parse_bytes("1.5tb"), Some(1.5 * 1024.0 * GB));
+        assert_eq!(parse_bytes(" 20 gb "), Some(20.0 * GB));
+        assert_eq!(parse_bytes("1pb"), Some((1u64 << 50) as f64));
+        assert_eq!(parse_bytes("20gib"), None);
+        assert_eq!(parse_bytes("3zb"), None);
+        assert_eq!(parse_bytes("gb"), None);
+    }
+
+    #[test]
+    fn nodes_past_the_watermarks() {
+        let snapshot = Snapshot {
+            disk: Arc::new(DiskAllocation {
+                nodes: vec![
+                    row("roomy", 3, 100, 50),
+                    row("flooded", 5, 100, 3),
+                    row("UNASSIGNED", 2, 0, 0),
+                    row("high", 4, 100, 8),
+                ],
+                watermarks: Some(watermarks("85%", "90%", "95%")),
+            }),
+            ..Snapshot::empty()
+        };
+
+        let view = disk_view(&snapshot);
+        let nodes: Vec<_> = view.nodes.iter()
+            .map(|disk| (disk.name.as_str(), disk.watermark.as_deref(), disk.blocks_allocation))
+            .collect();
+        assert_eq!(nodes, [("flooded", Some("flood_stage"), true), ("high", Some("high"), true), ("roomy", None, false)]);
+        assert_eq!(view.blocking_allocation, ["flooded", "high"]);
+        assert_eq!(view.unassigned_shards, 2);
+
+        let high = &view.nodes[1];
+        assert_eq!(high.shards, Some(4));
+        assert_eq!(high.used_percent, Some(92.0));
+        assert_eq!(high.to_low_bytes, Some(-7.0 * GB));
+        assert_eq!(high.to_high_bytes, Some(-2.0 * GB));
+        assert_eq!(high.to_flood_stage_bytes, Some(3.0 * GB));
+        assert_eq!(view.nodes[0].to_flood_stage_bytes, Some(-2.0 * GB));
+    }
+
+    #[test]
+    fn disabled_watermarks_are_not_measured() {
+        let snapshot = Snapshot {
+            disk: Arc::new(DiskAllocation {
+                nodes: vec![row("flooded", 5, 100, 3)],
+                watermarks: Some(DiskWatermarks { enabled: false, ..watermarks("85%", "90%", "95%") }),
+            }),
+            ..Snapshot::empty()
+        };
+
+        let view = disk_view(&snapshot);
+        assert_eq!(view.nodes[0].used_percent, Some(97.0));
+        assert_eq!(view.nodes[0].watermark, None);
+        assert!(view.blocking_allocation.is_empty());
+        assert!(view.watermarks.is_some(), "still reported as configured");
+    }
+}
@@ -0,0 +1,145 @@
+use crate::config::AuthConfig;
+
+use anyhow::{bail, Context, Result};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use std::fmt;
+use std::path::Path;
+
+#[derive(Clone)]
+pub enum Credentials {
+    Basic { username: String, password: String },
+    ApiKey(String),
+    Bearer(String),
+}
+
+// Keep secrets out of debug logs
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Credentials::Basic { username, .. } => write!(f, "Basic({username}, ***)"),
+            Credentials::ApiKey(_) => write!(f, "ApiKey(***)"),
+            Credentials::Bearer(_) => write!(f, "Bearer(***)"),
+        }
+    }
+}
+
+impl Credentials {
+    pub fn from_config(config: &AuthConfig) -> Result<Option<Self>> {
+        let password = secret(&config.password, &config.password_file, "password")?;
+        let api_key = secret(&config.api_key, &config.api_key_file, "api_key")?;
+        let bearer_token = secret(&config.bearer_token, &config.bearer_token_file, "bearer_token")?;
+
+        let basic = match (&config.username, password) {
+            (Some(username), Some(password)) => Some(Credentials::Basic {
+                username: username.clone(),
+                password,
+            }),
+            (Some(_), None) => bail!("elastic.auth: username is set but password is missing"),
+            (None, Some(_)) => bail!("elastic.auth: password is set but username is missing"),
+            (None, None) => None,
+        };
+
+        let api_key = match (&config.api_key_id, api_key) {
+            (Some(id), Some(key)) => Some(Credentials::ApiKey(BASE64_STANDARD.encode(format!("{id}:{key}")))),
+            (None, Some(encoded)) => Some(Credentials::ApiKey(encoded)),
+            (Some(_), None) => bail!("elastic.auth: api_key_id is set but api_key is missing"),
+            (None, None) => None,
+        };
+
+        let bearer = bearer_token.map(Credentials::Bearer);
+
+        let mut configured = [basic, api_key, bearer].into_iter().flatten();
+        let credentials = configured.next();
+
+        if configured.next().is_some() {
+            bail!("elastic.auth: only one of basic auth, api_key or bearer_token can be configured");
+        }
+
+        Ok(credentials)
+    }
+
+    pub fn apply(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        request.header(reqwest::header::AUTHORIZATION, self.header())
+    }
+
+    /// Value of the `Authorization` header for these credentials.
+    pub fn header(&self) -> String {
+        match self {
+            Credentials::Basic { username, password } => {
+                format!("Basic {}", BASE64_STANDARD.encode(format!("{username}:{password}")))
+            }
+            Credentials::ApiKey(key) => format!("ApiKey {key}"),
+            Credentials::Bearer(token) => format!("Bearer {token}"),
+        }
+    }
+}
+
+fn secret(value: &Option<String>, file: &Option<impl AsRef<Path>>, name: &str) -> Result<Option<String>> {
+    match (value, file) {
+        (Some(_), Some(_)) => bail!("elastic.auth: both {name} and {name}_file are set"),
+        (Some(value), None) => Ok(Some(value.clone())),
+        (None, Some(file)) => {
+            let file = file.as_ref();
+            let contents = std::fs::read_to_string(file)
+                .with_context(|| format!("elastic.auth: failed to read {name}_file {}", file.display()))?;
+            Ok(Some(contents.trim().to_string()))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    fn auth(config: serde_json::Value) -> Result<Option<Credentials>> {
+        Credentials::from_config(&serde_json::from_value(config).unwrap())
+    }
+
+    fn header(config: serde_json::Value) -> Option<String> {
+        auth(config).unwrap().map(|credentials| credentials.header())
+    }
+
+    fn secret_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("parietal-auth-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn encodes_each_kind_of_header() {
+        assert_eq!(header(json!({})), None);
+        // base64("elastic:changeme")
+        assert_eq!(header(json!({"username": "elastic", "password": "changeme"})).unwrap(), "Basic ZWxhc3RpYzpjaGFuZ2VtZQ==");
+        // base64("id:key")
+        assert_eq!(header(json!({"api_key_id": "id", "api_key": "key"})).unwrap(), "ApiKey aWQ6a2V5");
+        assert_eq!(header(json!({"api_key": "aWQ6a2V5"})).unwrap(), "ApiKey aWQ6a2V5");
+        assert_eq!(header(json!({"bearer_token": "token"})).unwrap(), "Bearer token");
+    }
+
+    #[test]
+    fn reads_secrets_from_files() {
+        let password = secret_file("password", "from-file\n");
+        let api_key = secret_file("api_key", "aWQ6a2V5\n");
+        let bearer = secret_file("bearer", "token\n");
+
+        assert_eq!(header(json!({"username": "u", "password_file": password})).unwrap(), format!("Basic {}", BASE64_STANDARD.encode("u:from-file")));
+        assert_eq!(header(json!({"api_key_file": api_key})).unwrap(), "ApiKey aWQ6a2V5");
+        assert_eq!(header(json!({"bearer_token_file": bearer})).unwrap(), "Bearer token");
+
+        assert!(auth(json!({"username": "u", "password": "p", "password_file": password})).is_err());
+        assert!(auth(json!({"username": "u", "password_file": "/nonexistent/password"})).is_err());
+    }
+
+    #[test]
+    fn rejects_incomplete_or_conflicting_credentials() {
+        assert!(auth(json!({"username": "elastic"})).is_err());
+        assert!(auth(json!({"password": "changeme"})).is_err());
+        assert!(auth(json!({"api_key_id": "id"})).is_err());
+        assert!(auth(json!({"username": "u", "password": "p", "api_key": "k"})).is_err());
+        assert!(auth(json!({"api_key": "k", "bearer_token": "t"})).is_err());
+        assert!(auth(json!({"username": "u", "password": "p", "bearer_token": "t"})).is_err());
+    }
+}
@@ -0,0 +1,251 @@
+use crate::config::CircuitBreakerConfig;
+
+use chrono::{DateTime, Utc};
+use juniper::{GraphQLEnum, GraphQLObject};
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+// Follows the paused clock in tests
+use tokio::time::Instant;
+
+#[derive(GraphQLEnum, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    #[default]
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(GraphQLObject, Serialize, Debug, Clone, Default)]
+#[graphql(description = "Circuit breaker guarding requests to the cluster")]
+pub struct BreakerStatus {
+    pub state: BreakerState,
+    pub consecutive_failures: i32,
+    pub open_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+struct BreakerInner {
+    state: BreakerState,
+    consecutive_failures: u32,
+    opened_at: Option<(Instant, DateTime<Utc>)>,
+    failed_in_refresh: bool,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_for: Duration,
+    inner: Mutex<BreakerInner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &CircuitBreakerConfig) -> Self {
+        CircuitBreaker {
+            failure_threshold: config.failure_threshold.max(1),
+            open_for: Duration::from_secs(config.open_secs),
+            inner: Mutex::new(BreakerInner {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+                failed_in_refresh: false,
+            }),
+        }
+    }
+
+    /// Whether a request may be sent, which is only while the breaker is
+    /// closed. Open and half-open breakers wait for a probe, see
+    /// `try_probe`.
+    pub fn allow(&self) -> bool {
+        self.inner.lock().unwrap().state == BreakerState::Closed
+    }
+
+    /// Whether the caller should send the single probe that decides if the
+    /// breaker closes again. An open breaker moves to half-open once its
+    /// cool-down has passed. A probe that never reports back (e.g. it was
+    /// cancelled) is replaced by another after a further cool-down.
+    pub fn try_probe(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.state == BreakerState::Closed {
+            return false;
+        }
+
+        let cooled_down = inner.opened_at
+            .map(|(at, _)| at.elapsed() >= self.open_for)
+            .unwrap_or(true);
+        if cooled_down {
+            inner.state = BreakerState::HalfOpen;
+            inner.opened_at = Some((Instant::now(), Utc::now()));
+        }
+        cooled_down
+    }
+
+    /// Starts counting failures for a new refresh. Resources are fetched
+    /// concurrently, so a host that is down fails all of them at once;
+    /// they count as a single failure.
+    pub fn start_refresh(&self) {
+        self.inner.lock().unwrap().failed_in_refresh = false;
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.state = BreakerState::Closed;
+        inner.consecutive_failures = 0;
+        inner.opened_at = None;
+    }
+
+    /// Counts a failure, at most one per refresh. A failed probe opens the
+    /// breaker again right away.
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.failed_in_refresh && inner.state != BreakerState::HalfOpen {
+            return;
+        }
+        inner.failed_in_refresh = true;
+        inner.consecutive_failures = inner.consecutive_failures.saturating_add(1);
+
+        let trip = inner.state == BreakerState::HalfOpen
+            || inner.consecutive_failures >= self.failure_threshold;
+
+        if trip {
+            if inner.state != BreakerState::Open {
+                log::warn!("Circuit breaker opened after {} consecutive failed refreshes", inner.consecutive_failures);
+            }
+            inner.state = BreakerState::Open;
+            inner.opened_at = Some((Instant::now(), Utc::now()));
+        }
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        let inner = self.inner.lock().unwrap();
+        let open_until = match inner.state {
+            BreakerState::Open => inner.opened_at
+                .and_then(|(_, at)| chrono::Duration::from_std(self.open_for).ok().map(|d| at + d)),
+            _ => None,
+        };
+
+        BreakerStatus {
+            state: inner.state,
+            consecutive_failures: inner.consecutive_failures.try_into().unwrap_or(i32::MAX),
+            open_until,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(&CircuitBreakerConfig { failure_threshold: 3, open_secs: 30 })
+    }
+
+    fn fail_refresh(breaker: &CircuitBreaker) {
+        breaker.start_refresh();
+        breaker.record_failure();
+    }
+
+    fn trip(breaker: &CircuitBreaker) {
+        for _ in 0..3 {
+            fail_refresh(breaker);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn opens_after_consecutive_failures() {
+        let breaker = breaker();
+
+        fail_refresh(&breaker);
+        fail_refresh(&breaker);
+        breaker.record_success();
+        fail_refresh(&breaker);
+        fail_refresh(&breaker);
+        assert!(breaker.allow(), "a success resets the count");
+        assert!(!breaker.try_probe(), "nothing to probe while closed");
+
+        fail_refresh(&breaker);
+        assert!(!breaker.allow());
+        let status = breaker.status();
+        assert_eq!(status.state, BreakerState::Open);
+        assert_eq!(status.consecutive_failures, 3);
+        assert!(status.open_until.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn counts_one_failure_per_refresh() {
+        let breaker = breaker();
+
+        // Every resource of a refresh failing against a host that is down
+        for _ in 0..2 {
+            breaker.start_refresh();
+            for _ in 0..5 {
+                breaker.record_failure();
+            }
+        }
+        assert!(breaker.allow());
+        assert_eq!(breaker.status().consecutive_failures, 2);
+
+        fail_refresh(&breaker);
+        assert_eq!(breaker.status().state, BreakerState::Open);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn probes_once_after_the_cool_down() {
+        let breaker = breaker();
+        trip(&breaker);
+
+        tokio::time::advance(Duration::from_secs(29)).await;
+        assert!(!breaker.try_probe());
+        assert_eq!(breaker.status().state, BreakerState::Open);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(breaker.try_probe());
+        assert_eq!(breaker.status().state, BreakerState::HalfOpen);
+        // Everything else waits for the probe
+        assert!(!breaker.try_probe());
+        assert!(!breaker.allow());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_successful_probe_closes_the_breaker() {
+        let breaker = breaker();
+        trip(&breaker);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(breaker.try_probe());
+
+        breaker.record_success();
+        assert!(breaker.allow());
+        assert_eq!(breaker.status().state, BreakerState::Closed);
+        assert_eq!(breaker.status().consecutive_failures, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_probe_opens_the_breaker_again() {
+        let breaker = breaker();
+        trip(&breaker);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(breaker.try_probe());
+
+        breaker.record_failure();
+        assert_eq!(breaker.status().state, BreakerState::Open);
+        // For a full cool-down from the failed probe
+        tokio::time::advance(Duration::from_secs(29)).await;
+        assert!(!breaker.try_probe());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(breaker.try_probe());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_lost_probe_is_replaced() {
+        let breaker = breaker();
+        trip(&breaker);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(breaker.try_probe());
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(breaker.try_probe());
+        assert_eq!(breaker.status().state, BreakerState::HalfOpen);
+    }
+}
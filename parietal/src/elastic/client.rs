@@ -1,64 +1,386 @@
-use crate::elastic::data::{ClusterInfo, IndexInfo, Recovery, ShallowShard, NodeOutput};
-use crate::elastic::demo::DEMO_DATA;
-use crate::data::CONFIG;
+use crate::elastic::data::{AllocationExplanation, CatAllocation, ClusterInfo, ClusterSettings, DiskAllocation, DiskWatermarks, ExplainResponse, IndexInfo, Recovery, ShallowShard, NodeOutput, NodesHttp, RootInfo};
+use crate::elastic::auth::Credentials;
+use crate::elastic::breaker::{BreakerStatus, CircuitBreaker};
+use crate::elastic::error::ElasticError;
+use crate::elastic::hosts::HostPool;
+use crate::elastic::recorder::Recorder;
+use crate::elastic::source::{parse, ClusterSource, ExplainRequest, Resource};
+use crate::elastic::tls;
+use crate::elastic::version::{Endpoints, ServerVersion};
+use crate::config::{ElasticConfig, RetryConfig, TimeoutConfig};
 
 use anyhow::Result;
+use async_trait::async_trait;
+use chrono::Utc;
+use log::debug;
+use rand::Rng;
+use reqwest::StatusCode;
 use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+/// How long fetched disk watermarks are used before fetching them again.
+const WATERMARKS_TTL: Duration = Duration::from_secs(300);
 
 #[derive(Debug)]
 pub struct ElasticsearchClient {
     client: reqwest::Client,
-    base_url: String,
-    demo: bool,
+    hosts: HostPool,
+    auth: Option<Credentials>,
+    timeouts: TimeoutConfig,
+    retry: RetryConfig,
+    breaker: CircuitBreaker,
+    version: RwLock<Option<ServerVersion>>,
+    version_detected: AtomicBool,
+    include_hidden_indices: bool,
+    /// None inside when the cluster has no `_cluster/settings`
+    watermarks: RwLock<Option<(Instant, Option<DiskWatermarks>)>>,
+    recorder: Option<Recorder>,
 }
 
 impl ElasticsearchClient {
-    pub fn new(base_url: &str) -> Self {
-        let config = CONFIG.get().unwrap();
+    pub fn new(elastic: &ElasticConfig) -> Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(Duration::from_millis(elastic.timeouts.connect_ms));
+        if let Some(tls_config) = tls::client_config(&elastic.tls)? {
+            builder = builder.use_preconfigured_tls(tls_config);
+        }
+
+        Ok(ElasticsearchClient {
+            client: builder.build()?,
+            hosts: HostPool::new(
+                elastic.seeds(),
+                elastic.sniff.then(|| Duration::from_secs(elastic.sniff_interval_secs)),
+            )?,
+            auth: Credentials::from_config(&elastic.auth)?,
+            timeouts: elastic.timeouts.clone(),
+            retry: elastic.retry.clone(),
+            breaker: CircuitBreaker::new(&elastic.circuit_breaker),
+            version: RwLock::new(None),
+            version_detected: AtomicBool::new(false),
+            include_hidden_indices: elastic.include_hidden_indices,
+            watermarks: RwLock::new(None),
+            recorder: Recorder::new(&elastic.record)?,
+        })
+    }
+
+    pub fn version(&self) -> Option<ServerVersion> {
+        self.version.read().unwrap().clone()
+    }
+
+    fn endpoints(&self) -> Endpoints {
+        self.version.read().unwrap().as_ref()
+            .map(|version| version.endpoints(self.include_hidden_indices))
+            .unwrap_or_default()
+    }
+
+    /// Calls `GET /` to learn the distribution and version, unless that was
+    /// already done on this connection. Clusters that refuse the call (e.g.
+    /// restricted API keys) or answer it in an unexpected way (e.g. a proxy)
+    /// fall back to the default endpoints.
+    pub async fn detect_version(&self) -> Result<(), ElasticError> {
+        if self.version_detected.load(Ordering::Relaxed) {
+            return Ok(());
+        }
 
-        ElasticsearchClient {
-            client: reqwest::Client::new(),
-            base_url: base_url.to_string(),
-            demo: config.demo,
+        let version = self.fetch_and_parse::<RootInfo>("root", "").await.and_then(|root| {
+            ServerVersion::from_root(&root).ok_or_else(|| ElasticError::Deserialize {
+                endpoint: "/".to_string(),
+                path: "version.number".to_string(),
+                message: "invalid version number".to_string(),
+                excerpt: root.version.number.clone(),
+            })
+        });
+
+        match version {
+            Ok(version) => {
+                if !version.is_supported() {
+                    log::warn!("{:?} {} is not a supported version, some data may be missing", version.distribution, version.number);
+                }
+                log::info!("Connected to {:?} {}", version.distribution, version.number);
+                *self.version.write().unwrap() = Some(version);
+            }
+            Err(e @ (ElasticError::Status { .. } | ElasticError::Deserialize { .. })) => {
+                log::warn!("Could not detect cluster version, using default endpoints: {}", e);
+            }
+            Err(e) => return Err(e),
         }
+
+        self.version_detected.store(true, Ordering::Relaxed);
+        Ok(())
     }
 
     async fn fetch_and_parse<T: DeserializeOwned>(
         &self,
+        resource: &str,
         endpoint: &str,
-    ) -> Result<T> {
-        let url = format!("{}/{}", self.base_url, endpoint);
-        let response = match self.demo {
-            true => DEMO_DATA.iter().find(|(k, _)| k == &endpoint).unwrap().1.to_string(),
-            false => self.client.get(&url).send().await?.text().await?, 
-        };
-        let jd = &mut serde_json::Deserializer::from_str(&response);
+    ) -> Result<T, ElasticError> {
+        let response = self.request(resource, endpoint, None).await?;
+        parse(endpoint, &response)
+    }
+
+    /// GETs `endpoint`, or POSTs `body` to it, retrying transient failures
+    /// with backoff. Every request is guarded by the circuit breaker.
+    async fn request(&self, resource: &str, endpoint: &str, body: Option<&Value>) -> Result<String, ElasticError> {
+        if !self.breaker.allow() {
+            return Err(ElasticError::CircuitOpen { endpoint: endpoint.to_string() });
+        }
+
+        self.request_with_retries(resource, endpoint, body).await
+    }
+
+    /// Like `request`, but sent whatever the breaker state is. The outcome
+    /// is still recorded with the breaker.
+    async fn request_with_retries(&self, resource: &str, endpoint: &str, body: Option<&Value>) -> Result<String, ElasticError> {
+        let timeout = self.timeout(resource);
+        // Make sure every host gets a chance before giving up
+        let max_attempts = self.retry.max_retries.max((self.hosts.host_count() as u32).saturating_sub(1));
+        let mut attempt = 0;
+
+        loop {
+            let host = self.hosts.current();
+            let url = format!("{}/{}", host, endpoint);
+
+            match self.send(resource, endpoint, &url, body, timeout).await {
+                Ok(body) => {
+                    self.breaker.record_success();
+                    return Ok(body);
+                }
+                Err(e) if attempt < max_attempts && e.is_retryable() => {
+                    // A node that is up but overloaded or restarting is no
+                    // better to retry than one that is down
+                    self.hosts.fail_over(&host);
+                    let backoff = backoff(&self.retry, attempt);
+                    debug!("Retrying {} in {:?}: {}", endpoint, backoff, e);
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    // A 4xx means the cluster is up and answering, so it doesn't trip the breaker
+                    match e.is_retryable() {
+                        true => self.breaker.record_failure(),
+                        false => self.breaker.record_success(),
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Sends one request to the current host, without retries and outside
+    /// the circuit breaker. For optional calls whose failures say nothing
+    /// about whether the cluster data can be fetched.
+    async fn request_once(&self, resource: &str, endpoint: &str, body: Option<&Value>) -> Result<String, ElasticError> {
+        let url = format!("{}/{}", self.hosts.current(), endpoint);
+        self.send(resource, endpoint, &url, body, self.timeout(resource)).await
+    }
+
+    /// Like `fetch_and_parse`, but sent once like `request_once`. None when
+    /// the endpoint is missing, as on clusters without the feature.
+    async fn fetch_optional<T: DeserializeOwned>(&self, resource: &str, endpoint: &str) -> Result<Option<T>, ElasticError> {
+        match self.request_once(resource, endpoint, None).await {
+            Ok(response) => parse(endpoint, &response).map(Some),
+            Err(ElasticError::Status { status: 404, .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn timeout(&self, resource: &str) -> Duration {
+        Duration::from_millis(*self.timeouts.endpoints.get(resource).unwrap_or(&self.timeouts.request_ms))
+    }
+
+    /// Sends one request and records the response.
+    async fn send(&self, resource: &str, endpoint: &str, url: &str, body: Option<&Value>, timeout: Duration) -> Result<String, ElasticError> {
+        let (status, response) = self.exchange(url, body, timeout).await?;
+
+        if let Some(recorder) = &self.recorder {
+            recorder.record(resource, endpoint, status.as_u16(), &response);
+        }
+
+        checked(url, status, response)
+    }
+
+    async fn exchange(&self, url: &str, body: Option<&Value>, timeout: Duration) -> Result<(StatusCode, String), ElasticError> {
+        let mut request = match body {
+            Some(body) => self.client.post(url).json(body),
+            None => self.client.get(url),
+        }.timeout(timeout);
+        if let Some(auth) = &self.auth {
+            request = auth.apply(request);
+        }
+
+        let response = request.send().await.map_err(|e| ElasticError::from_reqwest(url, e))?;
+        let status = response.status();
+        let body = response.text().await.map_err(|e| ElasticError::from_reqwest(url, e))?;
+
+        Ok((status, body))
+    }
 
-        let result: Result<T, _> = serde_path_to_error::deserialize(jd);
+    /// Sends the half-open probe, `GET /`. Any answer from the cluster,
+    /// even a 4xx from a restricted API key, closes the breaker again.
+    async fn probe(&self) -> Result<(), ElasticError> {
+        debug!("Probing the cluster before closing the circuit breaker");
+        match self.request_with_retries("root", "", None).await {
+            Err(e) if e.is_retryable() => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// The disk watermarks, from `_cluster/settings` at most every
+    /// `WATERMARKS_TTL`. Settings are big and rarely change. When they
+    /// can't be fetched the last known watermarks are used, if any.
+    async fn watermarks(&self) -> Option<DiskWatermarks> {
+        if let Some((fetched_at, watermarks)) = &*self.watermarks.read().unwrap() {
+            if fetched_at.elapsed() < WATERMARKS_TTL {
+                return watermarks.clone();
+            }
+        }
+
+        match self.fetch_optional::<ClusterSettings>("settings", self.endpoints().settings).await {
+            Ok(settings) => {
+                let watermarks = settings.map(|settings| settings.disk_watermarks());
+                *self.watermarks.write().unwrap() = Some((Instant::now(), watermarks.clone()));
+                watermarks
+            }
+            Err(e) => {
+                log::warn!("Failed to fetch the disk watermarks: {}", e);
+                self.watermarks.read().unwrap().as_ref().and_then(|(_, watermarks)| watermarks.clone())
+            }
+        }
+    }
 
-        match result {
-            Ok(data) => Ok(data),
-            Err(e) => Err(anyhow::anyhow!(e)),
+    /// Refreshes the host list from `_nodes/http` when sniffing is enabled
+    /// and the sniff interval has passed. A failed sniff waits for the next
+    /// interval too, and is sent once, outside the circuit breaker, as the
+    /// seeds keep working without it.
+    pub async fn sniff(&self) -> Result<(), ElasticError> {
+        if !self.hosts.start_sniff() {
+            return Ok(());
         }
+
+        let endpoint = self.endpoints().nodes_http;
+        let nodes: NodesHttp = parse(endpoint, &self.request_once("sniff", endpoint, None).await?)?;
+        let addresses = nodes.nodes.into_values()
+            .filter_map(|node| node.http)
+            .map(|http| http.publish_address)
+            .collect();
+        self.hosts.update(addresses);
+
+        Ok(())
+    }
+}
+
+/// Error bodies never match the expected type, so don't try to parse them.
+fn checked(url: &str, status: StatusCode, body: String) -> Result<String, ElasticError> {
+    match status.is_success() {
+        true => Ok(body),
+        false => Err(ElasticError::from_status(url, status, &body)),
+    }
+}
+
+/// Full jitter: a random delay between zero and the exponential cap.
+fn backoff(retry: &RetryConfig, attempt: u32) -> Duration {
+    let cap = retry.initial_backoff_ms
+        .saturating_mul(2u64.saturating_pow(attempt))
+        .min(retry.max_backoff_ms);
+    Duration::from_millis(rand::thread_rng().gen_range(0..=cap))
+}
+
+#[async_trait]
+impl ClusterSource for ElasticsearchClient {
+    async fn health(&self) -> Result<ClusterInfo, ElasticError> {
+        let mut health: ClusterInfo = self.fetch_and_parse(Resource::Health.name(), self.endpoints().health).await?;
+        health.set_version(self.version().as_ref());
+        Ok(health)
     }
 
-    pub async fn health(&self) -> Result<ClusterInfo> {
-        self.fetch_and_parse("_cluster/health").await
+    async fn indices(&self) -> Result<Vec<IndexInfo>, ElasticError> {
+        self.fetch_and_parse(Resource::Indices.name(), self.endpoints().indices).await
     }
 
-    pub async fn indices(&self) -> Result<Vec<IndexInfo>> {
-        self.fetch_and_parse("_cat/indices?format=json").await
+    async fn recovery(&self) -> Result<Recovery, ElasticError> {
+        self.fetch_and_parse(Resource::Recovery.name(), self.endpoints().recovery).await
     }
 
-    pub async fn recovery(&self) -> Result<Recovery> {
-        self.fetch_and_parse("_recovery?format=json&active_only=true").await
+    async fn shards(&self) -> Result<Vec<ShallowShard>, ElasticError> {
+        self.fetch_and_parse(Resource::Shards.name(), self.endpoints().shards).await
     }
 
-    pub async fn shards(&self) -> Result<Vec<ShallowShard>> {
-        self.fetch_and_parse("_cat/shards?format=json").await
+    async fn nodes(&self) -> Result<NodeOutput, ElasticError> {
+        self.fetch_and_parse(Resource::Nodes.name(), self.endpoints().nodes).await
     }
 
-    pub async fn nodes(&self) -> Result<NodeOutput> {
-        self.fetch_and_parse("_nodes/stats/fs,process,os?format=json").await
+    async fn allocation_explain(&self, request: &ExplainRequest) -> Result<Option<AllocationExplanation>, ElasticError> {
+        let endpoint = self.endpoints().allocation_explain;
+        let body = serde_json::to_value(request).unwrap_or_default();
+        let url = format!("{}/{}", self.hosts.current(), endpoint);
+        // Sent once, outside the breaker, like `request_once`. Explains run
+        // in the background, one per shard copy, so they belong to no
+        // recorded snapshot and aren't recorded.
+        let (status, response) = self.exchange(&url, Some(&body), self.timeout("allocation_explain")).await?;
+        let response: ExplainResponse = parse(endpoint, &checked(&url, status, response)?)?;
+        Ok(Some(response.into_explanation(Utc::now())))
+    }
+
+    /// Sent once and outside the breaker, as the nodes are complete
+    /// without it.
+    async fn disk_allocation(&self) -> Result<Option<DiskAllocation>, ElasticError> {
+        let (nodes, watermarks) = tokio::join!(
+            self.fetch_optional::<Vec<CatAllocation>>("allocation", self.endpoints().allocation),
+            self.watermarks(),
+        );
+
+        Ok(nodes?.map(|nodes| DiskAllocation { nodes, watermarks }))
+    }
+
+    async fn prepare(&self) -> Result<(), ElasticError> {
+        if let Some(recorder) = &self.recorder {
+            recorder.start_snapshot();
+        }
+        self.breaker.start_refresh();
+
+        // After the cool-down, a single request decides whether the cluster
+        // is back before the resources are fetched concurrently
+        if self.breaker.try_probe() {
+            self.probe().await?;
+        }
+        if !self.breaker.allow() {
+            return Err(ElasticError::CircuitOpen { endpoint: "/".to_string() });
+        }
+
+        if let Err(e) = self.sniff().await {
+            log::warn!("Failed to sniff nodes: {}", e);
+        }
+
+        self.detect_version().await
+    }
+
+    /// Asks for the version again on reconnect, as it may have changed
+    /// during a rolling upgrade.
+    fn disconnected(&self) {
+        self.version_detected.store(false, Ordering::Relaxed);
+    }
+
+    fn breaker_status(&self) -> BreakerStatus {
+        self.breaker.status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_the_cap() {
+        let retry = RetryConfig { max_retries: 10, initial_backoff_ms: 100, max_backoff_ms: 1_000 };
+
+        for (attempt, cap) in [(0, 100), (1, 200), (2, 400), (3, 800), (4, 1_000), (60, 1_000)] {
+            let delays: Vec<_> = (0..200).map(|_| backoff(&retry, attempt)).collect();
+            assert!(delays.iter().all(|delay| *delay <= Duration::from_millis(cap)), "attempt {}", attempt);
+            // Jittered rather than always the cap
+            assert!(delays.iter().any(|delay| *delay < Duration::from_millis(cap)), "attempt {}", attempt);
+        }
     }
 }
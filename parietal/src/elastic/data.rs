@@ -1,12 +1,15 @@
 use std::collections::HashMap;
-use bigdecimal::BigDecimal;
+use bigdecimal::{BigDecimal, ToPrimitive};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value};
 use anyhow::Result;
 use ts_rs::TS;
 use std::fmt;
 use juniper::GraphQLObject;
+use crate::elastic::version::{Distribution, ServerVersion};
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, Default, TS)]
 #[graphql(description = "Basic cluster information, such as health and status")]
 #[ts(export)]
 pub struct ClusterInfo {
@@ -25,16 +28,54 @@ pub struct ClusterInfo {
     number_of_in_flight_fetch: i32,
     task_max_waiting_in_queue_millis: i32,
     active_shards_percent_as_number: f64,
+    #[serde(default)]
+    distribution: Option<Distribution>,
+    #[serde(default)]
+    version: Option<String>,
 }
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+impl ClusterInfo {
+    /// Placeholder served until the first successful fetch.
+    pub fn unknown() -> Self {
+        ClusterInfo {
+            status: "unknown".to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn status(&self) -> &str {
+        &self.status
+    }
+
+    /// Whether shards are relocating, initializing or waiting for a node.
+    pub fn has_moving_shards(&self) -> bool {
+        self.relocating_shards > 0 || self.initializing_shards > 0 || self.unassigned_shards > 0
+    }
+
+    pub fn task_max_waiting_in_queue_millis(&self) -> i32 {
+        self.task_max_waiting_in_queue_millis
+    }
+
+    /// `_cluster/health` doesn't report the version, so it is filled in from
+    /// what the client detected on connect.
+    pub fn set_version(&mut self, version: Option<&ServerVersion>) {
+        self.distribution = version.map(|v| v.distribution);
+        self.version = version.map(|v| v.number.clone());
+    }
+}
+
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
 #[ts(export)]
 pub struct IndexInfo {
+    // Closed indices have no health, and before 7.2 no shard counts either
+    #[serde(deserialize_with = "null_as_default")]
     health: String,
     status: String,
     index: String,
     uuid: String,
+    #[serde(deserialize_with = "null_as_default")]
     pri: String,
+    #[serde(deserialize_with = "null_as_default")]
     rep: String,
     #[serde(alias = "docs.count")]
     docs_count: Option<String>,
@@ -46,11 +87,53 @@ pub struct IndexInfo {
     pri_store_size: Option<String>,
 }
 
-#[derive(GraphQLObject, Serialize, Debug, Clone)]
+impl IndexInfo {
+    pub fn name(&self) -> &str {
+        &self.index
+    }
+}
+
+#[derive(GraphQLObject, Serialize, Debug, Clone, PartialEq, Default)]
 pub struct Recovery {
     indices: Vec<IndexRecovery>
 }
 
+impl Recovery {
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    pub fn indices(&self) -> &[IndexRecovery] {
+        &self.indices
+    }
+
+    /// Ongoing shard recoveries with the name of their index.
+    pub fn shards(&self) -> impl Iterator<Item = (&str, &RecoveryShard)> {
+        self.indices.iter().flat_map(|index| index.shards.iter().map(|shard| (index.id.as_str(), shard)))
+    }
+
+    /// Number of shards being recovered.
+    pub fn shard_count(&self) -> usize {
+        self.indices.iter().map(|index| index.shards.len()).sum()
+    }
+
+    /// Only the indices whose name passes `keep`.
+    pub fn filter(&self, keep: impl Fn(&str) -> bool) -> Recovery {
+        Recovery {
+            indices: self.indices.iter().filter(|index| keep(&index.id)).cloned().collect(),
+        }
+    }
+
+    /// Back in the `_recovery` response format, which is what
+    /// `Deserialize` reads.
+    pub fn to_response(&self) -> Value {
+        let indices = self.indices.iter()
+            .map(|index| (index.id.clone(), json!({ "shards": index.shards })));
+
+        Value::Object(indices.collect())
+    }
+}
+
 impl<'de> Deserialize<'de> for Recovery {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -75,17 +158,17 @@ impl<'de> Deserialize<'de> for Recovery {
                     value.id = key;
                     indices.push(value);
                 }
+                // Sorted like `to_response` writes them, so both parse the same
+                indices.sort_by(|a, b| a.id.cmp(&b.id));
                 Ok(Recovery { indices })
             }
-
-            
         }
 
         deserializer.deserialize_map(RecoveryVisitor)
     }
 }
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
 #[ts(export)]
 pub struct IndexRecovery {
     #[serde(skip)]
@@ -93,7 +176,7 @@ pub struct IndexRecovery {
     shards: Vec<RecoveryShard>,
 }
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
 #[ts(export)]
 pub struct RecoveryShard {
     #[serde(alias = "type")]
@@ -109,7 +192,32 @@ pub struct RecoveryShard {
     verify_index: VerifyIndexInfo,
 }
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+impl IndexRecovery {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl RecoveryShard {
+    /// Recovery type, e.g. `PEER` or `EXISTING_STORE`.
+    pub fn recovery_type(&self) -> &str {
+        &self.shard_type
+    }
+
+    pub fn primary(&self) -> bool {
+        self.primary
+    }
+
+    pub fn source_node(&self) -> &str {
+        &self.source.name
+    }
+
+    pub fn target_node(&self) -> &str {
+        &self.target.name
+    }
+}
+
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
 #[ts(export)]
 struct NodeTargetInfo {
     id: String,
@@ -119,7 +227,7 @@ struct NodeTargetInfo {
     name: String,
 }
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
 #[ts(export)]
 struct TransportIndexInfo {
     size: SizeInfo,
@@ -129,17 +237,19 @@ struct TransportIndexInfo {
     target_throttle_time_in_millis: i32,
 }
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
 #[ts(export)]
 struct SizeInfo {
     total_in_bytes: BigDecimal,
     reused_in_bytes: BigDecimal,
     recovered_in_bytes: BigDecimal,
+    // Only reported by Elasticsearch 7.15 and later
+    #[serde(default)]
     recovered_from_snapshot_in_bytes: BigDecimal,
     percent: String, // This can sometimes be a string like "18.2%"
 }
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
 #[ts(export)]
 struct FilesInfo {
     total: i32,
@@ -148,7 +258,7 @@ struct FilesInfo {
     percent: String, // This can sometimes be a string like "91.6%"
 }
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
 #[ts(export)]
 struct TranslogInfo {
     recovered: i32,
@@ -158,14 +268,14 @@ struct TranslogInfo {
     total_time_in_millis: i32,
 }
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
 #[ts(export)]
 struct VerifyIndexInfo {
     check_index_time_in_millis: i32,
     total_time_in_millis: i32,
 }
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, TS)]
 #[ts(export)]
 pub struct ShallowShard {
     pub index: String,
@@ -176,9 +286,127 @@ pub struct ShallowShard {
     pub store: Option<String>,
     pub ip: Option<String>,
     pub node: Option<String>,
+    /// Why the copy is unassigned or stuck, when it has been explained
+    pub allocation_explanation: Option<AllocationExplanation>,
 }
 
-#[derive(GraphQLObject, Serialize, Debug, Clone, TS)]
+impl ShallowShard {
+    /// Node the copy is on, or relocating away from.
+    pub fn current_node(&self) -> Option<&str> {
+        // Relocating shards are listed as "source -> ip id target"
+        let node = self.node.as_deref()?;
+        Some(node.split_once(" -> ").map_or(node, |(source, _)| source))
+    }
+}
+
+/// What `_cluster/allocation/explain` said about one shard copy.
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, TS)]
+#[ts(export)]
+pub struct AllocationExplanation {
+    /// e.g. `unassigned`, `initializing` or `relocating`
+    pub current_state: String,
+    /// Why the copy became unassigned, e.g. `NODE_LEFT`
+    pub unassigned_reason: Option<String>,
+    pub unassigned_details: Option<String>,
+    pub unassigned_at: Option<String>,
+    /// For unassigned copies, e.g. `yes`, `no`, `throttled` or
+    /// `no_valid_shard_copy`
+    pub can_allocate: Option<String>,
+    /// For assigned copies
+    pub can_remain_on_current_node: Option<String>,
+    pub explanation: Option<String>,
+    /// Deciders that don't let the copy stay on its current node
+    pub can_remain_decisions: Vec<DeciderVerdict>,
+    pub nodes: Vec<NodeAllocation>,
+    /// The `can_allocate` verdict, or the decider that said no on the
+    /// most nodes, e.g. `same_shard` or `disk_threshold`
+    pub root_cause: String,
+    pub explained_at: DateTime<Utc>,
+}
+
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, TS)]
+#[ts(export)]
+pub struct NodeAllocation {
+    pub node_id: String,
+    pub node_name: String,
+    /// e.g. `yes`, `no`, `throttled` or `worse_balance`
+    #[serde(alias = "node_decision")]
+    pub decision: String,
+    pub weight_ranking: Option<i32>,
+    /// Only deciders that didn't say yes
+    #[serde(default)]
+    pub deciders: Vec<DeciderVerdict>,
+}
+
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, TS)]
+#[ts(export)]
+pub struct DeciderVerdict {
+    pub decider: String,
+    /// `YES`, `NO` or `THROTTLE`
+    pub decision: String,
+    pub explanation: String,
+}
+
+/// A `_cluster/allocation/explain` response.
+#[derive(Deserialize, Debug)]
+pub struct ExplainResponse {
+    current_state: String,
+    unassigned_info: Option<UnassignedInfo>,
+    can_allocate: Option<String>,
+    allocate_explanation: Option<String>,
+    can_remain_on_current_node: Option<String>,
+    #[serde(default)]
+    can_remain_decisions: Vec<DeciderVerdict>,
+    rebalance_explanation: Option<String>,
+    /// Set instead of the others for copies still initializing or relocating
+    explanation: Option<String>,
+    #[serde(default)]
+    node_allocation_decisions: Vec<NodeAllocation>,
+}
+
+#[derive(Deserialize, Debug)]
+struct UnassignedInfo {
+    reason: String,
+    at: Option<String>,
+    details: Option<String>,
+}
+
+impl ExplainResponse {
+    pub fn into_explanation(self, explained_at: DateTime<Utc>) -> AllocationExplanation {
+        let verdicts = match self.can_allocate.is_some() {
+            true => self.node_allocation_decisions.iter().flat_map(|node| &node.deciders).collect::<Vec<_>>(),
+            false => self.can_remain_decisions.iter().collect(),
+        };
+        let mut refusals: HashMap<&str, usize> = HashMap::new();
+        for verdict in verdicts.into_iter().filter(|verdict| verdict.decision == "NO") {
+            *refusals.entry(&verdict.decider).or_default() += 1;
+        }
+        // Ties go to the first decider by name, so the cause doesn't flap
+        let refusal = refusals.into_iter().max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(a.0))).map(|(decider, _)| decider.to_string());
+
+        let root_cause = match (self.can_allocate.as_deref(), self.can_remain_on_current_node.as_deref()) {
+            (Some("no"), _) | (None, Some("no")) => refusal.unwrap_or_else(|| "no_eligible_node".to_string()),
+            (Some(verdict), _) => verdict.to_string(),
+            (None, _) => self.current_state.clone(),
+        };
+
+        AllocationExplanation {
+            current_state: self.current_state,
+            unassigned_reason: self.unassigned_info.as_ref().map(|info| info.reason.clone()),
+            unassigned_details: self.unassigned_info.as_ref().and_then(|info| info.details.clone()),
+            unassigned_at: self.unassigned_info.and_then(|info| info.at),
+            can_allocate: self.can_allocate,
+            can_remain_on_current_node: self.can_remain_on_current_node,
+            explanation: self.allocate_explanation.or(self.rebalance_explanation).or(self.explanation),
+            can_remain_decisions: self.can_remain_decisions,
+            nodes: self.node_allocation_decisions,
+            root_cause,
+            explained_at,
+        }
+    }
+}
+
+#[derive(GraphQLObject, Serialize, Debug, Clone, PartialEq, Default, TS)]
 #[ts(export)]
 pub struct NodeOutput {
     pub nodes: Vec<NodeInfo>,
@@ -196,17 +424,36 @@ impl<'de> Deserialize<'de> for NodeOutput {
 
         let raw_node_output = RawNodeOutput::deserialize(deserializer)?;
 
-        // Convert HashMap<String, NodeInfo> to Vec<NodeInfo>
-        let nodes: Vec<NodeInfo> = raw_node_output.nodes.into_iter().map(|(_, v)| v).collect();
+        // Convert HashMap<String, NodeInfo> to Vec<NodeInfo>, in a stable order
+        let mut nodes: Vec<NodeInfo> = raw_node_output.nodes.into_iter()
+            .map(|(id, node)| NodeInfo { id, ..node })
+            .collect();
+        nodes.sort_by(|a, b| (&a.name, &a.transport_address, &a.id).cmp(&(&b.name, &b.transport_address, &b.id)));
 
         Ok(NodeOutput { nodes })
     }
 }
 
+impl NodeOutput {
+    /// Back in the `_nodes` response format, which is what `Deserialize`
+    /// reads, keyed by node ID.
+    pub fn to_response(&self) -> Value {
+        let nodes = self.nodes.iter().map(|node| {
+            let mut value = serde_json::to_value(node).unwrap_or_default();
+            let attributes = node.attributes.iter().map(|a| (a.key.clone(), Value::from(a.value.clone())));
+            value["attributes"] = Value::Object(attributes.collect());
+            (node.id.clone(), value)
+        });
+
+        json!({ "nodes": Value::Object(nodes.collect()) })
+    }
+}
 
-#[derive(GraphQLObject, Serialize, Debug, Clone, TS)]
+#[derive(GraphQLObject, Serialize, Debug, Clone, PartialEq, TS)]
 #[ts(export)]
 pub struct NodeInfo {
+    /// Key of the node in `_nodes`. Unlike the name, unique in a cluster.
+    id: String,
     name: String,
     transport_address: String,
     host: String,
@@ -219,6 +466,112 @@ pub struct NodeInfo {
     os: NodeOS,
 }
 
+impl NodeInfo {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Total and available bytes of the node's data paths.
+    pub fn disk_space(&self) -> Option<(f64, f64)> {
+        Some((self.fs.total.total_in_bytes.to_f64()?, self.fs.total.available_in_bytes.to_f64()?))
+    }
+
+    /// Share of the disk that is not available, as Elasticsearch computes
+    /// it for its disk watermarks.
+    pub fn disk_used_percent(&self) -> Option<f64> {
+        let (total, available) = self.disk_space()?;
+
+        (total > 0.0).then(|| (total - available) / total * 100.0)
+    }
+}
+
+/// `_cat/allocation` and the disk watermarks in effect, fetched along with
+/// the nodes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct DiskAllocation {
+    pub nodes: Vec<CatAllocation>,
+    pub watermarks: Option<DiskWatermarks>,
+}
+
+/// A row of `_cat/allocation?bytes=b`. Unassigned shards get a row of
+/// their own, with `UNASSIGNED` as the node and no disk figures.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CatAllocation {
+    pub node: String,
+    pub shards: Option<String>,
+    #[serde(rename = "disk.indices")]
+    pub disk_indices: Option<String>,
+    #[serde(rename = "disk.used")]
+    pub disk_used: Option<String>,
+    #[serde(rename = "disk.avail")]
+    pub disk_avail: Option<String>,
+    #[serde(rename = "disk.total")]
+    pub disk_total: Option<String>,
+    pub host: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// Disk watermarks as configured: a share of the disk like `85%` or
+/// `0.85`, or the space to keep free like `500gb`.
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
+#[ts(export)]
+pub struct DiskWatermarks {
+    /// `cluster.routing.allocation.disk.threshold_enabled`
+    pub enabled: bool,
+    pub low: String,
+    pub high: String,
+    pub flood_stage: String,
+    /// Most free space a share watermark asks for, since 8.5
+    pub low_max_headroom: Option<String>,
+    pub high_max_headroom: Option<String>,
+    pub flood_stage_max_headroom: Option<String>,
+}
+
+/// A `_cluster/settings?include_defaults=true&flat_settings=true` response.
+#[derive(Deserialize, Debug, Default)]
+pub struct ClusterSettings {
+    #[serde(default)]
+    persistent: HashMap<String, Value>,
+    #[serde(default)]
+    transient: HashMap<String, Value>,
+    #[serde(default)]
+    defaults: HashMap<String, Value>,
+}
+
+impl ClusterSettings {
+    /// The value in effect: transient over persistent over the default.
+    fn get(&self, key: &str) -> Option<&str> {
+        [&self.transient, &self.persistent, &self.defaults].into_iter()
+            .find_map(|settings| settings.get(key))
+            .and_then(Value::as_str)
+    }
+
+    /// The watermarks in effect, the Elasticsearch defaults for any that
+    /// aren't listed.
+    pub fn disk_watermarks(&self) -> DiskWatermarks {
+        let setting = |name: String| self.get(&format!("cluster.routing.allocation.disk.{}", name));
+        let watermark = |level: &str, default: &str| setting(format!("watermark.{}", level)).unwrap_or(default).to_string();
+        // -1 is the default once the watermark itself is set
+        let headroom = |level: &str| setting(format!("watermark.{}.max_headroom", level))
+            .filter(|headroom| *headroom != "-1")
+            .map(str::to_string);
+
+        DiskWatermarks {
+            enabled: setting("threshold_enabled".to_string()) != Some("false"),
+            low: watermark("low", "85%"),
+            high: watermark("high", "90%"),
+            flood_stage: watermark("flood_stage", "95%"),
+            low_max_headroom: headroom("low"),
+            high_max_headroom: headroom("high"),
+            flood_stage_max_headroom: headroom("flood_stage"),
+        }
+    }
+}
+
 impl<'de> Deserialize<'de> for NodeInfo {
 
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
@@ -232,7 +585,8 @@ impl<'de> Deserialize<'de> for NodeInfo {
             host: String,
             ip: String,
             roles: Vec<String>,
-            attributes: std::collections::HashMap<String, String>,
+            #[serde(default)]
+            attributes: std::collections::BTreeMap<String, String>,
             process: NodeProcess,
             fs: NodeFileSystem,
             os: NodeOS,
@@ -241,13 +595,22 @@ impl<'de> Deserialize<'de> for NodeInfo {
         let raw = RawNodeInfo::deserialize(deserializer)?;
 
         let attributes = raw.attributes.into_iter().map(|(k, v)| NodeAttribute { key: k, value: v }).collect();
+        // OpenSearch 2 renamed the master role, read both the same
+        let roles = raw.roles.into_iter()
+            .map(|role| match role.as_str() {
+                "cluster_manager" => "master".to_string(),
+                _ => role,
+            })
+            .collect();
 
         Ok(NodeInfo {
+            // Filled in from the key by `NodeOutput`
+            id: String::new(),
             name: raw.name,
             transport_address: raw.transport_address,
             host: raw.host,
             ip: raw.ip,
-            roles: raw.roles,
+            roles,
             attributes,
             process: raw.process,
             fs: raw.fs,
@@ -256,7 +619,7 @@ impl<'de> Deserialize<'de> for NodeInfo {
     }
 }
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
 #[ts(export)]
 pub struct NodeOS {
     mem: NodeOSMemory,
@@ -264,7 +627,7 @@ pub struct NodeOS {
     cpu: NodeCpu,
 }
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
 #[ts(export)]
 pub struct NodeProcess {
     timestamp: BigDecimal,
@@ -272,21 +635,22 @@ pub struct NodeProcess {
     mem: NodeProcessMemory,
 }
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
 #[ts(export)]
 pub struct NodeCpu {
     pub percent: i32,
 }
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
 #[ts(export)]
 pub struct NodeFileSystem {
     pub total: NodeFSTotal,
-    #[serde(alias = "io_stats")]
+    // io_stats is only reported on Linux
+    #[serde(alias = "io_stats", default)]
     pub stats: NodeFSStats,
 }
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
 #[ts(export)]
 pub struct NodeFSTotal {
     pub total_in_bytes: BigDecimal,
@@ -294,13 +658,13 @@ pub struct NodeFSTotal {
     available_in_bytes: BigDecimal,
 }
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, Default, TS)]
 #[ts(export)]
 pub struct NodeFSStats {
     total: Option<NodeFSStatsTotal>,
 }
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
 #[ts(export)]
 pub struct NodeFSStatsTotal {
     pub operations: BigDecimal,
@@ -310,13 +674,13 @@ pub struct NodeFSStatsTotal {
     pub write_kilobytes: BigDecimal,
 }
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
 #[ts(export)]
 pub struct NodeProcessMemory {
     total_virtual_in_bytes: BigDecimal,
 }
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
 #[ts(export)]
 pub struct NodeOSMemory {
     total_in_bytes: BigDecimal,
@@ -326,8 +690,46 @@ pub struct NodeOSMemory {
     used_percent: Option<i32>,
 }
 
-#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone, PartialEq, TS)]
 pub struct NodeAttribute {
     key: String,
     value: String,
 }
+
+/// Response of `_nodes/http`, used for sniffing.
+#[derive(Deserialize, Debug)]
+pub struct NodesHttp {
+    pub nodes: HashMap<String, NodeHttpInfo>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NodeHttpInfo {
+    pub http: Option<NodeHttp>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NodeHttp {
+    pub publish_address: String,
+}
+
+/// Response of `GET /`, used for version detection.
+#[derive(Deserialize, Debug)]
+pub struct RootInfo {
+    pub version: RootVersion,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RootVersion {
+    pub number: String,
+    /// Only set by OpenSearch, as "opensearch"
+    pub distribution: Option<String>,
+}
+
+/// Reads `null` as the default, for cat columns left empty.
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
@@ -0,0 +1,286 @@
+use juniper::{graphql_value, FieldError, GraphQLEnum, GraphQLObject, IntoFieldError, ScalarValue};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// How much of a response body is kept for error messages.
+const EXCERPT_LEN: usize = 200;
+
+#[derive(Debug)]
+pub enum ElasticError {
+    /// The request could not be sent or the response could not be read.
+    Transport { url: String, source: reqwest::Error },
+    Timeout { url: String },
+    /// Elasticsearch answered with a non-success status, usually with an
+    /// `{"error": {"type": ..., "reason": ...}}` body.
+    Status {
+        url: String,
+        status: u16,
+        error_type: Option<String>,
+        reason: Option<String>,
+    },
+    /// The response didn't match the expected shape.
+    Deserialize {
+        endpoint: String,
+        path: String,
+        message: String,
+        excerpt: String,
+    },
+    CircuitOpen { endpoint: String },
+    /// A fixture-backed source has no data for the resource.
+    MissingFixture { origin: String, resource: &'static str },
+}
+
+impl ElasticError {
+    pub fn from_reqwest(url: &str, source: reqwest::Error) -> Self {
+        match source.is_timeout() {
+            true => ElasticError::Timeout { url: url.to_string() },
+            false => ElasticError::Transport { url: url.to_string(), source },
+        }
+    }
+
+    pub fn from_status(url: &str, status: reqwest::StatusCode, body: &str) -> Self {
+        #[derive(Deserialize)]
+        struct ErrorBody {
+            error: ErrorDetail,
+        }
+
+        // Very old versions and some proxies return the error as a plain string
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum ErrorDetail {
+            Structured {
+                #[serde(rename = "type")]
+                error_type: Option<String>,
+                reason: Option<String>,
+            },
+            Plain(String),
+        }
+
+        let (error_type, reason) = match serde_json::from_str::<ErrorBody>(body) {
+            Ok(ErrorBody { error: ErrorDetail::Structured { error_type, reason } }) => (error_type, reason),
+            Ok(ErrorBody { error: ErrorDetail::Plain(reason) }) => (None, Some(reason)),
+            Err(_) => (None, (!body.trim().is_empty()).then(|| excerpt(body))),
+        };
+
+        ElasticError::Status {
+            url: url.to_string(),
+            status: status.as_u16(),
+            error_type,
+            reason,
+        }
+    }
+
+    pub fn from_deserialize(endpoint: &str, error: serde_path_to_error::Error<serde_json::Error>, body: &str) -> Self {
+        ElasticError::Deserialize {
+            endpoint: endpoint.to_string(),
+            path: error.path().to_string(),
+            message: error.into_inner().to_string(),
+            excerpt: excerpt(body),
+        }
+    }
+
+    /// Whether trying again (possibly on another host) could help.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ElasticError::Transport { .. } | ElasticError::Timeout { .. } => true,
+            ElasticError::Status { status, .. } => *status >= 500 || *status == 429,
+            ElasticError::Deserialize { .. }
+            | ElasticError::CircuitOpen { .. }
+            | ElasticError::MissingFixture { .. } => false,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ElasticError::Transport { .. } => ErrorKind::Transport,
+            ElasticError::Timeout { .. } => ErrorKind::Timeout,
+            ElasticError::Status { .. } => ErrorKind::Status,
+            ElasticError::Deserialize { .. } => ErrorKind::Deserialize,
+            ElasticError::CircuitOpen { .. } => ErrorKind::CircuitOpen,
+            ElasticError::MissingFixture { .. } => ErrorKind::MissingFixture,
+        }
+    }
+
+    pub fn info(&self) -> ErrorInfo {
+        let mut info = ErrorInfo {
+            kind: self.kind(),
+            message: self.to_string(),
+            url: None,
+            status: None,
+            error_type: None,
+            reason: None,
+            path: None,
+            excerpt: None,
+        };
+
+        match self {
+            ElasticError::Transport { url, .. } | ElasticError::Timeout { url } => {
+                info.url = Some(url.clone());
+            }
+            ElasticError::Status { url, status, error_type, reason } => {
+                info.url = Some(url.clone());
+                info.status = Some(*status as i32);
+                info.error_type = error_type.clone();
+                info.reason = reason.clone();
+            }
+            ElasticError::Deserialize { path, excerpt, .. } => {
+                info.path = Some(path.clone());
+                info.excerpt = Some(excerpt.clone());
+            }
+            ElasticError::CircuitOpen { .. } | ElasticError::MissingFixture { .. } => {}
+        }
+
+        info
+    }
+}
+
+impl fmt::Display for ElasticError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElasticError::Transport { url, source } => {
+                write!(f, "Request to {} failed", url)?;
+                // reqwest keeps the useful part (e.g. "Connection refused") deep in the source chain
+                let mut cause: Option<&dyn std::error::Error> = Some(source);
+                while let Some(e) = cause {
+                    write!(f, ": {}", e)?;
+                    cause = e.source();
+                }
+                Ok(())
+            }
+            ElasticError::Timeout { url } => write!(f, "Request to {} timed out", url),
+            ElasticError::Status { url, status, error_type, reason } => {
+                write!(f, "{} returned HTTP {}", url, status)?;
+                match (error_type, reason) {
+                    (Some(error_type), Some(reason)) => write!(f, ": {}: {}", error_type, reason),
+                    (Some(detail), None) | (None, Some(detail)) => write!(f, ": {}", detail),
+                    (None, None) => Ok(()),
+                }
+            }
+            ElasticError::Deserialize { endpoint, path, message, excerpt } => {
+                write!(f, "Unexpected response from {} at {}: {} (response: {})", endpoint, path, message, excerpt)
+            }
+            ElasticError::CircuitOpen { endpoint } => write!(f, "Circuit breaker is open, skipping {}", endpoint),
+            ElasticError::MissingFixture { origin, resource } => write!(f, "No {} fixture in {}", resource, origin),
+        }
+    }
+}
+
+impl std::error::Error for ElasticError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ElasticError::Transport { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[derive(GraphQLEnum, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Transport,
+    Timeout,
+    Status,
+    Deserialize,
+    CircuitOpen,
+    MissingFixture,
+}
+
+/// Serializable summary of an `ElasticError`, for the API.
+#[derive(GraphQLObject, Serialize, Debug, Clone)]
+#[graphql(description = "An error returned while talking to the cluster")]
+pub struct ErrorInfo {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub url: Option<String>,
+    pub status: Option<i32>,
+    /// Elasticsearch `error.type`, e.g. `security_exception`
+    pub error_type: Option<String>,
+    pub reason: Option<String>,
+    /// Path to the field that failed to deserialize
+    pub path: Option<String>,
+    pub excerpt: Option<String>,
+}
+
+impl<S: ScalarValue> IntoFieldError<S> for ErrorInfo {
+    fn into_field_error(self) -> FieldError<S> {
+        let kind = serde_json::to_value(self.kind).ok().and_then(|kind| kind.as_str().map(str::to_string));
+
+        FieldError::new(
+            self.message,
+            graphql_value!({
+                "kind": kind,
+                "status": self.status,
+                "errorType": self.error_type,
+                "reason": self.reason,
+            }),
+        )
+    }
+}
+
+fn excerpt(body: &str) -> String {
+    match body.char_indices().nth(EXCERPT_LEN) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use juniper::DefaultScalarValue;
+    use reqwest::StatusCode;
+
+    #[test]
+    fn reads_the_elasticsearch_error_body() {
+        let body = r#"{
+            "error": {
+                "root_cause": [{ "type": "index_not_found_exception", "reason": "no such index [logs]", "index": "logs" }],
+                "type": "index_not_found_exception",
+                "reason": "no such index [logs]",
+                "index": "logs"
+            },
+            "status": 404
+        }"#;
+
+        let error = ElasticError::from_status("http://es:9200/logs", StatusCode::NOT_FOUND, body);
+        let info = error.info();
+        assert_eq!(info.kind, ErrorKind::Status);
+        assert_eq!(info.status, Some(404));
+        assert_eq!(info.error_type.as_deref(), Some("index_not_found_exception"));
+        assert_eq!(info.reason.as_deref(), Some("no such index [logs]"));
+        assert_eq!(error.to_string(), "http://es:9200/logs returned HTTP 404: index_not_found_exception: no such index [logs]");
+        assert!(!error.is_retryable());
+    }
+
+    #[test]
+    fn keeps_an_excerpt_of_other_bodies() {
+        let body = format!("<html><body>502 Bad Gateway</body></html>{}", " ".repeat(EXCERPT_LEN));
+
+        let error = ElasticError::from_status("http://proxy/", StatusCode::BAD_GATEWAY, &body);
+        let info = error.info();
+        assert_eq!(info.error_type, None);
+        let reason = info.reason.unwrap();
+        assert!(reason.starts_with("<html><body>502 Bad Gateway"));
+        assert!(reason.ends_with("..."));
+        assert!(error.is_retryable());
+
+        let error = ElasticError::from_status("http://proxy/", StatusCode::BAD_GATEWAY, "  ");
+        assert_eq!(error.info().reason, None);
+        assert_eq!(error.to_string(), "http://proxy/ returned HTTP 502");
+    }
+
+    #[test]
+    fn graphql_errors_carry_the_details_as_extensions() {
+        let body = r#"{"error":{"type":"security_exception","reason":"missing authentication credentials"},"status":401}"#;
+        let error = ElasticError::from_status("http://es:9200/_cat/indices", StatusCode::UNAUTHORIZED, body);
+
+        let error: FieldError<DefaultScalarValue> = error.info().into_field_error();
+        assert_eq!(error.message(), "http://es:9200/_cat/indices returned HTTP 401: security_exception: missing authentication credentials");
+        assert_eq!(error.extensions(), &graphql_value!({
+            "kind": "status",
+            "status": 401,
+            "errorType": "security_exception",
+            "reason": "missing authentication credentials",
+        }));
+    }
+}
@@ -0,0 +1,200 @@
+use crate::elastic::data::{ClusterInfo, IndexInfo, NodeOutput, Recovery, ShallowShard};
+use crate::elastic::demo::DEMO_DATA;
+use crate::elastic::error::ElasticError;
+use crate::elastic::source::{parse, ClusterSource, Resource};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// `GET /`, for version detection.
+pub const ROOT_FIXTURE: &str = "_root.json";
+/// `_cat/allocation` and `_cluster/settings`, for the disk view.
+pub const ALLOCATION_FIXTURE: &str = "_cat_allocation.json";
+pub const SETTINGS_FIXTURE: &str = "_cluster_settings.json";
+
+/// Serves the same canned response for each resource on every refresh,
+/// either the embedded demo data or files from a fixture directory.
+#[derive(Debug)]
+pub struct FixtureSource {
+    origin: String,
+    bodies: HashMap<Resource, String>,
+}
+
+impl FixtureSource {
+    pub fn embedded() -> Self {
+        FixtureSource {
+            origin: "demo".to_string(),
+            bodies: DEMO_DATA.iter().map(|(resource, body)| (*resource, body.to_string())).collect(),
+        }
+    }
+
+    /// The demo dataset, loaded from `dir` when set and the embedded
+    /// fixtures otherwise.
+    pub fn demo(dir: Option<&Path>) -> Result<Self> {
+        match dir {
+            Some(dir) => {
+                let source = Self::from_dir(dir)?;
+                log::info!("Loaded demo data from {}", dir.display());
+                Ok(source)
+            }
+            None => Ok(Self::embedded()),
+        }
+    }
+
+    /// Loads `_cluster_health.json`, `_cat_indices.json`, `_recovery.json`,
+    /// `_cat_shards.json` and `_nodes.json` from `dir`. Every file must
+    /// exist and parse; all problems are reported at once.
+    pub fn from_dir(dir: &Path) -> Result<Self> {
+        let mut bodies = HashMap::new();
+        let mut problems = Vec::new();
+
+        for resource in Resource::ALL {
+            let path = dir.join(resource.fixture_file());
+            match std::fs::read_to_string(&path) {
+                Ok(body) => {
+                    bodies.insert(resource, body);
+                }
+                Err(e) => problems.push(format!("{}: cannot read {}: {}", resource.name(), path.display(), e)),
+            }
+        }
+
+        let source = FixtureSource {
+            origin: dir.display().to_string(),
+            bodies,
+        };
+        problems.extend(source.validate());
+
+        if !problems.is_empty() {
+            bail!("Invalid fixtures in {}:\n  {}", dir.display(), problems.join("\n  "));
+        }
+
+        Ok(source)
+    }
+
+    /// Parses every loaded body, so broken fixtures fail at startup rather
+    /// than on each refresh.
+    fn validate(&self) -> Vec<String> {
+        Resource::ALL.into_iter()
+            .filter(|resource| self.bodies.contains_key(resource))
+            .filter_map(|resource| {
+                let result = match resource {
+                    Resource::Health => self.get::<ClusterInfo>(resource).map(drop),
+                    Resource::Indices => self.get::<Vec<IndexInfo>>(resource).map(drop),
+                    Resource::Recovery => self.get::<Recovery>(resource).map(drop),
+                    Resource::Shards => self.get::<Vec<ShallowShard>>(resource).map(drop),
+                    Resource::Nodes => self.get::<NodeOutput>(resource).map(drop),
+                };
+                result.err().map(|e| format!("{}: {}", resource.name(), e))
+            })
+            .collect()
+    }
+
+    pub fn body(&self, resource: Resource) -> Option<&str> {
+        self.bodies.get(&resource).map(String::as_str)
+    }
+
+    fn get<T: DeserializeOwned>(&self, resource: Resource) -> Result<T, ElasticError> {
+        let body = self.body(resource).ok_or_else(|| ElasticError::MissingFixture {
+            origin: self.origin.clone(),
+            resource: resource.name(),
+        })?;
+
+        parse(resource.fixture_file(), body)
+    }
+}
+
+#[async_trait]
+impl ClusterSource for FixtureSource {
+    async fn health(&self) -> Result<ClusterInfo, ElasticError> {
+        self.get(Resource::Health)
+    }
+
+    async fn indices(&self) -> Result<Vec<IndexInfo>, ElasticError> {
+        self.get(Resource::Indices)
+    }
+
+    async fn recovery(&self) -> Result<Recovery, ElasticError> {
+        self.get(Resource::Recovery)
+    }
+
+    async fn shards(&self) -> Result<Vec<ShallowShard>, ElasticError> {
+        self.get(Resource::Shards)
+    }
+
+    async fn nodes(&self) -> Result<NodeOutput, ElasticError> {
+        self.get(Resource::Nodes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn load(fixture: &str) -> FixtureSource {
+        FixtureSource::from_dir(&Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures").join(fixture)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn every_version_fixture_loads() {
+        for fixture in ["elasticsearch-6.8", "elasticsearch-7.17", "elasticsearch-8.13", "opensearch-1.3", "opensearch-2.11"] {
+            let source = load(fixture);
+            assert_eq!(source.shards().await.unwrap().len(), 6, "{}", fixture);
+            assert_eq!(source.nodes().await.unwrap().nodes.len(), 3, "{}", fixture);
+            assert_eq!(source.recovery().await.unwrap().indices().len(), 1, "{}", fixture);
+        }
+    }
+
+    #[tokio::test]
+    async fn closed_indices_without_health_load() {
+        let indices = serde_json::to_value(load("elasticsearch-6.8").indices().await.unwrap()).unwrap();
+        assert_eq!(indices[1]["status"], "close");
+        assert_eq!(indices[1]["health"], "");
+    }
+
+    #[tokio::test]
+    async fn cluster_manager_reads_as_master() {
+        // OpenSearch 1.x still says master, 2.x says cluster_manager
+        for fixture in ["opensearch-1.3", "opensearch-2.11"] {
+            let nodes = serde_json::to_value(load(fixture).nodes().await.unwrap()).unwrap();
+            for node in nodes["nodes"].as_array().unwrap() {
+                assert!(node["roles"].as_array().unwrap().contains(&Value::from("master")), "{}", fixture);
+            }
+        }
+    }
+
+    #[test]
+    fn a_missing_fixture_is_named() {
+        let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures").join("elasticsearch-8.13");
+        let dir = std::env::temp_dir().join(format!("parietal-fixtures-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for resource in Resource::ALL {
+            std::fs::copy(source.join(resource.fixture_file()), dir.join(resource.fixture_file())).unwrap();
+        }
+        FixtureSource::from_dir(&dir).unwrap();
+
+        std::fs::remove_file(dir.join("_cat_shards.json")).unwrap();
+        let error = FixtureSource::from_dir(&dir).unwrap_err().to_string();
+        assert!(error.contains("shards: cannot read"), "{}", error);
+        assert!(error.contains(&dir.join("_cat_shards.json").display().to_string()), "{}", error);
+        assert_eq!(error.lines().count(), 2, "only the missing file is reported: {}", error);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn extra_cat_columns_of_8x_are_ignored() {
+        let source = load("elasticsearch-8.13");
+        assert!(source.body(Resource::Shards).unwrap().contains("\"dataset\""));
+        assert!(source.body(Resource::Indices).unwrap().contains("\"dataset.size\""));
+
+        let shards = serde_json::to_value(source.shards().await.unwrap()).unwrap();
+        assert_eq!(shards[0]["store"], "48.2mb");
+        let indices = serde_json::to_value(source.indices().await.unwrap()).unwrap();
+        assert_eq!(indices[0]["store_size"], "240.3mb");
+    }
+}
@@ -0,0 +1,176 @@
+use anyhow::{bail, Result};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// The set of hosts the client talks to: the configured seeds plus any
+/// nodes discovered by sniffing. Requests go to the current host until it
+/// fails, then move on to the next one.
+#[derive(Debug)]
+pub struct HostPool {
+    seeds: Vec<String>,
+    hosts: RwLock<Vec<String>>,
+    current: AtomicUsize,
+    sniff_interval: Option<Duration>,
+    last_sniff: Mutex<Option<Instant>>,
+}
+
+impl HostPool {
+    pub fn new(seeds: Vec<String>, sniff_interval: Option<Duration>) -> Result<Self> {
+        let seeds: Vec<String> = seeds.iter()
+            .map(|seed| seed.trim_end_matches('/').to_string())
+            .filter(|seed| !seed.is_empty())
+            .collect();
+
+        if seeds.is_empty() {
+            bail!("elastic: no url or urls configured");
+        }
+
+        Ok(HostPool {
+            hosts: RwLock::new(seeds.clone()),
+            seeds,
+            current: AtomicUsize::new(0),
+            sniff_interval,
+            last_sniff: Mutex::new(None),
+        })
+    }
+
+    pub fn current(&self) -> String {
+        let hosts = self.hosts.read().unwrap();
+        hosts[self.current.load(Ordering::Relaxed) % hosts.len()].clone()
+    }
+
+    /// Moves on to the next host, unless another request already did.
+    pub fn fail_over(&self, failed: &str) {
+        let hosts = self.hosts.read().unwrap();
+        let current = self.current.load(Ordering::Relaxed);
+
+        if hosts[current % hosts.len()] == failed {
+            let next = (current + 1) % hosts.len();
+            log::warn!("Host {} failed, failing over to {}", failed, hosts[next]);
+            self.current.store(next, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether a sniff is due, in which case it counts as done from now on
+    /// whether it succeeds or not.
+    pub fn start_sniff(&self) -> bool {
+        let Some(interval) = self.sniff_interval else {
+            return false;
+        };
+
+        let mut last_sniff = self.last_sniff.lock().unwrap();
+        let due = last_sniff.map(|at| at.elapsed() >= interval).unwrap_or(true);
+        if due {
+            *last_sniff = Some(Instant::now());
+        }
+        due
+    }
+
+    /// Replaces the host list with sniffed `publish_address`es. The seeds are
+    /// kept at the end as a fallback.
+    pub fn update(&self, publish_addresses: Vec<String>) {
+        let scheme = self.seeds[0].split_once("://").map(|(scheme, _)| scheme).unwrap_or("http");
+        let mut hosts: Vec<String> = publish_addresses.iter()
+            .map(|address| {
+                // Addresses look like `hostname/10.0.0.1:9200` when a hostname is known
+                let address = address.rsplit('/').next().unwrap_or(address);
+                format!("{}://{}", scheme, address)
+            })
+            .collect();
+        hosts.sort();
+
+        for seed in &self.seeds {
+            if !hosts.contains(seed) {
+                hosts.push(seed.clone());
+            }
+        }
+
+        let current = self.current();
+        let mut pool = self.hosts.write().unwrap();
+        if *pool != hosts {
+            log::info!("Sniffed hosts: {:?}", hosts);
+            let index = hosts.iter().position(|h| *h == current).unwrap_or(0);
+            *pool = hosts;
+            self.current.store(index, Ordering::Relaxed);
+        }
+    }
+
+    pub fn host_count(&self) -> usize {
+        self.hosts.read().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(seeds: &[&str]) -> HostPool {
+        HostPool::new(seeds.iter().map(|seed| seed.to_string()).collect(), Some(Duration::from_secs(60))).unwrap()
+    }
+
+    #[test]
+    fn rejects_an_empty_pool() {
+        assert!(HostPool::new(Vec::new(), None).is_err());
+        assert!(HostPool::new(vec![String::new(), "/".to_string()], None).is_err());
+    }
+
+    #[test]
+    fn fails_over_round_robin() {
+        let hosts = pool(&["http://a:9200/", "http://b:9200", "http://c:9200"]);
+        assert_eq!(hosts.current(), "http://a:9200");
+
+        hosts.fail_over("http://a:9200");
+        assert_eq!(hosts.current(), "http://b:9200");
+        // A second request that saw the same host fail doesn't skip a host
+        hosts.fail_over("http://a:9200");
+        assert_eq!(hosts.current(), "http://b:9200");
+
+        hosts.fail_over("http://b:9200");
+        hosts.fail_over("http://c:9200");
+        assert_eq!(hosts.current(), "http://a:9200");
+    }
+
+    #[test]
+    fn normalises_sniffed_addresses() {
+        let hosts = pool(&["https://seed:9200"]);
+
+        hosts.update(vec![
+            "es-2.internal/10.0.0.2:9200".to_string(),
+            "10.0.0.1:9200".to_string(),
+            "[::1]:9200".to_string(),
+        ]);
+
+        assert_eq!(*hosts.hosts.read().unwrap(), [
+            "https://10.0.0.1:9200",
+            "https://10.0.0.2:9200",
+            "https://[::1]:9200",
+            // Seeds stay as a fallback
+            "https://seed:9200",
+        ]);
+        assert_eq!(hosts.host_count(), 4);
+    }
+
+    #[test]
+    fn keeps_the_current_host_across_updates() {
+        let hosts = pool(&["http://10.0.0.2:9200"]);
+
+        hosts.update(vec!["10.0.0.1:9200".to_string(), "10.0.0.2:9200".to_string()]);
+        assert_eq!(hosts.current(), "http://10.0.0.2:9200");
+
+        // Gone from the cluster, but still a seed
+        hosts.update(vec!["10.0.0.3:9200".to_string()]);
+        assert_eq!(hosts.current(), "http://10.0.0.2:9200");
+        assert_eq!(hosts.host_count(), 2);
+    }
+
+    #[test]
+    fn counts_failed_sniffs_until_the_next_interval() {
+        let hosts = pool(&["http://seed:9200"]);
+        assert!(hosts.start_sniff());
+        // Nothing was updated, the sniff failed
+        assert!(!hosts.start_sniff());
+
+        assert!(!HostPool::new(vec!["http://seed:9200".to_string()], None).unwrap().start_sniff());
+    }
+}
@@ -0,0 +1,506 @@
+use crate::config::{MockConfig, MockSource};
+use crate::elastic::auth::Credentials;
+use crate::elastic::fixture::{FixtureSource, ALLOCATION_FIXTURE, SETTINGS_FIXTURE};
+use crate::elastic::simulator::SimulatorSource;
+use crate::elastic::source::{ClusterSource, ExplainRequest, Resource};
+use crate::elastic::version::Distribution;
+
+use anyhow::{Context, Result};
+use once_cell::sync::OnceCell;
+use rand::Rng;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use warp::http::{Method, Response, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::Filter;
+
+/// Files served from `fixture_dir` besides the resources, when present.
+const DISK_FIXTURES: &[&str] = &[ALLOCATION_FIXTURE, SETTINGS_FIXTURE];
+
+#[derive(Debug)]
+enum MockData {
+    /// Resource fixtures, and the disk fixtures by file name
+    Fixtures(FixtureSource, HashMap<&'static str, String>),
+    Simulator(Box<SimulatorSource>),
+}
+
+impl MockData {
+    fn body(&self, resource: Resource) -> Option<String> {
+        match self {
+            MockData::Fixtures(source, _) => source.body(resource).map(str::to_string),
+            MockData::Simulator(source) => Some(source.body(resource)),
+        }
+    }
+
+    /// Fixtures don't know about allocation, so no copy can be explained.
+    fn explain(&self, request: &ExplainRequest) -> Option<String> {
+        match self {
+            MockData::Fixtures(..) => None,
+            MockData::Simulator(source) => source.explain_body(request),
+        }
+    }
+
+    /// Body of `_cat/allocation` or `_cluster/settings`, by fixture file name.
+    fn disk(&self, file: &'static str) -> Option<String> {
+        match self {
+            MockData::Fixtures(_, disk) => disk.get(file).cloned(),
+            MockData::Simulator(source) => Some(match file {
+                ALLOCATION_FIXTURE => source.allocation_body(),
+                _ => source.settings_body(),
+            }),
+        }
+    }
+}
+
+/// A small HTTP server answering the Elasticsearch endpoints the client
+/// uses, with optional auth, latency and injected failures.
+#[derive(Debug)]
+pub struct MockServer {
+    config: MockConfig,
+    data: MockData,
+    credentials: Option<Credentials>,
+    /// Where the server ended up listening, as `port` may be 0.
+    addr: OnceCell<SocketAddr>,
+    requests: AtomicUsize,
+}
+
+impl MockServer {
+    pub fn new(config: &MockConfig) -> Result<Self> {
+        let data = match (config.source, &config.fixture_dir) {
+            (MockSource::Fixtures, Some(dir)) => {
+                let source = FixtureSource::from_dir(dir)?;
+                let mut disk = derived_disk_fixtures(&source);
+                disk.extend(DISK_FIXTURES.iter()
+                    .filter_map(|file| Some((*file, std::fs::read_to_string(dir.join(file)).ok()?))));
+                MockData::Fixtures(source, disk)
+            }
+            (MockSource::Fixtures, None) => {
+                let source = FixtureSource::embedded();
+                let disk = derived_disk_fixtures(&source);
+                MockData::Fixtures(source, disk)
+            }
+            (MockSource::Simulator, _) => MockData::Simulator(Box::new(SimulatorSource::new(&config.simulator)?)),
+        };
+
+        Ok(MockServer {
+            config: config.clone(),
+            data,
+            credentials: Credentials::from_config(&config.auth).context("mock.auth")?,
+            addr: OnceCell::new(),
+            requests: AtomicUsize::new(0),
+        })
+    }
+
+    /// Binds the port and serves in the background. Returns once the
+    /// server accepts connections, so clients can connect right away.
+    pub fn spawn(self) -> Result<SocketAddr> {
+        Arc::new(self).serve()
+    }
+
+    fn serve(self: Arc<Self>) -> Result<SocketAddr> {
+        let server = self;
+
+        let filter = {
+            let server = server.clone();
+            warp::method()
+                .and(warp::path::full())
+                .and(warp::header::optional::<String>("authorization"))
+                .and(warp::body::bytes())
+                .then(move |method: Method, path: warp::path::FullPath, authorization: Option<String>, body: Bytes| {
+                    let server = server.clone();
+                    async move { server.respond(&method, path.as_str(), authorization.as_deref(), &body).await }
+                })
+        };
+
+        let bind = SocketAddr::new(server.config.bind_address, server.config.port);
+        let (addr, serve) = warp::serve(filter)
+            .try_bind_ephemeral(bind)
+            // warp's error already includes its source chain
+            .map_err(|e| anyhow::anyhow!("mock: failed to bind {}: {}", bind, e))?;
+        // Before serving, so sniffing never sees the configured port 0
+        let _ = server.addr.set(addr);
+        tokio::spawn(serve);
+
+        if let MockData::Simulator(_) = server.data {
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(Duration::from_secs(server.config.tick_secs.max(1))).await;
+                    if let MockData::Simulator(source) = &server.data {
+                        let _ = source.prepare().await;
+                    }
+                }
+            });
+        }
+
+        log::info!("Mock Elasticsearch listening on {}", addr);
+        Ok(addr)
+    }
+
+    async fn respond(&self, method: &Method, path: &str, authorization: Option<&str>, body: &[u8]) -> Response<String> {
+        if self.config.latency_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(self.config.latency_ms)).await;
+        }
+
+        let (status, body) = self.route(method, path, authorization, body);
+        let request = self.requests.fetch_add(1, Ordering::Relaxed) + 1;
+        log::debug!("Mock #{}: {} {} -> {}", request, method, path, status.as_u16());
+
+        let mut response = Response::builder()
+            .status(status)
+            .header("content-type", "application/json");
+        if status == StatusCode::UNAUTHORIZED {
+            response = response.header("www-authenticate", "Basic realm=\"security\" charset=\"UTF-8\"");
+        }
+
+        response.body(body).unwrap()
+    }
+
+    fn route(&self, method: &Method, path: &str, authorization: Option<&str>, body: &[u8]) -> (StatusCode, String) {
+        if let Some(credentials) = &self.credentials {
+            if authorization != Some(credentials.header().as_str()) {
+                let reason = match authorization {
+                    Some(_) => format!("unable to authenticate user for REST request [{}]", path),
+                    None => format!("missing authentication credentials for REST request [{}]", path),
+                };
+                return error(StatusCode::UNAUTHORIZED, "security_exception", &reason);
+            }
+        }
+
+        if rand::thread_rng().gen_bool(self.config.error_rate.clamp(0.0, 1.0)) {
+            let status = StatusCode::from_u16(self.config.error_status).unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+            return error(status, "mock_injected_exception", "failure injected by the mock server");
+        }
+
+        if path.trim_start_matches('/') == "_cluster/allocation/explain" {
+            return match *method {
+                Method::GET | Method::POST => self.explain(body),
+                _ => method_not_allowed(path, method, "GET,POST"),
+            };
+        }
+
+        if method != Method::GET {
+            return method_not_allowed(path, method, "GET");
+        }
+
+        let resource = match path.trim_start_matches('/') {
+            "" => return (StatusCode::OK, self.root().to_string()),
+            "_nodes/http" => return (StatusCode::OK, self.nodes_http().to_string()),
+            "_cat/allocation" => return self.disk(ALLOCATION_FIXTURE),
+            "_cluster/settings" => return self.disk(SETTINGS_FIXTURE),
+            "_cluster/health" => Resource::Health,
+            "_cat/indices" => Resource::Indices,
+            "_cat/shards" => Resource::Shards,
+            "_recovery" => Resource::Recovery,
+            "_nodes" => Resource::Nodes,
+            path if path.starts_with("_nodes/stats") => Resource::Nodes,
+            _ => {
+                // Elasticsearch answers unknown paths with a plain string error
+                let message = format!("no handler found for uri [{}] and method [{}]", path, method);
+                return (StatusCode::BAD_REQUEST, json!({ "error": message, "status": 400 }).to_string());
+            }
+        };
+
+        // Bodies are served as-is, so broken fixtures reach the client too
+        match self.data.body(resource) {
+            Some(body) => (StatusCode::OK, body),
+            None => error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "mock_fixture_exception",
+                &format!("no {} fixture loaded", resource.name()),
+            ),
+        }
+    }
+
+    /// Answers like Elasticsearch: the body names the copy to explain, and
+    /// copies that can't be found are a bad request.
+    fn explain(&self, body: &[u8]) -> (StatusCode, String) {
+        let request: ExplainRequest = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(e) => return error(StatusCode::BAD_REQUEST, "x_content_parse_exception", &e.to_string()),
+        };
+
+        match self.data.explain(&request) {
+            Some(body) => (StatusCode::OK, body),
+            None => error(
+                StatusCode::BAD_REQUEST,
+                "illegal_argument_exception",
+                &format!("unable to find shard [{}][{}] to explain", request.index, request.shard),
+            ),
+        }
+    }
+
+    /// Like an Elasticsearch without the API when there's no fixture, which
+    /// the client takes as the disk view being unavailable.
+    fn disk(&self, file: &'static str) -> (StatusCode, String) {
+        match self.data.disk(file) {
+            Some(body) => (StatusCode::OK, body),
+            None => error(
+                StatusCode::NOT_FOUND,
+                "mock_fixture_exception",
+                &format!("no {} fixture loaded", file),
+            ),
+        }
+    }
+
+    fn root(&self) -> Value {
+        let mut version = json!({
+            "number": self.config.version,
+            "build_flavor": "default",
+        });
+        if self.config.distribution == Distribution::OpenSearch {
+            version["distribution"] = json!("opensearch");
+        }
+
+        json!({
+            "name": "cortex-mock",
+            "cluster_name": "cortex-mock",
+            "version": version,
+            "tagline": "You Know, for Search",
+        })
+    }
+
+    /// Advertises the mock itself as the only HTTP node, so sniffing keeps
+    /// talking to it.
+    fn nodes_http(&self) -> Value {
+        let mut addr = self.addr.get().copied()
+            .unwrap_or_else(|| SocketAddr::new(self.config.bind_address, self.config.port));
+        // Listening everywhere, loopback is one of them
+        if addr.ip().is_unspecified() {
+            addr.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+
+        json!({
+            "nodes": {
+                "cortex-mock": {
+                    "http": { "publish_address": addr.to_string() },
+                },
+            },
+        })
+    }
+}
+
+/// Disk fixtures for fixture directories without them: `_cat/allocation`
+/// rows built from the disk figures of the nodes and the shards on them,
+/// and settings with nothing set, so the default watermarks apply.
+fn derived_disk_fixtures(source: &FixtureSource) -> HashMap<&'static str, String> {
+    let mut disk = HashMap::from([(SETTINGS_FIXTURE, json!({ "persistent": {}, "transient": {}, "defaults": {} }).to_string())]);
+    let parse = |resource| source.body(resource).and_then(|body| serde_json::from_str::<Value>(body).ok());
+    let (Some(nodes), Some(shards)) = (parse(Resource::Nodes), parse(Resource::Shards)) else {
+        return disk;
+    };
+
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    let mut unassigned = 0;
+    for shard in shards.as_array().into_iter().flatten() {
+        match shard["node"].as_str() {
+            // A relocating shard counts on its source, `source -> ip id target`
+            Some(node) => *counts.entry(node.split(" -> ").next().unwrap_or(node)).or_default() += 1,
+            None => unassigned += 1,
+        }
+    }
+
+    let mut rows: Vec<_> = nodes["nodes"].as_object().into_iter().flatten()
+        .map(|(_, node)| {
+            let name = node["name"].as_str().unwrap_or_default();
+            let total = node["fs"]["total"]["total_in_bytes"].as_u64().unwrap_or_default();
+            let avail = node["fs"]["total"]["available_in_bytes"].as_u64().unwrap_or_default();
+            json!({
+                // Shards only name their node, so nodes sharing a name get them all on the first
+                "shards": counts.remove(name).unwrap_or_default().to_string(),
+                "disk.used": (total - avail.min(total)).to_string(),
+                "disk.avail": avail.to_string(),
+                "disk.total": total.to_string(),
+                "host": node["host"],
+                "ip": node["ip"],
+                "node": name,
+            })
+        })
+        .collect();
+    if unassigned > 0 {
+        rows.push(json!({ "shards": unassigned.to_string(), "node": "UNASSIGNED" }));
+    }
+
+    disk.insert(ALLOCATION_FIXTURE, Value::Array(rows).to_string());
+    disk
+}
+
+fn method_not_allowed(path: &str, method: &Method, allowed: &str) -> (StatusCode, String) {
+    let message = format!("Incorrect HTTP method for uri [{}] and method [{}], allowed: [{}]", path, method, allowed);
+    (StatusCode::METHOD_NOT_ALLOWED, json!({ "error": message, "status": 405 }).to_string())
+}
+
+fn error(status: StatusCode, error_type: &str, reason: &str) -> (StatusCode, String) {
+    let cause = json!({ "type": error_type, "reason": reason });
+    let body = json!({
+        "error": {
+            "root_cause": [cause],
+            "type": error_type,
+            "reason": reason,
+        },
+        "status": status.as_u16(),
+    });
+
+    (status, body.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AuthConfig;
+    use crate::elastic::breaker::BreakerState;
+    use crate::elastic::client::ElasticsearchClient;
+    use crate::elastic::error::ElasticError;
+
+    fn start(config: MockConfig) -> (Arc<MockServer>, String) {
+        let server = Arc::new(MockServer::new(&MockConfig { port: 0, ..config }).unwrap());
+        let addr = server.clone().serve().unwrap();
+        (server, format!("http://{}", addr))
+    }
+
+    /// A client for `url`, retrying quickly. `overrides` replaces top-level
+    /// keys of the elastic config.
+    fn client(url: &str, overrides: Value) -> ElasticsearchClient {
+        let mut config = json!({
+            "url": url,
+            "retry": { "max_retries": 2, "initial_backoff_ms": 1, "max_backoff_ms": 1 },
+        });
+        for (key, value) in overrides.as_object().unwrap() {
+            config[key] = value.clone();
+        }
+        ElasticsearchClient::new(&serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    fn requests(server: &MockServer) -> usize {
+        server.requests.load(Ordering::Relaxed)
+    }
+
+    fn basic_auth(username: &str, password: &str) -> AuthConfig {
+        AuthConfig { username: Some(username.to_string()), password: Some(password.to_string()), ..AuthConfig::default() }
+    }
+
+    #[tokio::test]
+    async fn serves_every_resource_to_the_client() {
+        let (server, url) = start(MockConfig { auth: basic_auth("elastic", "changeme"), ..MockConfig::default() });
+        let client = client(&url, json!({ "auth": { "username": "elastic", "password": "changeme" } }));
+
+        client.prepare().await.unwrap();
+        assert_eq!(client.version().unwrap().number, "8.14.3");
+
+        client.health().await.unwrap();
+        assert!(!client.indices().await.unwrap().is_empty());
+        client.recovery().await.unwrap();
+        assert!(!client.shards().await.unwrap().is_empty());
+        assert!(!client.nodes().await.unwrap().nodes.is_empty());
+        assert_eq!(client.breaker_status().state, BreakerState::Closed);
+        // `GET /` plus one request per resource, nothing retried
+        assert_eq!(requests(&server), 6);
+    }
+
+    #[tokio::test]
+    async fn derives_the_disk_fixtures_from_the_nodes() {
+        let (server, url) = start(MockConfig::default());
+        let client = client(&url, json!({}));
+
+        let disk = client.disk_allocation().await.unwrap().unwrap();
+        let nodes = client.nodes().await.unwrap().nodes;
+        let rows: Vec<_> = disk.nodes.iter().filter(|row| row.node != "UNASSIGNED").collect();
+        assert_eq!(rows.len(), nodes.len());
+        let mut expected: Vec<_> = nodes.iter()
+            .map(|node| (node.name().to_string(), node.disk_space().map(|(total, available)| (total.to_string(), available.to_string()))))
+            .collect();
+        let mut derived: Vec<_> = rows.iter()
+            .map(|row| (row.node.clone(), row.disk_total.clone().zip(row.disk_avail.clone())))
+            .collect();
+        expected.sort();
+        derived.sort();
+        assert_eq!(derived, expected);
+
+        let shards = client.shards().await.unwrap();
+        let listed: usize = disk.nodes.iter().map(|row| row.shards.as_deref().unwrap().parse::<usize>().unwrap()).sum();
+        assert_eq!(listed, shards.len());
+        assert_eq!(disk.watermarks.unwrap().high, "90%");
+        assert_eq!(client.breaker_status().state, BreakerState::Closed);
+        assert_eq!(requests(&server), 4, "allocation, settings, nodes and shards, nothing retried");
+    }
+
+    #[tokio::test]
+    async fn disk_allocation_is_optional() {
+        // A cluster without the endpoints, or a proxy that doesn't pass them on
+        let missing = warp::any().map(|| warp::reply::with_status("", StatusCode::NOT_FOUND));
+        let (addr, serving) = warp::serve(missing).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(serving);
+        let unsupported = client(&format!("http://{}", addr), json!({}));
+        assert!(unsupported.disk_allocation().await.unwrap().is_none());
+
+        let (server, url) = start(MockConfig { error_rate: 1.0, ..MockConfig::default() });
+        let client = client(&url, json!({ "circuit_breaker": { "failure_threshold": 1, "open_secs": 60 } }));
+        assert!(matches!(client.disk_allocation().await, Err(ElasticError::Status { status: 503, .. })));
+        assert_eq!(requests(&server), 2, "allocation and settings, neither retried");
+        assert_eq!(client.breaker_status().consecutive_failures, 0);
+        assert_eq!(client.breaker_status().state, BreakerState::Closed);
+    }
+
+    #[tokio::test]
+    async fn wrong_credentials_are_a_401() {
+        let (server, url) = start(MockConfig { auth: basic_auth("elastic", "changeme"), ..MockConfig::default() });
+        let client = client(&url, json!({ "auth": { "username": "elastic", "password": "wrong" } }));
+
+        match client.health().await {
+            Err(ElasticError::Status { status: 401, error_type, .. }) => assert_eq!(error_type.as_deref(), Some("security_exception")),
+            other => panic!("expected a 401, got {:?}", other),
+        }
+        // Not retried, and the cluster answered, so the breaker stays closed
+        assert_eq!(requests(&server), 1);
+        assert_eq!(client.breaker_status().state, BreakerState::Closed);
+    }
+
+    #[tokio::test]
+    async fn injected_503s_are_retried_and_trip_the_breaker() {
+        let (server, url) = start(MockConfig { error_rate: 1.0, ..MockConfig::default() });
+        let client = client(&url, json!({ "circuit_breaker": { "failure_threshold": 2, "open_secs": 60 } }));
+
+        // Failing to detect the version isn't fatal
+        client.prepare().await.unwrap();
+        for _ in 0..2 {
+            match client.health().await {
+                Err(ElasticError::Status { status: 503, .. }) => {}
+                other => panic!("expected a 503, got {:?}", other),
+            }
+        }
+        assert_eq!(requests(&server), 9, "`GET /` and two health requests, each retried twice");
+        assert_eq!(client.breaker_status().consecutive_failures, 1, "failures of one refresh count once");
+
+        client.prepare().await.unwrap();
+        assert!(matches!(client.health().await, Err(ElasticError::Status { status: 503, .. })));
+        assert_eq!(client.breaker_status().state, BreakerState::Open);
+
+        assert!(matches!(client.health().await, Err(ElasticError::CircuitOpen { .. })));
+        assert!(matches!(client.prepare().await, Err(ElasticError::CircuitOpen { .. })));
+        assert_eq!(requests(&server), 12, "nothing is sent while the breaker is open");
+    }
+
+    #[tokio::test]
+    async fn slow_responses_time_out() {
+        let (_server, url) = start(MockConfig { latency_ms: 500, ..MockConfig::default() });
+        let client = client(&url, json!({
+            "timeouts": { "connect_ms": 1_000, "request_ms": 50, "endpoints": {} },
+            "retry": { "max_retries": 0, "initial_backoff_ms": 1, "max_backoff_ms": 1 },
+        }));
+
+        assert!(matches!(client.health().await, Err(ElasticError::Timeout { .. })));
+    }
+
+    #[tokio::test]
+    async fn sniffing_finds_the_bound_port() {
+        let (server, url) = start(MockConfig::default());
+        assert_eq!(server.nodes_http()["nodes"]["cortex-mock"]["http"]["publish_address"], url.trim_start_matches("http://"));
+
+        let client = client(&url, json!({ "sniff": true }));
+        client.prepare().await.unwrap();
+        client.health().await.unwrap();
+    }
+}
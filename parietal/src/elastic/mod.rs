@@ -1,3 +1,15 @@
+pub mod auth;
+pub mod breaker;
 pub mod client;
 pub mod data;
 mod demo;
+pub mod error;
+pub mod fixture;
+mod hosts;
+pub mod mock;
+mod recorder;
+pub mod replay;
+pub mod simulator;
+pub mod source;
+mod tls;
+pub mod version;
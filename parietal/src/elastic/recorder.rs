@@ -0,0 +1,382 @@
+use crate::config::RecordConfig;
+use crate::elastic::fixture::{ALLOCATION_FIXTURE, ROOT_FIXTURE, SETTINGS_FIXTURE};
+use crate::elastic::source::Resource;
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::Ipv6Addr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Name of the per-snapshot manifest, one JSON line per response.
+pub const MANIFEST_FILE: &str = "responses.jsonl";
+
+static IPV4: Lazy<Regex> = Lazy::new(|| Regex::new(r"\b(?:\d{1,3}\.){3}\d{1,3}\b").unwrap());
+
+/// Runs of characters an IPv6 address is made of. Only runs that parse as
+/// one are replaced, which leaves e.g. times of day alone.
+static IPV6_CANDIDATE: Lazy<Regex> = Lazy::new(|| Regex::new(r"[0-9A-Fa-f:.]*:[0-9A-Fa-f:.]*").unwrap());
+
+/// Fields holding hostnames or addresses, scrubbed as a whole.
+const HOST_FIELDS: &[&str] = &[
+    "host", "hostname", "ip", "transport_address", "publish_address", "bound_address",
+    // Flat `_cluster/settings`
+    "network.host", "network.bind_host", "network.publish_host", "discovery.seed_hosts",
+];
+
+#[derive(Serialize)]
+struct ManifestEntry<'a> {
+    timestamp: String,
+    endpoint: &'a str,
+    status: u16,
+    file: &'a str,
+}
+
+/// Writes fetched responses to disk so they can be replayed later.
+#[derive(Debug)]
+pub struct Recorder {
+    dir: PathBuf,
+    scrubber: Option<Mutex<Scrubber>>,
+    snapshot: Mutex<Option<PathBuf>>,
+}
+
+impl Recorder {
+    pub fn new(config: &RecordConfig) -> Result<Option<Self>> {
+        let Some(dir) = &config.dir else {
+            return Ok(None);
+        };
+
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("elastic.record: failed to create {}", dir.display()))?;
+        log::info!("Recording responses to {}", dir.display());
+
+        Ok(Some(Recorder {
+            dir: dir.clone(),
+            scrubber: config.scrub.then(|| Mutex::new(Scrubber::default())),
+            snapshot: Mutex::new(None),
+        }))
+    }
+
+    /// Starts a new snapshot directory; called at the start of each refresh.
+    pub fn start_snapshot(&self) {
+        let path = self.dir.join(Utc::now().format("%Y%m%dT%H%M%S%.3fZ").to_string());
+
+        match std::fs::create_dir_all(&path) {
+            Ok(()) => *self.snapshot.lock().unwrap() = Some(path),
+            Err(e) => log::warn!("Failed to create snapshot directory {}: {}", path.display(), e),
+        }
+    }
+
+    /// Records a response. Failures are only logged, recording must never
+    /// break fetching.
+    pub fn record(&self, resource: &str, endpoint: &str, status: u16, body: &str) {
+        if let Err(e) = self.write(resource, endpoint, status, body) {
+            log::warn!("Failed to record response for {}: {:#}", endpoint, e);
+        }
+    }
+
+    fn write(&self, resource: &str, endpoint: &str, status: u16, body: &str) -> Result<()> {
+        let Some(snapshot) = self.snapshot.lock().unwrap().clone() else {
+            return Ok(());
+        };
+
+        // Responses use the fixture file names, so a snapshot can be loaded
+        // as a fixture directory
+        let fixture = match (resource, Resource::from_name(resource)) {
+            (_, Some(resource)) => resource.fixture_file().to_string(),
+            ("root", None) => ROOT_FIXTURE.to_string(),
+            ("allocation", None) => ALLOCATION_FIXTURE.to_string(),
+            ("settings", None) => SETTINGS_FIXTURE.to_string(),
+            (_, None) => format!("_{}.json", resource),
+        };
+        let file = match status {
+            200..=299 => fixture,
+            _ => format!("{}.{}", fixture, status),
+        };
+
+        let body = match &self.scrubber {
+            Some(scrubber) => scrubber.lock().unwrap().scrub(body),
+            None => body.to_string(),
+        };
+        std::fs::write(snapshot.join(&file), body)?;
+
+        let entry = ManifestEntry {
+            timestamp: Utc::now().to_rfc3339(),
+            endpoint,
+            status,
+            file: &file,
+        };
+        let mut manifest = OpenOptions::new().create(true).append(true).open(snapshot.join(MANIFEST_FILE))?;
+        writeln!(manifest, "{}", serde_json::to_string(&entry)?)?;
+
+        Ok(())
+    }
+}
+
+/// Replaces hostnames, IPs, node names and the cluster name with
+/// placeholders. The same value always maps to the same placeholder, so
+/// references between responses (e.g. a shard's node) still line up.
+#[derive(Debug, Default)]
+struct Scrubber {
+    hosts: HashMap<String, String>,
+    ips: HashMap<String, String>,
+    nodes: HashMap<String, String>,
+    clusters: HashMap<String, String>,
+}
+
+impl Scrubber {
+    fn scrub(&mut self, body: &str) -> String {
+        match serde_json::from_str::<Value>(body) {
+            Ok(mut value) => {
+                self.scrub_value(&mut value, &mut Vec::new());
+                value.to_string()
+            }
+            Err(_) => self.scrub_ips(body),
+        }
+    }
+
+    /// `path` holds the keys leading to `value`, array indices left out.
+    fn scrub_value(&mut self, value: &mut Value, path: &mut Vec<String>) {
+        match value {
+            Value::String(s) => {
+                let key = path.last().map(String::as_str);
+                *s = match key {
+                    Some(key) if HOST_FIELDS.contains(&key) => self.scrub_host(s),
+                    Some("cluster_name" | "cluster.name") => placeholder(&mut self.clusters, s, "cluster"),
+                    Some("node") => self.scrub_node_column(s),
+                    _ if is_node_name(path) => placeholder(&mut self.nodes, s, "node"),
+                    _ => self.scrub_ips(s),
+                };
+            }
+            Value::Array(values) => {
+                for value in values {
+                    self.scrub_value(value, path);
+                }
+            }
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    path.push(key.clone());
+                    self.scrub_value(value, path);
+                    path.pop();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The `node` column of the cat APIs: a node name, `UNASSIGNED`, or
+    /// `source -> ip id target` for a relocating shard.
+    fn scrub_node_column(&mut self, value: &str) -> String {
+        if value == "UNASSIGNED" {
+            return value.to_string();
+        }
+
+        let Some((source, target)) = value.split_once(" -> ") else {
+            return placeholder(&mut self.nodes, value, "node");
+        };
+        let source = placeholder(&mut self.nodes, source, "node");
+        match target.splitn(3, ' ').collect::<Vec<_>>()[..] {
+            [ip, id, name] => format!("{} -> {} {} {}", source, self.scrub_host(ip), id, placeholder(&mut self.nodes, name, "node")),
+            _ => format!("{} -> {}", source, self.scrub_ips(target)),
+        }
+    }
+
+    /// Scrubs `hostname`, `hostname:port`, `ip:port`, `[ipv6]:port` and
+    /// `hostname/ip:port`.
+    fn scrub_host(&mut self, value: &str) -> String {
+        value.split('/')
+            .map(|part| {
+                if is_ip(part) {
+                    return self.scrub_ips(part);
+                }
+
+                let (host, port) = match part.rsplit_once(':') {
+                    Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => (host, Some(port)),
+                    _ => (part, None),
+                };
+
+                let host = match is_ip(host) {
+                    true => self.scrub_ips(host),
+                    false => placeholder(&mut self.hosts, host, "host"),
+                };
+
+                match port {
+                    Some(port) => format!("{}:{}", host, port),
+                    None => host,
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    fn scrub_ips(&mut self, value: &str) -> String {
+        let ips = &mut self.ips;
+
+        // IPv6 first, the IPv4 pattern would match the tail of a mapped address
+        let value = IPV6_CANDIDATE.replace_all(value, |caps: &regex::Captures| {
+            match caps[0].parse::<Ipv6Addr>() {
+                Ok(_) => {
+                    let n = ips.len() + 1;
+                    ips.entry(caps[0].to_string()).or_insert_with(|| format!("fd00::{:x}", n)).clone()
+                }
+                Err(_) => caps[0].to_string(),
+            }
+        });
+
+        IPV4.replace_all(&value, |caps: &regex::Captures| {
+            let n = ips.len() + 1;
+            let next = format!("10.{}.{}.{}", (n >> 16) & 0xff, (n >> 8) & 0xff, n & 0xff);
+            ips.entry(caps[0].to_string()).or_insert(next).clone()
+        }).into_owned()
+    }
+}
+
+/// Whether the string at `path` is a node name: the name of a `_nodes`
+/// entry, of a recovery's source or target, or of the node answering
+/// `GET /`, and the node names of allocation explain and the settings.
+fn is_node_name(path: &[String]) -> bool {
+    let path: Vec<&str> = path.iter().map(String::as_str).collect();
+    matches!(
+        path[..],
+        ["name"]
+            | [.., "nodes", _, "name"]
+            | [.., "source" | "target" | "current_node", "name"]
+            | [.., "node_name" | "node.name" | "cluster.initial_master_nodes"]
+    )
+}
+
+fn is_ip(value: &str) -> bool {
+    let value = value.trim_start_matches('[').trim_end_matches(']');
+    IPV4.is_match(value) || value.parse::<Ipv6Addr>().is_ok()
+}
+
+fn placeholder(known: &mut HashMap<String, String>, value: &str, prefix: &str) -> String {
+    let next = format!("{}-{}", prefix, known.len() + 1);
+    known.entry(value.to_string()).or_insert(next).clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SimulatorConfig;
+    use crate::elastic::data::{ClusterInfo, IndexInfo, NodeOutput, Recovery, ShallowShard};
+    use crate::elastic::demo::DEMO_DATA;
+    use crate::elastic::fixture::FixtureSource;
+    use crate::elastic::simulator::SimulatorSource;
+    use crate::elastic::source::{parse, ClusterSource};
+
+    fn parses(resource: Resource, body: &str) -> bool {
+        let file = resource.fixture_file();
+        match resource {
+            Resource::Health => parse::<ClusterInfo>(file, body).is_ok(),
+            Resource::Indices => parse::<Vec<IndexInfo>>(file, body).is_ok(),
+            Resource::Recovery => parse::<Recovery>(file, body).is_ok(),
+            Resource::Shards => parse::<Vec<ShallowShard>>(file, body).is_ok(),
+            Resource::Nodes => parse::<NodeOutput>(file, body).is_ok(),
+        }
+    }
+
+    #[tokio::test]
+    async fn scrubbed_fixtures_keep_no_names_or_addresses() {
+        let config = SimulatorConfig { cluster_name: "secret-cluster".to_string(), nodes: 6, indices: 10, seed: Some(3), ..SimulatorConfig::default() };
+        let simulator = SimulatorSource::new(&config).unwrap();
+        for _ in 0..3 {
+            simulator.prepare().await.unwrap();
+        }
+
+        let mut scrubber = Scrubber::default();
+        let node_names = Regex::new(r#""node\d+"|sim-node-"#).unwrap();
+        for (resource, demo) in DEMO_DATA {
+            let simulated = simulator.body(*resource);
+            if *resource == Resource::Shards {
+                assert!(simulated.contains(" -> "), "no relocating shard to scrub");
+            }
+
+            for body in [demo.to_string(), simulated] {
+                let scrubbed = scrubber.scrub(&body);
+                assert!(parses(*resource, &scrubbed), "{} no longer parses", resource.name());
+                assert!(!node_names.is_match(&scrubbed), "{} keeps a node name", resource.name());
+                assert!(!scrubbed.contains("127.0.0.1"), "{} keeps an IP", resource.name());
+                assert!(!scrubbed.contains("Demo Cluster"), "{} keeps the cluster name", resource.name());
+                assert!(!scrubbed.contains("secret-cluster"), "{} keeps the cluster name", resource.name());
+            }
+        }
+
+        // A shard on a node and the node itself still line up
+        let shards = scrubber.scrub(r#"[{"node": "sim-node-001"}]"#);
+        let nodes = scrubber.scrub(r#"{"nodes": {"abc": {"name": "sim-node-001"}}}"#);
+        let placeholder = &scrubber.nodes["sim-node-001"];
+        assert!(shards.contains(placeholder) && nodes.contains(placeholder));
+    }
+
+    fn recorder(name: &str) -> Recorder {
+        let dir = std::env::temp_dir().join(format!("parietal-recorder-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        Recorder::new(&RecordConfig { dir: Some(dir), scrub: false }).unwrap().unwrap()
+    }
+
+    fn files(recorder: &Recorder) -> Vec<String> {
+        let snapshot = recorder.snapshot.lock().unwrap().clone().unwrap();
+        let mut files: Vec<_> = std::fs::read_dir(&snapshot).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn records_under_the_fixture_file_names() {
+        let recorder = recorder("names");
+
+        recorder.start_snapshot();
+        recorder.record("health", "_cluster/health", 200, "{}");
+        recorder.record("root", "", 200, "{}");
+        recorder.record("allocation", "_cat/allocation", 200, "[]");
+        recorder.record("settings", "_cluster/settings", 200, "{}");
+        recorder.record("sniff", "_nodes/http", 200, "{}");
+        recorder.record("nodes", "_nodes/stats", 503, "{}");
+
+        assert_eq!(files(&recorder), [
+            ALLOCATION_FIXTURE, "_cluster_health.json", SETTINGS_FIXTURE, "_nodes.json.503", ROOT_FIXTURE, "_sniff.json", MANIFEST_FILE,
+        ]);
+    }
+
+    #[tokio::test]
+    async fn recorded_snapshots_load_as_fixtures() {
+        let recorder = recorder("round-trip");
+
+        recorder.start_snapshot();
+        for (resource, body) in DEMO_DATA {
+            recorder.record(resource.name(), resource.fixture_file(), 200, body);
+        }
+
+        let snapshot = recorder.snapshot.lock().unwrap().clone().unwrap();
+        let fixtures = FixtureSource::from_dir(&snapshot).unwrap();
+        for (resource, body) in DEMO_DATA {
+            assert_eq!(fixtures.body(*resource), Some(*body));
+        }
+        assert_eq!(fixtures.nodes().await.unwrap().nodes.len(), FixtureSource::embedded().nodes().await.unwrap().nodes.len());
+    }
+
+    #[test]
+    fn scrubs_relocations_and_ipv6_addresses() {
+        let mut scrubber = Scrubber::default();
+
+        let scrubbed = scrubber.scrub(r#"{"node": "node-a -> 192.168.1.7 Xy1z node-b", "ip": "[2001:db8::7]:9300"}"#);
+        let value: Value = serde_json::from_str(&scrubbed).unwrap();
+        // Keys are visited in order, so the IPv6 address comes first
+        assert_eq!(value["ip"], "[fd00::1]:9300");
+        assert_eq!(value["node"], "node-1 -> 10.0.0.2 Xy1z node-2");
+
+        assert_eq!(scrubber.scrub_ips("from fe80::1 and ::ffff:10.9.8.7 at 12:30:45"), "from fd00::3 and fd00::4 at 12:30:45");
+        assert_eq!(scrubber.scrub_host("db-1/2001:db8::7"), "host-1/fd00::1");
+        assert_eq!(scrubber.scrub_node_column("UNASSIGNED"), "UNASSIGNED");
+    }
+}
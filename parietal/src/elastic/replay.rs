@@ -0,0 +1,436 @@
+use crate::config::ReplayConfig;
+use crate::elastic::data::{ClusterInfo, IndexInfo, NodeOutput, Recovery, ShallowShard};
+use crate::elastic::error::ElasticError;
+use crate::elastic::source::{parse, ClusterSource, Resource};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use juniper::GraphQLObject;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Snapshot directories are named like this by the recorder.
+const SNAPSHOT_NAME_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+
+#[derive(Debug)]
+struct Snapshot {
+    timestamp: DateTime<Utc>,
+    bodies: HashMap<Resource, Arc<str>>,
+}
+
+#[derive(GraphQLObject, Serialize, Debug, Clone)]
+#[graphql(description = "Playback state of a replayed recording")]
+pub struct ReplayStatus {
+    pub position: i32,
+    pub snapshots: i32,
+    pub timestamp: DateTime<Utc>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub paused: bool,
+    pub speed: f64,
+}
+
+#[derive(Debug)]
+struct Playback {
+    /// Replay time at `anchor`
+    clock: DateTime<Utc>,
+    anchor: Instant,
+    speed: f64,
+    paused: bool,
+    position: usize,
+}
+
+impl Playback {
+    fn now(&self) -> DateTime<Utc> {
+        match self.paused {
+            true => self.clock,
+            false => {
+                let elapsed = self.anchor.elapsed().as_secs_f64() * self.speed;
+                self.clock + chrono::Duration::milliseconds((elapsed * 1000.0) as i64)
+            }
+        }
+    }
+
+    fn set_clock(&mut self, clock: DateTime<Utc>) {
+        self.clock = clock;
+        self.anchor = Instant::now();
+    }
+}
+
+/// Pause, seek and speed controls of a `ReplaySource`, shared with the API.
+#[derive(Debug)]
+pub struct ReplayControl {
+    timestamps: Vec<DateTime<Utc>>,
+    loop_playback: bool,
+    playback: Mutex<Playback>,
+}
+
+impl ReplayControl {
+    fn start(&self) -> DateTime<Utc> {
+        self.timestamps[0]
+    }
+
+    fn end(&self) -> DateTime<Utc> {
+        self.timestamps[self.timestamps.len() - 1]
+    }
+
+    /// Moves to the snapshot matching the current replay time. Called once
+    /// per refresh.
+    fn advance(&self) -> usize {
+        let mut playback = self.playback.lock().unwrap();
+        let mut now = playback.now();
+
+        if now > self.end() && self.loop_playback && !playback.paused {
+            playback.set_clock(self.start());
+            now = self.start();
+        }
+
+        let position = self.position_at(now);
+        playback.position = position;
+        position
+    }
+
+    /// Index of the last snapshot taken at or before `at`.
+    fn position_at(&self, at: DateTime<Utc>) -> usize {
+        self.timestamps.partition_point(|ts| *ts <= at).saturating_sub(1)
+    }
+
+    fn position(&self) -> usize {
+        self.playback.lock().unwrap().position
+    }
+
+    pub fn pause(&self) {
+        let mut playback = self.playback.lock().unwrap();
+        let now = playback.now();
+        playback.set_clock(now);
+        playback.paused = true;
+    }
+
+    pub fn resume(&self) {
+        let mut playback = self.playback.lock().unwrap();
+        let clock = playback.clock;
+        playback.set_clock(clock);
+        playback.paused = false;
+    }
+
+    pub fn set_speed(&self, speed: f64) -> Result<()> {
+        if !speed.is_finite() || speed <= 0.0 {
+            bail!("Replay speed must be a positive number");
+        }
+
+        let mut playback = self.playback.lock().unwrap();
+        let now = playback.now();
+        playback.set_clock(now);
+        playback.speed = speed;
+        Ok(())
+    }
+
+    /// Jumps to a point in time, clamped to the recording.
+    pub fn seek_time(&self, at: DateTime<Utc>) {
+        let at = at.clamp(self.start(), self.end());
+        self.playback.lock().unwrap().set_clock(at);
+    }
+
+    pub fn seek_position(&self, position: usize) -> Result<()> {
+        let Some(at) = self.timestamps.get(position) else {
+            bail!("Position {} is out of range, the recording has {} snapshots", position, self.timestamps.len());
+        };
+
+        self.seek_time(*at);
+        Ok(())
+    }
+
+    /// Reports the snapshot at the playback clock, which the data catches
+    /// up with on the next refresh.
+    pub fn status(&self) -> ReplayStatus {
+        let playback = self.playback.lock().unwrap();
+        let position = self.position_at(playback.now());
+
+        ReplayStatus {
+            position: position as i32,
+            snapshots: self.timestamps.len() as i32,
+            timestamp: self.timestamps[position],
+            start: self.start(),
+            end: self.end(),
+            paused: playback.paused,
+            speed: playback.speed,
+        }
+    }
+}
+
+/// Steps through snapshots captured by record mode, following the recorded
+/// timestamps at a configurable speed.
+#[derive(Debug)]
+pub struct ReplaySource {
+    origin: String,
+    snapshots: Vec<Snapshot>,
+    control: ReplayControl,
+}
+
+impl ReplaySource {
+    pub fn new(config: &ReplayConfig) -> Result<Self> {
+        let path = config.path.as_ref().context("elastic.replay.path is required for the replay source")?;
+
+        let name = path.to_string_lossy();
+        let mut snapshots = if path.is_dir() {
+            load_dir(path)?
+        } else if name.ends_with(".tar") {
+            load_archive(open(path)?)?
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            load_archive(flate2::read::GzDecoder::new(open(path)?))?
+        } else {
+            bail!("elastic.replay.path {} is neither a directory nor a .tar/.tar.gz archive", path.display());
+        };
+
+        if snapshots.is_empty() {
+            bail!("No complete snapshots found in {}", path.display());
+        }
+
+        snapshots.sort_by_key(|snapshot| snapshot.timestamp);
+        log::info!("Replaying {} snapshots from {}", snapshots.len(), path.display());
+
+        let timestamps: Vec<_> = snapshots.iter().map(|snapshot| snapshot.timestamp).collect();
+        let control = ReplayControl {
+            playback: Mutex::new(Playback {
+                clock: timestamps[0],
+                anchor: Instant::now(),
+                speed: 1.0,
+                paused: config.start_paused,
+                position: 0,
+            }),
+            timestamps,
+            loop_playback: config.loop_playback,
+        };
+        control.set_speed(config.speed).context("elastic.replay.speed")?;
+
+        Ok(ReplaySource {
+            origin: path.display().to_string(),
+            snapshots,
+            control,
+        })
+    }
+
+    fn get<T: DeserializeOwned>(&self, resource: Resource) -> Result<T, ElasticError> {
+        let snapshot = &self.snapshots[self.control.position()];
+        let body = snapshot.bodies.get(&resource).ok_or_else(|| ElasticError::MissingFixture {
+            origin: self.origin.clone(),
+            resource: resource.name(),
+        })?;
+
+        parse(resource.fixture_file(), body)
+    }
+}
+
+#[async_trait]
+impl ClusterSource for ReplaySource {
+    async fn health(&self) -> Result<ClusterInfo, ElasticError> {
+        self.get(Resource::Health)
+    }
+
+    async fn indices(&self) -> Result<Vec<IndexInfo>, ElasticError> {
+        self.get(Resource::Indices)
+    }
+
+    async fn recovery(&self) -> Result<Recovery, ElasticError> {
+        self.get(Resource::Recovery)
+    }
+
+    async fn shards(&self) -> Result<Vec<ShallowShard>, ElasticError> {
+        self.get(Resource::Shards)
+    }
+
+    async fn nodes(&self) -> Result<NodeOutput, ElasticError> {
+        self.get(Resource::Nodes)
+    }
+
+    async fn prepare(&self) -> Result<(), ElasticError> {
+        self.control.advance();
+        Ok(())
+    }
+
+    fn replay(&self) -> Option<&ReplayControl> {
+        Some(&self.control)
+    }
+}
+
+fn open(path: &Path) -> Result<std::fs::File> {
+    std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))
+}
+
+/// Turns snapshot names and their files into snapshots. Refreshes only
+/// fetch the resources that are due, so a resource missing from a snapshot
+/// is carried over from the ones before it, like the warehouse does.
+/// Snapshots before every resource has been seen once are skipped, and so
+/// are snapshots whose name isn't a timestamp, as there is no telling when
+/// to play them. A lone snapshot is the exception: it is a plain fixture
+/// directory and needs no timing.
+fn collect(groups: BTreeMap<String, HashMap<Resource, String>>) -> Vec<Snapshot> {
+    let single = groups.len() == 1;
+    let mut snapshots = Vec::new();
+    let mut current: HashMap<Resource, Arc<str>> = HashMap::new();
+
+    for (name, bodies) in groups {
+        let timestamp = match NaiveDateTime::parse_from_str(&name, SNAPSHOT_NAME_FORMAT) {
+            Ok(timestamp) => timestamp.and_utc(),
+            Err(_) if single => DateTime::<Utc>::UNIX_EPOCH,
+            Err(_) => {
+                log::warn!("Skipping snapshot {}, its name is not a {} timestamp", name, SNAPSHOT_NAME_FORMAT);
+                continue;
+            }
+        };
+
+        current.extend(bodies.into_iter().map(|(resource, body)| (resource, Arc::from(body))));
+        if current.len() < Resource::ALL.len() {
+            log::warn!("Skipping incomplete snapshot {}", name);
+            continue;
+        }
+
+        snapshots.push(Snapshot { timestamp, bodies: current.clone() });
+    }
+
+    snapshots
+}
+
+/// Loads a record directory. A directory holding fixture files directly is
+/// a single snapshot.
+fn load_dir(dir: &Path) -> Result<Vec<Snapshot>> {
+    let mut groups = BTreeMap::new();
+
+    let mut dirs = vec![dir.to_path_buf()];
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+
+    for snapshot_dir in dirs {
+        let mut bodies = HashMap::new();
+        for resource in Resource::ALL {
+            let path = snapshot_dir.join(resource.fixture_file());
+            if path.is_file() {
+                let body = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                bodies.insert(resource, body);
+            }
+        }
+
+        if !bodies.is_empty() {
+            let name = snapshot_dir.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+            groups.insert(name, bodies);
+        }
+    }
+
+    Ok(collect(groups))
+}
+
+fn load_archive(reader: impl Read) -> Result<Vec<Snapshot>> {
+    let mut groups: BTreeMap<String, HashMap<Resource, String>> = BTreeMap::new();
+    let mut archive = tar::Archive::new(reader);
+
+    for entry in archive.entries().context("Failed to read archive")? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_path_buf();
+
+        let Some(resource) = path.file_name()
+            .and_then(|name| Resource::ALL.into_iter().find(|r| name == r.fixture_file()))
+        else {
+            continue;
+        };
+
+        let name = path.parent()
+            .and_then(|parent| parent.file_name())
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let mut body = String::new();
+        entry.read_to_string(&mut body)
+            .with_context(|| format!("Failed to read {} from archive", path.display()))?;
+        groups.entry(name).or_default().insert(resource, body);
+    }
+
+    Ok(collect(groups))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures").join(name)
+    }
+
+    /// Lays out a recording like record mode does, one snapshot directory
+    /// per fixture directory.
+    fn recording(name: &str, snapshots: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("parietal-replay-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        for (snapshot, fixture_dir) in snapshots {
+            std::fs::create_dir_all(dir.join(snapshot)).unwrap();
+            for resource in Resource::ALL {
+                std::fs::copy(fixture(fixture_dir).join(resource.fixture_file()), dir.join(snapshot).join(resource.fixture_file())).unwrap();
+            }
+        }
+
+        dir
+    }
+
+    async fn cluster_name(source: &ReplaySource) -> String {
+        source.prepare().await.unwrap();
+        serde_json::to_value(source.health().await.unwrap()).unwrap()["cluster_name"].as_str().unwrap().to_string()
+    }
+
+    fn replay(path: PathBuf) -> ReplaySource {
+        ReplaySource::new(&ReplayConfig { path: Some(path), start_paused: true, ..ReplayConfig::default() }).unwrap()
+    }
+
+    #[tokio::test]
+    async fn replays_snapshots_in_order_with_the_recorded_gap() {
+        // Named out of order, and the first one sorts last as a directory entry
+        let source = replay(recording("ordered", &[
+            ("20240601T100010.500Z", "elasticsearch-7.17"),
+            ("20240601T100000.500Z", "elasticsearch-6.8"),
+        ]));
+
+        let status = source.control.status();
+        assert_eq!(status.snapshots, 2);
+        assert_eq!(status.end - status.start, chrono::Duration::seconds(10));
+
+        assert_eq!(cluster_name(&source).await, "es6-cluster");
+
+        // Nothing changes until the recorded gap has passed
+        source.control.seek_time(status.start + chrono::Duration::milliseconds(9_999));
+        assert_eq!(cluster_name(&source).await, "es6-cluster");
+
+        source.control.seek_time(status.start + chrono::Duration::seconds(10));
+        assert_eq!(cluster_name(&source).await, "es7-cluster");
+        assert_eq!(source.control.status().position, 1);
+    }
+
+    #[test]
+    fn skips_snapshots_without_a_timestamp() {
+        let source = replay(recording("untimed", &[
+            ("20240601T100000.000Z", "elasticsearch-6.8"),
+            ("copy-of-something", "elasticsearch-7.17"),
+            ("20240601T100005.000Z", "opensearch-2.11"),
+        ]));
+
+        let status = source.control.status();
+        assert_eq!(status.snapshots, 2);
+        assert_eq!(status.end - status.start, chrono::Duration::seconds(5));
+    }
+
+    #[test]
+    fn replays_a_fixture_directory_as_one_snapshot() {
+        let source = replay(fixture("elasticsearch-7.17"));
+        assert_eq!(source.control.status().snapshots, 1);
+    }
+}
@@ -0,0 +1,1060 @@
+use crate::config::SimulatorConfig;
+use crate::elastic::data::{AllocationExplanation, CatAllocation, ClusterInfo, ClusterSettings, DiskAllocation, ExplainResponse, IndexInfo, NodeOutput, Recovery, ShallowShard};
+use crate::elastic::error::ElasticError;
+use crate::elastic::source::{parse, ClusterSource, ExplainRequest, Resource};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+const MB: u64 = 1024 * 1024;
+const GB: u64 = 1024 * MB;
+const NODE_MEMORY: u64 = 64 * GB;
+/// Rough document size, used to derive doc counts from shard sizes.
+const BYTES_PER_DOC: u64 = 2048;
+
+#[derive(Debug)]
+struct SimNode {
+    id: String,
+    name: String,
+    ip: String,
+    attributes: Vec<(String, String)>,
+    disk: u64,
+    /// Ticks left until a failed node rejoins; 0 when it is up.
+    down_ticks: u32,
+}
+
+impl SimNode {
+    fn is_up(&self) -> bool {
+        self.down_ticks == 0
+    }
+
+    fn attribute(&self, key: &str) -> Option<&str> {
+        self.attributes.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug)]
+struct SimIndex {
+    name: String,
+    uuid: String,
+    /// Value of the tier attribute the index is pinned to.
+    tier: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ShardState {
+    Started,
+    Relocating,
+    Initializing,
+    Unassigned,
+}
+
+impl ShardState {
+    fn name(&self) -> &'static str {
+        match self {
+            ShardState::Started => "STARTED",
+            ShardState::Relocating => "RELOCATING",
+            ShardState::Initializing => "INITIALIZING",
+            ShardState::Unassigned => "UNASSIGNED",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SimRecovery {
+    /// `PEER` or `EXISTING_STORE`
+    kind: &'static str,
+    source: usize,
+    target: usize,
+    recovered: u64,
+    started_ms: i64,
+}
+
+/// One shard copy. A relocating copy sits on its source node with the
+/// target in `recovery`; an initializing copy sits on its target.
+#[derive(Debug)]
+struct SimShard {
+    index: usize,
+    number: u32,
+    primary: bool,
+    size: u64,
+    state: ShardState,
+    node: Option<usize>,
+    /// Node an unassigned primary was lost with, it comes back from there.
+    last_node: Option<usize>,
+    recovery: Option<SimRecovery>,
+}
+
+impl SimShard {
+    fn is_active(&self) -> bool {
+        matches!(self.state, ShardState::Started | ShardState::Relocating)
+    }
+
+    /// Nodes holding (or receiving) this copy.
+    fn occupies(&self, node: usize) -> bool {
+        self.node == Some(node) || self.recovery.is_some_and(|r| r.target == node)
+    }
+}
+
+/// Shards are stored grouped: the copies of a shard are adjacent, and the
+/// shards of an index are adjacent, so lookups are ranges instead of scans.
+#[derive(Debug)]
+struct Simulation {
+    config: SimulatorConfig,
+    rng: StdRng,
+    nodes: Vec<SimNode>,
+    indices: Vec<SimIndex>,
+    shards: Vec<SimShard>,
+}
+
+impl Simulation {
+    fn new(config: &SimulatorConfig) -> Self {
+        let mut rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        let nodes: Vec<_> = (0..config.nodes)
+            .map(|i| SimNode {
+                id: random_id(&mut rng),
+                name: format!("sim-node-{:03}", i + 1),
+                ip: format!("10.0.{}.{}", i / 250, i % 250 + 1),
+                attributes: config.attributes.iter()
+                    .filter(|(_, values)| !values.is_empty())
+                    .map(|(key, values)| (key.clone(), values[i % values.len()].clone()))
+                    .collect(),
+                disk: config.disk_per_node_gb * GB,
+                down_ticks: 0,
+            })
+            .collect();
+
+        let tiers = config.tier_attribute.as_ref().and_then(|tier| config.attributes.get(tier));
+        let indices: Vec<_> = (0..config.indices)
+            .map(|i| SimIndex {
+                name: format!("sim-index-{:04}", i + 1),
+                uuid: random_id(&mut rng),
+                tier: tiers.filter(|t| !t.is_empty()).map(|t| t[i % t.len()].clone()),
+            })
+            .collect();
+
+        let mut shards = Vec::new();
+        for index in 0..indices.len() {
+            let min = config.min_shard_size_mb.min(config.max_shard_size_mb) * MB;
+            let max = config.max_shard_size_mb.max(config.min_shard_size_mb) * MB;
+            let base = rng.gen_range(min..=max);
+
+            for number in 0..config.shards_per_index {
+                // Shards of one index are roughly the same size
+                let size = (base as f64 * rng.gen_range(0.8..1.2)) as u64;
+                for copy in 0..=config.replicas {
+                    shards.push(SimShard {
+                        index,
+                        number,
+                        primary: copy == 0,
+                        size,
+                        state: ShardState::Unassigned,
+                        node: None,
+                        last_node: None,
+                        recovery: None,
+                    });
+                }
+            }
+        }
+
+        let mut simulation = Simulation { config: config.clone(), rng, nodes, indices, shards };
+        simulation.allocate_initial();
+        simulation
+    }
+
+    /// Places every copy that fits as started, so the simulation begins
+    /// from a settled cluster.
+    fn allocate_initial(&mut self) {
+        let mut usage = vec![0; self.nodes.len()];
+
+        for i in 0..self.shards.len() {
+            if let Some(node) = self.pick_node(i, None, &usage) {
+                let shard = &mut self.shards[i];
+                shard.state = ShardState::Started;
+                shard.node = Some(node);
+                usage[node] += shard.size;
+            }
+        }
+    }
+
+    fn copies_per_shard(&self) -> usize {
+        self.config.replicas as usize + 1
+    }
+
+    /// All copies of the shard that copy `i` belongs to.
+    fn copies(&self, i: usize) -> std::ops::Range<usize> {
+        let start = i - i % self.copies_per_shard();
+        start..start + self.copies_per_shard()
+    }
+
+    fn index_shards(&self, index: usize) -> &[SimShard] {
+        let len = self.config.shards_per_index as usize * self.copies_per_shard();
+        &self.shards[index * len..(index + 1) * len]
+    }
+
+    fn tick(&mut self) {
+        self.advance_recoveries();
+        self.rejoin_nodes();
+        self.fail_node();
+        self.assign_unassigned();
+        self.start_relocations();
+    }
+
+    fn advance_recoveries(&mut self) {
+        let step = self.config.recovery_mb_per_tick * MB;
+
+        for shard in &mut self.shards {
+            let Some(recovery) = &mut shard.recovery else {
+                continue;
+            };
+
+            recovery.recovered = (recovery.recovered + step).min(shard.size);
+            if recovery.recovered < shard.size {
+                continue;
+            }
+
+            shard.node = Some(recovery.target);
+            shard.state = ShardState::Started;
+            shard.recovery = None;
+        }
+    }
+
+    fn rejoin_nodes(&mut self) {
+        for node in &mut self.nodes {
+            if node.down_ticks > 0 {
+                node.down_ticks -= 1;
+                if node.down_ticks == 0 {
+                    log::info!("Simulator: {} rejoined the cluster", node.name);
+                }
+            }
+        }
+    }
+
+    fn fail_node(&mut self) {
+        if !self.rng.gen_bool(self.config.node_failure_chance.clamp(0.0, 1.0)) {
+            return;
+        }
+
+        let up: Vec<_> = (0..self.nodes.len()).filter(|&n| self.nodes[n].is_up()).collect();
+        let Some(&failed) = up.choose(&mut self.rng) else {
+            return;
+        };
+
+        self.nodes[failed].down_ticks = self.config.node_down_ticks.max(1);
+        log::info!("Simulator: {} left the cluster", self.nodes[failed].name);
+
+        for i in 0..self.shards.len() {
+            let shard = &mut self.shards[i];
+            let target_lost = shard.recovery.is_some_and(|r| r.target == failed);
+            let source_lost = shard.recovery.is_some_and(|r| r.source == failed);
+
+            match shard.state {
+                ShardState::Relocating if target_lost => {
+                    shard.state = ShardState::Started;
+                    shard.recovery = None;
+                }
+                ShardState::Initializing if source_lost || target_lost => self.unassign(i, failed),
+                ShardState::Started | ShardState::Relocating if shard.node == Some(failed) => self.unassign(i, failed),
+                _ => {}
+            }
+        }
+    }
+
+    /// Drops a copy lost with `node`, promoting a started replica when the
+    /// primary is lost.
+    fn unassign(&mut self, i: usize, node: usize) {
+        let shard = &mut self.shards[i];
+        shard.state = ShardState::Unassigned;
+        shard.node = None;
+        shard.recovery = None;
+
+        if !shard.primary {
+            return;
+        }
+
+        let replica = self.copies(i).find(|&c| !self.shards[c].primary && self.shards[c].is_active());
+
+        match replica {
+            Some(replica) => {
+                self.shards[replica].primary = true;
+                self.shards[i].primary = false;
+            }
+            None => self.shards[i].last_node = Some(node),
+        }
+    }
+
+    fn active_recoveries(&self) -> usize {
+        self.shards.iter().filter(|s| s.recovery.is_some()).count()
+    }
+
+    fn assign_unassigned(&mut self) {
+        let mut budget = self.config.max_concurrent_recoveries.saturating_sub(self.active_recoveries());
+        let mut usage = self.disk_usage();
+
+        for i in 0..self.shards.len() {
+            if budget == 0 {
+                return;
+            }
+
+            let shard = &self.shards[i];
+            if shard.state != ShardState::Unassigned {
+                continue;
+            }
+
+            let recovery = match shard.primary {
+                // A primary without replicas comes back from disk once its node does
+                true => match shard.last_node {
+                    Some(node) if self.nodes[node].is_up() => SimRecovery {
+                        kind: "EXISTING_STORE",
+                        source: node,
+                        target: node,
+                        recovered: 0,
+                        started_ms: now_ms(),
+                    },
+                    _ => continue,
+                },
+                false => {
+                    let Some(source) = self.primary_node(i) else {
+                        continue;
+                    };
+                    let Some(target) = self.pick_node(i, None, &usage) else {
+                        continue;
+                    };
+                    SimRecovery { kind: "PEER", source, target, recovered: 0, started_ms: now_ms() }
+                }
+            };
+
+            let shard = &mut self.shards[i];
+            shard.state = ShardState::Initializing;
+            shard.node = Some(recovery.target);
+            shard.last_node = None;
+            shard.recovery = Some(recovery);
+            usage[recovery.target] += shard.size;
+            budget -= 1;
+        }
+    }
+
+    fn start_relocations(&mut self) {
+        let budget = self.config.max_concurrent_recoveries.saturating_sub(self.active_recoveries());
+        let started: Vec<_> = (0..self.shards.len()).filter(|&i| self.shards[i].state == ShardState::Started).collect();
+
+        let count = self.config.relocations_per_tick.min(budget);
+        let candidates: Vec<_> = started.choose_multiple(&mut self.rng, count).copied().collect();
+        let mut usage = self.disk_usage();
+
+        for i in candidates {
+            let source = self.shards[i].node.unwrap();
+            let Some(target) = self.pick_node(i, Some(source), &usage) else {
+                continue;
+            };
+
+            let shard = &mut self.shards[i];
+            usage[target] += shard.size;
+            shard.state = ShardState::Relocating;
+            shard.recovery = Some(SimRecovery { kind: "PEER", source, target, recovered: 0, started_ms: now_ms() });
+        }
+    }
+
+    fn primary_node(&self, i: usize) -> Option<usize> {
+        self.copies(i)
+            .map(|c| &self.shards[c])
+            .find(|s| s.primary && s.is_active())
+            .and_then(|s| s.node)
+    }
+
+    /// Picks the emptiest eligible node for a copy: up, in the index's
+    /// tier, and not already holding a copy of the same shard.
+    fn pick_node(&self, i: usize, exclude: Option<usize>, usage: &[u64]) -> Option<usize> {
+        let tier = self.indices[self.shards[i].index].tier.as_deref();
+        let tier_attribute = self.config.tier_attribute.as_deref().unwrap_or_default();
+
+        (0..self.nodes.len())
+            .filter(|&n| Some(n) != exclude && self.nodes[n].is_up())
+            .filter(|&n| tier.is_none() || self.nodes[n].attribute(tier_attribute) == tier)
+            .filter(|&n| !self.copies(i).any(|c| self.shards[c].occupies(n)))
+            .min_by_key(|&n| usage[n])
+    }
+
+    /// Bytes held or being received by each node.
+    fn disk_usage(&self) -> Vec<u64> {
+        let mut usage = vec![0; self.nodes.len()];
+        for shard in &self.shards {
+            if let Some(node) = shard.node {
+                usage[node] += shard.size;
+            }
+            if let Some(recovery) = shard.recovery.filter(|r| shard.node != Some(r.target)) {
+                usage[recovery.target] += shard.size;
+            }
+        }
+        usage
+    }
+
+    fn health(&self) -> Value {
+        let count = |f: &dyn Fn(&SimShard) -> bool| self.shards.iter().filter(|s| f(s)).count();
+
+        let active = count(&|s| s.is_active());
+        let inactive_primaries = count(&|s| s.primary && !s.is_active());
+        let inactive = count(&|s| !s.is_active());
+        let up = self.nodes.iter().filter(|n| n.is_up()).count();
+
+        let status = match (inactive_primaries, inactive) {
+            (0, 0) => "green",
+            (0, _) => "yellow",
+            _ => "red",
+        };
+
+        json!({
+            "cluster_name": self.config.cluster_name,
+            "status": status,
+            "timed_out": false,
+            "number_of_nodes": up,
+            "number_of_data_nodes": up,
+            "active_primary_shards": count(&|s| s.primary && s.is_active()),
+            "active_shards": active,
+            "relocating_shards": count(&|s| s.state == ShardState::Relocating),
+            "initializing_shards": count(&|s| s.state == ShardState::Initializing),
+            "unassigned_shards": count(&|s| s.state == ShardState::Unassigned),
+            "delayed_unassigned_shards": 0,
+            "number_of_pending_tasks": 0,
+            "number_of_in_flight_fetch": 0,
+            "task_max_waiting_in_queue_millis": 0,
+            "active_shards_percent_as_number": match self.shards.len() {
+                0 => 100.0,
+                total => active as f64 * 100.0 / total as f64,
+            },
+        })
+    }
+
+    fn indices(&self) -> Value {
+        let indices = self.indices.iter().enumerate().map(|(i, index)| {
+            let shards = self.index_shards(i);
+            let primaries: Vec<_> = shards.iter().filter(|s| s.primary).collect();
+
+            let health = if primaries.iter().any(|s| !s.is_active()) {
+                "red"
+            } else if shards.iter().any(|s| !s.is_active()) {
+                "yellow"
+            } else {
+                "green"
+            };
+            let store: u64 = shards.iter().filter(|s| s.is_active()).map(|s| s.size).sum();
+            let pri_store: u64 = primaries.iter().filter(|s| s.is_active()).map(|s| s.size).sum();
+
+            json!({
+                "health": health,
+                "status": "open",
+                "index": index.name,
+                "uuid": index.uuid,
+                "pri": self.config.shards_per_index.to_string(),
+                "rep": self.config.replicas.to_string(),
+                "docs.count": (pri_store / BYTES_PER_DOC).to_string(),
+                "docs.deleted": "0",
+                "store.size": human_size(store),
+                "pri.store.size": human_size(pri_store),
+            })
+        });
+
+        Value::Array(indices.collect())
+    }
+
+    fn recovery(&self) -> Value {
+        let mut indices = Map::new();
+
+        for shard in &self.shards {
+            let Some(recovery) = &shard.recovery else {
+                continue;
+            };
+
+            let elapsed = now_ms() - recovery.started_ms;
+            let entry = indices.entry(self.indices[shard.index].name.clone())
+                .or_insert_with(|| json!({ "shards": [] }));
+            entry["shards"].as_array_mut().unwrap().push(json!({
+                "id": shard.number,
+                "type": recovery.kind,
+                "stage": "INDEX",
+                "primary": shard.primary,
+                "start_time_in_millis": recovery.started_ms,
+                "total_time_in_millis": elapsed,
+                "source": self.recovery_node(recovery.source),
+                "target": self.recovery_node(recovery.target),
+                "index": {
+                    "size": {
+                        "total_in_bytes": shard.size,
+                        "reused_in_bytes": 0,
+                        "recovered_in_bytes": recovery.recovered,
+                        "recovered_from_snapshot_in_bytes": 0,
+                        "percent": percent(recovery.recovered, shard.size),
+                    },
+                    "files": {
+                        "total": 100,
+                        "reused": 0,
+                        "recovered": recovery.recovered * 100 / shard.size.max(1),
+                        "percent": percent(recovery.recovered, shard.size),
+                    },
+                    "total_time_in_millis": elapsed,
+                    "source_throttle_time_in_millis": 0,
+                    "target_throttle_time_in_millis": 0,
+                },
+                "translog": {
+                    "recovered": 0,
+                    "total": 0,
+                    "percent": "100.0%",
+                    "total_on_start": 0,
+                    "total_time_in_millis": 0,
+                },
+                "verify_index": {
+                    "check_index_time_in_millis": 0,
+                    "total_time_in_millis": 0,
+                },
+            }));
+        }
+
+        Value::Object(indices)
+    }
+
+    fn recovery_node(&self, node: usize) -> Value {
+        let node = &self.nodes[node];
+        json!({
+            "id": node.id,
+            "host": node.ip,
+            "transport_address": format!("{}:9300", node.ip),
+            "ip": node.ip,
+            "name": node.name,
+        })
+    }
+
+    fn shards(&self) -> Value {
+        let shards = self.shards.iter().map(|shard| {
+            let node = shard.node.map(|n| &self.nodes[n]);
+            let name = match (node, shard.state, &shard.recovery) {
+                (Some(node), ShardState::Relocating, Some(recovery)) => {
+                    let target = &self.nodes[recovery.target];
+                    Some(format!("{} -> {} {} {}", node.name, target.ip, target.id, target.name))
+                }
+                (node, _, _) => node.map(|n| n.name.clone()),
+            };
+
+            json!({
+                "index": self.indices[shard.index].name,
+                "shard": shard.number.to_string(),
+                "prirep": if shard.primary { "p" } else { "r" },
+                "state": shard.state.name(),
+                "docs": node.map(|_| (shard.size / BYTES_PER_DOC).to_string()),
+                "store": node.map(|_| human_size(shard.size)),
+                "ip": node.map(|n| n.ip.clone()),
+                "node": name,
+            })
+        });
+
+        Value::Array(shards.collect())
+    }
+
+    fn allocation(&self) -> Value {
+        let usage = self.disk_usage();
+        let mut rows: Vec<_> = (0..self.nodes.len())
+            .filter(|&n| self.nodes[n].is_up())
+            .map(|n| {
+                let node = &self.nodes[n];
+                let used = usage[n].min(node.disk);
+                json!({
+                    "shards": self.shards.iter().filter(|s| s.node == Some(n)).count().to_string(),
+                    "disk.indices": used.to_string(),
+                    "disk.used": used.to_string(),
+                    "disk.avail": (node.disk - used).to_string(),
+                    "disk.total": node.disk.to_string(),
+                    "disk.percent": (used * 100 / node.disk.max(1)).to_string(),
+                    "host": node.ip,
+                    "ip": node.ip,
+                    "node": node.name,
+                })
+            })
+            .collect();
+
+        let unassigned = self.shards.iter().filter(|s| s.state == ShardState::Unassigned).count();
+        if unassigned > 0 {
+            rows.push(json!({ "shards": unassigned.to_string(), "node": "UNASSIGNED" }));
+        }
+
+        Value::Array(rows)
+    }
+
+    /// Default disk settings of a recent Elasticsearch.
+    fn settings(&self) -> Value {
+        json!({
+            "persistent": {},
+            "transient": {},
+            "defaults": {
+                "cluster.routing.allocation.disk.threshold_enabled": "true",
+                "cluster.routing.allocation.disk.watermark.low": "85%",
+                "cluster.routing.allocation.disk.watermark.low.max_headroom": "200GB",
+                "cluster.routing.allocation.disk.watermark.high": "90%",
+                "cluster.routing.allocation.disk.watermark.high.max_headroom": "150GB",
+                "cluster.routing.allocation.disk.watermark.flood_stage": "95%",
+                "cluster.routing.allocation.disk.watermark.flood_stage.max_headroom": "100GB",
+            },
+        })
+    }
+
+    /// An allocation explain response for the copy in `request`, with
+    /// verdicts from the deciders the simulation models. None when there
+    /// is no such copy.
+    fn explain(&self, request: &ExplainRequest) -> Option<Value> {
+        let index = self.indices.iter().position(|index| index.name == request.index)?;
+        if request.shard >= self.config.shards_per_index {
+            return None;
+        }
+        let first = (index * self.config.shards_per_index as usize + request.shard as usize) * self.copies_per_shard();
+        let i = self.copies(first).find(|&c| {
+            let shard = &self.shards[c];
+            shard.primary == request.primary && match &request.current_node {
+                Some(name) => shard.node.is_some_and(|n| &self.nodes[n].name == name),
+                None => shard.state == ShardState::Unassigned,
+            }
+        })?;
+
+        let shard = &self.shards[i];
+        let mut response = json!({
+            "index": request.index,
+            "shard": request.shard,
+            "primary": shard.primary,
+            "current_state": shard.state.name().to_lowercase(),
+        });
+
+        if let Some(node) = shard.node.map(|n| &self.nodes[n]) {
+            response["current_node"] = json!({ "id": node.id, "name": node.name, "transport_address": format!("{}:9300", node.ip) });
+            match (shard.state, shard.recovery) {
+                (ShardState::Relocating, Some(recovery)) => response["explanation"] = json!(format!(
+                    "the shard is in the process of relocating from node [{}] to node [{}], wait until relocation has completed",
+                    node.name, self.nodes[recovery.target].name,
+                )),
+                (ShardState::Initializing, _) => response["explanation"] = json!(format!(
+                    "the shard is in the process of initializing on node [{}], wait until initialization has completed",
+                    node.name,
+                )),
+                _ => {
+                    response["can_remain_on_current_node"] = json!("yes");
+                    response["can_rebalance_cluster"] = json!("yes");
+                    response["rebalance_explanation"] = json!("cannot rebalance as no target node exists that can both allocate this shard and improve the cluster balance");
+                }
+            }
+            return Some(response);
+        }
+
+        let node_left = shard.last_node.is_some() || self.nodes.iter().any(|node| !node.is_up());
+        response["unassigned_info"] = json!({
+            "reason": if node_left { "NODE_LEFT" } else { "INDEX_CREATED" },
+            "last_allocation_status": "no_attempt",
+        });
+        let throttled = self.config.max_concurrent_recoveries <= self.active_recoveries();
+
+        // A primary without replicas can only come back from its own node
+        if let Some(node) = shard.last_node {
+            let (verdict, explanation) = match (self.nodes[node].is_up(), throttled) {
+                (false, _) => ("no_valid_shard_copy", "cannot allocate because a previous copy of the primary shard existed but can no longer be found on the nodes in the cluster"),
+                (true, true) => ("throttled", "allocation temporarily throttled"),
+                (true, false) => ("yes", "can allocate the shard"),
+            };
+            response["can_allocate"] = json!(verdict);
+            response["allocate_explanation"] = json!(explanation);
+            return Some(response);
+        }
+
+        let usage = self.disk_usage();
+        let mut up: Vec<_> = (0..self.nodes.len()).filter(|&n| self.nodes[n].is_up()).collect();
+        up.sort_by_key(|&n| usage[n]);
+        let tier = self.indices[index].tier.as_deref();
+        let tier_attribute = self.config.tier_attribute.as_deref().unwrap_or_default();
+
+        let decisions: Vec<_> = up.iter().enumerate().map(|(rank, &n)| {
+            let node = &self.nodes[n];
+            let mut deciders = Vec::new();
+            if !shard.primary && self.primary_node(i).is_none() {
+                deciders.push(("replica_after_primary_active", "NO", "primary shard for this replica is not yet active".to_string()));
+            }
+            if tier.is_some() && node.attribute(tier_attribute) != tier {
+                deciders.push(("filter", "NO", format!(
+                    "node does not match index setting [index.routing.allocation.require.{}] filters [{}:\"{}\"]",
+                    tier_attribute, tier_attribute, tier.unwrap_or_default(),
+                )));
+            }
+            if self.copies(i).any(|c| self.shards[c].occupies(n)) {
+                deciders.push(("same_shard", "NO", format!("a copy of this shard is already allocated to this node [{}]", node.name)));
+            }
+            if throttled {
+                deciders.push(("throttling", "THROTTLE", format!(
+                    "reached the limit of ongoing recoveries [{}], cluster setting [cluster.routing.allocation.node_concurrent_recoveries={}]",
+                    self.config.max_concurrent_recoveries, self.config.max_concurrent_recoveries,
+                )));
+            }
+
+            let decision = match (deciders.iter().any(|d| d.1 == "NO"), deciders.is_empty()) {
+                (true, _) => "no",
+                (false, false) => "throttled",
+                (false, true) => "yes",
+            };
+            json!({
+                "node_id": node.id,
+                "node_name": node.name,
+                "transport_address": format!("{}:9300", node.ip),
+                "node_decision": decision,
+                "weight_ranking": rank + 1,
+                "deciders": deciders.into_iter()
+                    .map(|(decider, decision, explanation)| json!({ "decider": decider, "decision": decision, "explanation": explanation }))
+                    .collect::<Vec<_>>(),
+            })
+        }).collect();
+
+        let has = |decision: &str| decisions.iter().any(|d| d["node_decision"] == decision);
+        let (verdict, explanation) = match (has("yes"), has("throttled")) {
+            (true, _) => ("yes", "can allocate the shard"),
+            (false, true) => ("throttled", "allocation temporarily throttled"),
+            (false, false) => ("no", "cannot allocate because allocation is not permitted to any of the nodes"),
+        };
+        response["can_allocate"] = json!(verdict);
+        response["allocate_explanation"] = json!(explanation);
+        response["node_allocation_decisions"] = Value::Array(decisions);
+
+        Some(response)
+    }
+
+    fn nodes(&mut self) -> Value {
+        let timestamp = now_ms();
+        let usage = self.disk_usage();
+        let mut recoveries = vec![0; self.nodes.len()];
+        for recovery in self.shards.iter().filter_map(|s| s.recovery) {
+            recoveries[recovery.source] += 1;
+            if recovery.target != recovery.source {
+                recoveries[recovery.target] += 1;
+            }
+        }
+
+        let mut nodes = Map::new();
+        for n in 0..self.nodes.len() {
+            if !self.nodes[n].is_up() {
+                continue;
+            }
+
+            let cpu = (self.rng.gen_range(5..40) + recoveries[n] * 10).min(100);
+            let mem_used = (NODE_MEMORY as f64 * self.rng.gen_range(0.7..0.9)) as u64;
+            let disk_used = usage[n].min(self.nodes[n].disk);
+
+            let node = &self.nodes[n];
+            let free = node.disk - disk_used;
+            nodes.insert(node.id.clone(), json!({
+                "name": node.name,
+                "transport_address": format!("{}:9300", node.ip),
+                "host": node.ip,
+                "ip": node.ip,
+                "roles": ["data", "ingest", "master"],
+                "attributes": node.attributes.iter().cloned().collect::<HashMap<_, _>>(),
+                "process": {
+                    "timestamp": timestamp,
+                    "cpu": { "percent": cpu },
+                    "mem": { "total_virtual_in_bytes": NODE_MEMORY * 4 },
+                },
+                "fs": {
+                    "total": {
+                        "total_in_bytes": node.disk,
+                        "free_in_bytes": free,
+                        "available_in_bytes": free,
+                    },
+                },
+                "os": {
+                    "cpu": { "percent": cpu },
+                    "mem": {
+                        "total_in_bytes": NODE_MEMORY,
+                        "free_in_bytes": NODE_MEMORY - mem_used,
+                        "used_in_bytes": mem_used,
+                        "free_percent": (NODE_MEMORY - mem_used) * 100 / NODE_MEMORY,
+                        "used_percent": mem_used * 100 / NODE_MEMORY,
+                    },
+                    "swap": {
+                        "total_in_bytes": 0,
+                        "free_in_bytes": 0,
+                        "used_in_bytes": 0,
+                    },
+                },
+            }));
+        }
+
+        json!({ "nodes": nodes })
+    }
+}
+
+/// Generates a synthetic cluster from `SimulatorConfig` and evolves it on
+/// every refresh: rebalancing relocations, node failures and the recoveries
+/// that follow.
+#[derive(Debug)]
+pub struct SimulatorSource {
+    simulation: Mutex<Simulation>,
+}
+
+impl SimulatorSource {
+    pub fn new(config: &SimulatorConfig) -> Result<Self> {
+        if config.nodes == 0 {
+            bail!("elastic.simulator.nodes must be at least 1");
+        }
+
+        let simulation = Simulation::new(config);
+        log::info!(
+            "Simulating {} nodes with {} indices and {} shards",
+            simulation.nodes.len(),
+            simulation.indices.len(),
+            simulation.shards.len(),
+        );
+
+        Ok(SimulatorSource {
+            simulation: Mutex::new(simulation),
+        })
+    }
+
+    /// Renders a resource as the matching Elasticsearch response and parses
+    /// it like any other, so the simulator exercises the same code paths.
+    pub fn body(&self, resource: Resource) -> String {
+        let mut simulation = self.simulation.lock().unwrap();
+
+        let value = match resource {
+            Resource::Health => simulation.health(),
+            Resource::Indices => simulation.indices(),
+            Resource::Recovery => simulation.recovery(),
+            Resource::Shards => simulation.shards(),
+            Resource::Nodes => simulation.nodes(),
+        };
+
+        value.to_string()
+    }
+
+    /// The `_cat/allocation` response.
+    pub fn allocation_body(&self) -> String {
+        self.simulation.lock().unwrap().allocation().to_string()
+    }
+
+    /// The `_cluster/settings` response, with defaults.
+    pub fn settings_body(&self) -> String {
+        self.simulation.lock().unwrap().settings().to_string()
+    }
+
+    /// The `_cluster/allocation/explain` response for `request`, none when
+    /// the copy doesn't exist.
+    pub fn explain_body(&self, request: &ExplainRequest) -> Option<String> {
+        self.simulation.lock().unwrap().explain(request).map(|value| value.to_string())
+    }
+
+    fn get<T: DeserializeOwned>(&self, resource: Resource) -> Result<T, ElasticError> {
+        parse(resource.fixture_file(), &self.body(resource))
+    }
+}
+
+#[async_trait]
+impl ClusterSource for SimulatorSource {
+    async fn health(&self) -> Result<ClusterInfo, ElasticError> {
+        self.get(Resource::Health)
+    }
+
+    async fn indices(&self) -> Result<Vec<IndexInfo>, ElasticError> {
+        self.get(Resource::Indices)
+    }
+
+    async fn recovery(&self) -> Result<Recovery, ElasticError> {
+        self.get(Resource::Recovery)
+    }
+
+    async fn shards(&self) -> Result<Vec<ShallowShard>, ElasticError> {
+        self.get(Resource::Shards)
+    }
+
+    async fn nodes(&self) -> Result<NodeOutput, ElasticError> {
+        self.get(Resource::Nodes)
+    }
+
+    async fn allocation_explain(&self, request: &ExplainRequest) -> Result<Option<AllocationExplanation>, ElasticError> {
+        let endpoint = "_cluster/allocation/explain";
+        let Some(response) = self.explain_body(request) else {
+            return Err(ElasticError::Status {
+                url: endpoint.to_string(),
+                status: 400,
+                error_type: Some("illegal_argument_exception".to_string()),
+                reason: Some(format!("unable to find shard [{}][{}] to explain", request.index, request.shard)),
+            });
+        };
+
+        let response: ExplainResponse = parse(endpoint, &response)?;
+        Ok(Some(response.into_explanation(chrono::Utc::now())))
+    }
+
+    async fn disk_allocation(&self) -> Result<Option<DiskAllocation>, ElasticError> {
+        let nodes: Vec<CatAllocation> = parse("_cat/allocation", &self.allocation_body())?;
+        let settings: ClusterSettings = parse("_cluster/settings", &self.settings_body())?;
+        Ok(Some(DiskAllocation { nodes, watermarks: Some(settings.disk_watermarks()) }))
+    }
+
+    async fn prepare(&self) -> Result<(), ElasticError> {
+        self.simulation.lock().unwrap().tick();
+        Ok(())
+    }
+}
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+fn random_id(rng: &mut StdRng) -> String {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    (0..22).map(|_| *CHARS.choose(rng).unwrap() as char).collect()
+}
+
+fn percent(part: u64, total: u64) -> String {
+    match total {
+        0 => "100.0%".to_string(),
+        total => format!("{:.1}%", part as f64 * 100.0 / total as f64),
+    }
+}
+
+/// Formats bytes the way `_cat` APIs do, e.g. `6.8mb`.
+fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["kb", "mb", "gb", "tb", "pb"];
+
+    if bytes < 1024 {
+        return format!("{}b", bytes);
+    }
+
+    let mut size = bytes as f64;
+    let mut unit = "b";
+    for next in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+
+    format!("{:.1}{}", size, unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> SimulatorConfig {
+        SimulatorConfig {
+            nodes: 6,
+            indices: 10,
+            shards_per_index: 3,
+            replicas: 1,
+            min_shard_size_mb: 100,
+            max_shard_size_mb: 1_000,
+            recovery_mb_per_tick: 500,
+            node_failure_chance: 0.0,
+            node_down_ticks: 2,
+            seed: Some(7),
+            ..SimulatorConfig::default()
+        }
+    }
+
+    fn states(simulation: &Simulation) -> Vec<ShardState> {
+        simulation.shards.iter().map(|shard| shard.state).collect()
+    }
+
+    #[tokio::test]
+    async fn a_seed_reproduces_the_same_cluster() {
+        let config = SimulatorConfig { node_failure_chance: 0.2, ..config() };
+        let (first, second) = (SimulatorSource::new(&config).unwrap(), SimulatorSource::new(&config).unwrap());
+        let other = SimulatorSource::new(&SimulatorConfig { seed: Some(8), ..config.clone() }).unwrap();
+        assert_ne!(first.body(Resource::Indices), other.body(Resource::Indices));
+
+        for _ in 0..20 {
+            first.prepare().await.unwrap();
+            second.prepare().await.unwrap();
+            // Recovery and nodes carry wall-clock times, the rest must match exactly
+            for resource in [Resource::Health, Resource::Indices, Resource::Shards] {
+                assert_eq!(first.body(resource), second.body(resource), "{}", resource.name());
+            }
+            assert_eq!(first.allocation_body(), second.allocation_body());
+        }
+    }
+
+    #[tokio::test]
+    async fn every_copy_of_every_shard_is_listed() {
+        let config = SimulatorConfig { node_failure_chance: 0.3, ..config() };
+        let source = SimulatorSource::new(&config).unwrap();
+
+        for _ in 0..20 {
+            source.prepare().await.unwrap();
+            let shards: Vec<ShallowShard> = source.get(Resource::Shards).unwrap();
+            assert_eq!(shards.len(), 10 * 3 * 2);
+
+            let mut copies: HashMap<(&str, &str), (usize, usize)> = HashMap::new();
+            for shard in &shards {
+                let (primaries, total) = copies.entry((&shard.index, &shard.shard)).or_default();
+                *primaries += usize::from(shard.prirep == "p");
+                *total += 1;
+            }
+            assert_eq!(copies.len(), 10 * 3);
+            assert!(copies.values().all(|&counts| counts == (1, 2)), "one primary and one replica per shard");
+
+            let health: Value = serde_json::from_str(&source.body(Resource::Health)).unwrap();
+            let listed = ["active_shards", "initializing_shards", "unassigned_shards"].iter()
+                .map(|field| health[field].as_u64().unwrap())
+                .sum::<u64>();
+            assert_eq!(listed, 60);
+        }
+    }
+
+    #[test]
+    fn relocations_start_and_finish() {
+        let mut simulation = Simulation::new(&config());
+        assert!(states(&simulation).iter().all(|state| *state == ShardState::Started));
+
+        simulation.tick();
+        let relocating: Vec<_> = (0..simulation.shards.len())
+            .filter(|&i| simulation.shards[i].state == ShardState::Relocating)
+            .map(|i| (i, simulation.shards[i].recovery.unwrap().target))
+            .collect();
+        assert_eq!(relocating.len(), 2, "relocations_per_tick");
+        assert_eq!(simulation.recovery().as_object().unwrap().values().map(|index| index["shards"].as_array().unwrap().len()).sum::<usize>(), 2);
+        let shards = simulation.shards().to_string();
+        assert_eq!(shards.matches(" -> ").count(), 2);
+
+        simulation.config.relocations_per_tick = 0;
+        // At most 1000mb per shard, recovered at 500mb per tick
+        for _ in 0..3 {
+            simulation.tick();
+        }
+        for (i, target) in relocating {
+            assert_eq!(simulation.shards[i].state, ShardState::Started);
+            assert_eq!(simulation.shards[i].node, Some(target));
+        }
+        assert!(states(&simulation).iter().all(|state| *state == ShardState::Started));
+        assert_eq!(simulation.recovery(), json!({}));
+    }
+
+    #[test]
+    fn copies_lost_with_a_node_recover_elsewhere() {
+        let mut simulation = Simulation::new(&SimulatorConfig { relocations_per_tick: 0, node_failure_chance: 1.0, ..config() });
+
+        simulation.tick();
+        let failed = simulation.nodes.iter().position(|node| !node.is_up()).unwrap();
+        assert!(simulation.shards.iter().all(|shard| !shard.occupies(failed)));
+        assert!(states(&simulation).contains(&ShardState::Initializing), "replicas of the lost copies are rebuilt");
+        assert_eq!(simulation.health()["status"], "yellow");
+
+        simulation.config.node_failure_chance = 0.0;
+        for _ in 0..3 {
+            simulation.tick();
+        }
+        assert!(states(&simulation).iter().all(|state| *state == ShardState::Started));
+        assert_eq!(simulation.health()["status"], "green");
+        assert!(simulation.shards.iter().all(|shard| shard.node != Some(failed)));
+    }
+}
@@ -0,0 +1,120 @@
+use crate::elastic::breaker::BreakerStatus;
+use crate::elastic::data::{AllocationExplanation, ClusterInfo, DiskAllocation, IndexInfo, NodeOutput, Recovery, ShallowShard};
+use crate::elastic::error::ElasticError;
+use crate::elastic::replay::ReplayControl;
+
+use async_trait::async_trait;
+use juniper::GraphQLEnum;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+
+/// The resources a `Warehouse` keeps track of.
+#[derive(GraphQLEnum, Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Resource {
+    Health,
+    Indices,
+    Recovery,
+    Shards,
+    Nodes,
+}
+
+impl Resource {
+    pub const ALL: [Resource; 5] = [
+        Resource::Health,
+        Resource::Indices,
+        Resource::Recovery,
+        Resource::Shards,
+        Resource::Nodes,
+    ];
+
+    /// Name used in config keys and the API.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Resource::Health => "health",
+            Resource::Indices => "indices",
+            Resource::Recovery => "recovery",
+            Resource::Shards => "shards",
+            Resource::Nodes => "nodes",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Resource> {
+        Resource::ALL.into_iter().find(|resource| resource.name() == name)
+    }
+
+    /// File the resource is stored in inside a fixture directory.
+    pub fn fixture_file(&self) -> &'static str {
+        match self {
+            Resource::Health => "_cluster_health.json",
+            Resource::Indices => "_cat_indices.json",
+            Resource::Recovery => "_recovery.json",
+            Resource::Shards => "_cat_shards.json",
+            Resource::Nodes => "_nodes.json",
+        }
+    }
+}
+
+/// Body of a `_cluster/allocation/explain` request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ExplainRequest {
+    pub index: String,
+    pub shard: u32,
+    pub primary: bool,
+    /// Required for assigned copies, to tell them apart
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_node: Option<String>,
+}
+
+/// Where a `Warehouse` gets its data from: a live cluster, canned fixtures,
+/// or anything else that can produce the same responses.
+#[async_trait]
+pub trait ClusterSource: Debug + Send + Sync {
+    async fn health(&self) -> Result<ClusterInfo, ElasticError>;
+    async fn indices(&self) -> Result<Vec<IndexInfo>, ElasticError>;
+    async fn recovery(&self) -> Result<Recovery, ElasticError>;
+    async fn shards(&self) -> Result<Vec<ShallowShard>, ElasticError>;
+    async fn nodes(&self) -> Result<NodeOutput, ElasticError>;
+
+    /// Asks why a shard copy is where it is, or unassigned. Sources that
+    /// can't tell return none.
+    async fn allocation_explain(&self, _request: &ExplainRequest) -> Result<Option<AllocationExplanation>, ElasticError> {
+        Ok(None)
+    }
+
+    /// Disk usage per node with the watermarks in effect, fetched along
+    /// with the nodes. Sources that can't tell return none.
+    async fn disk_allocation(&self) -> Result<Option<DiskAllocation>, ElasticError> {
+        Ok(None)
+    }
+
+    /// Runs before every refresh, e.g. to discover the cluster version.
+    async fn prepare(&self) -> Result<(), ElasticError> {
+        Ok(())
+    }
+
+    /// Called when a refresh failed.
+    fn disconnected(&self) {}
+
+    fn breaker_status(&self) -> BreakerStatus {
+        BreakerStatus::default()
+    }
+
+    /// Playback controls, for sources that replay a recording.
+    fn replay(&self) -> Option<&ReplayControl> {
+        None
+    }
+}
+
+/// Parses a response body, keeping the failing path on errors.
+pub fn parse<T: DeserializeOwned>(endpoint: &str, body: &str) -> Result<T, ElasticError> {
+    let jd = &mut serde_json::Deserializer::from_str(body);
+
+    let result: Result<T, _> = serde_path_to_error::deserialize(jd);
+
+    match result {
+        Ok(data) => Ok(data),
+        Err(e) => Err(ElasticError::from_deserialize(endpoint, e, body)),
+    }
+}
@@ -0,0 +1,386 @@
+use crate::config::TlsConfig;
+
+use anyhow::{bail, Context, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Builds the rustls configuration described by `tls`, or `None` when the
+/// defaults are fine and reqwest can build its own.
+pub fn client_config(tls: &TlsConfig) -> Result<Option<ClientConfig>> {
+    let customized = tls.ca_file.is_some()
+        || tls.client_cert_file.is_some()
+        || tls.client_key_file.is_some()
+        || tls.client_pkcs12_file.is_some()
+        || !tls.pinned_fingerprints.is_empty()
+        || tls.insecure;
+
+    if !customized {
+        return Ok(None);
+    }
+
+    if tls.insecure && !tls.pinned_fingerprints.is_empty() {
+        bail!("elastic.tls: insecure and pinned_fingerprints are mutually exclusive");
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let verifier = server_verifier(tls, &provider)?;
+
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier);
+
+    let config = match client_identity(tls)? {
+        Some((chain, key)) => builder
+            .with_client_auth_cert(chain, key)
+            .context("elastic.tls: invalid client certificate or key")?,
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(Some(config))
+}
+
+/// Verifier for the server certificate: the WebPKI checks against the
+/// bundled roots and `ca_file`, narrowed down by any pins.
+fn server_verifier(tls: &TlsConfig, provider: &Arc<CryptoProvider>) -> Result<Arc<dyn ServerCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+    let ca_certs = match &tls.ca_file {
+        Some(ca_file) => {
+            let certs = read_pem_certs(ca_file).context("elastic.tls: invalid ca_file")?;
+            if certs.is_empty() {
+                bail!("elastic.tls: ca_file {} contains no certificates", ca_file.display());
+            }
+            for cert in &certs {
+                roots.add(cert.clone())
+                    .with_context(|| format!("elastic.tls: invalid CA certificate in {}", ca_file.display()))?;
+            }
+            certs
+        }
+        None => Vec::new(),
+    };
+
+    if tls.insecure {
+        log::error!("!!! elastic.tls.insecure is set: TLS certificate verification is DISABLED. Never use this in production !!!");
+        return Ok(Arc::new(InsecureVerifier { provider: provider.clone() }));
+    }
+
+    let webpki = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .context("elastic.tls: failed to build certificate verifier")?;
+
+    if tls.pinned_fingerprints.is_empty() {
+        return Ok(webpki);
+    }
+
+    let pins = tls.pinned_fingerprints.iter()
+        .map(|pin| parse_fingerprint(pin))
+        .collect::<Result<Vec<_>>>()?;
+    let pinned_cas = ca_certs.into_iter()
+        .filter(|cert| pins.contains(&fingerprint(cert)))
+        .collect();
+
+    Ok(Arc::new(PinnedVerifier { webpki, pins, pinned_cas, provider: provider.clone() }))
+}
+
+type Identity = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
+fn client_identity(tls: &TlsConfig) -> Result<Option<Identity>> {
+    match (&tls.client_cert_file, &tls.client_key_file, &tls.client_pkcs12_file) {
+        (Some(_), Some(_), Some(_)) => {
+            bail!("elastic.tls: use either client_cert_file/client_key_file or client_pkcs12_file, not both")
+        }
+        (Some(cert_file), Some(key_file), None) => {
+            let chain = read_pem_certs(cert_file).context("elastic.tls: invalid client_cert_file")?;
+            if chain.is_empty() {
+                bail!("elastic.tls: client_cert_file {} contains no certificates", cert_file.display());
+            }
+            let mut reader = BufReader::new(open(key_file)?);
+            let key = rustls_pemfile::private_key(&mut reader)
+                .with_context(|| format!("elastic.tls: failed to parse client_key_file {}", key_file.display()))?
+                .with_context(|| format!("elastic.tls: client_key_file {} contains no private key", key_file.display()))?;
+            Ok(Some((chain, key)))
+        }
+        (None, None, Some(pkcs12_file)) => {
+            let data = std::fs::read(pkcs12_file)
+                .with_context(|| format!("elastic.tls: failed to read client_pkcs12_file {}", pkcs12_file.display()))?;
+            let password = tls.client_pkcs12_password.as_deref().unwrap_or_default();
+            let keystore = p12_keystore::KeyStore::from_pkcs12(&data, password)
+                .with_context(|| format!("elastic.tls: failed to decode client_pkcs12_file {}", pkcs12_file.display()))?;
+            let (_, key_chain) = keystore.private_key_chain()
+                .with_context(|| format!("elastic.tls: client_pkcs12_file {} contains no private key", pkcs12_file.display()))?;
+            let chain = key_chain.chain().iter()
+                .map(|cert| CertificateDer::from(cert.as_der().to_vec()))
+                .collect();
+            let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_chain.key().to_vec()));
+            Ok(Some((chain, key)))
+        }
+        (None, None, None) => Ok(None),
+        _ => bail!("elastic.tls: client_cert_file and client_key_file must be set together"),
+    }
+}
+
+fn open(path: &Path) -> Result<std::fs::File> {
+    std::fs::File::open(path).with_context(|| format!("elastic.tls: failed to open {}", path.display()))
+}
+
+fn read_pem_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(open(path)?);
+    rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("failed to parse PEM certificates in {}", path.display()))
+}
+
+fn parse_fingerprint(pin: &str) -> Result<[u8; 32]> {
+    let hex = pin.replace(':', "").to_ascii_lowercase();
+    let mut fingerprint = [0u8; 32];
+
+    if hex.len() != 64 || !hex.is_ascii() {
+        bail!("elastic.tls: pinned fingerprint {pin} is not a SHA-256 hex digest");
+    }
+
+    for (i, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .with_context(|| format!("elastic.tls: pinned fingerprint {pin} is not a SHA-256 hex digest"))?;
+    }
+
+    Ok(fingerprint)
+}
+
+fn fingerprint(cert: &CertificateDer<'_>) -> [u8; 32] {
+    Sha256::digest(cert.as_ref()).into()
+}
+
+/// Trusts a server only when its chain passes the usual WebPKI checks
+/// (roots, hostname, validity) and the leaf either is pinned itself or is
+/// issued, through the presented intermediates, by a pinned certificate.
+/// Merely presenting a pinned CA next to an unrelated leaf is not enough.
+#[derive(Debug)]
+struct PinnedVerifier {
+    webpki: Arc<WebPkiServerVerifier>,
+    pins: Vec<[u8; 32]>,
+    /// Certificates from `ca_file` that are pinned, as they may be left out
+    /// of the chain the server presents.
+    pinned_cas: Vec<CertificateDer<'static>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.webpki.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+
+        if self.pins.contains(&fingerprint(end_entity)) {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        // Check there is a path from the leaf to a pinned certificate by
+        // verifying again with only the pinned certificates as roots
+        let mut pinned_roots = RootCertStore::empty();
+        let pinned_intermediates = intermediates.iter().filter(|cert| self.pins.contains(&fingerprint(cert)));
+        for cert in self.pinned_cas.iter().chain(pinned_intermediates) {
+            // Certificates that cannot act as a root cannot anchor a path either
+            let _ = pinned_roots.add(cert.clone().into_owned());
+        }
+
+        let mismatch = || rustls::Error::General("server certificate chain does not include any pinned fingerprint".into());
+        if pinned_roots.is_empty() {
+            return Err(mismatch());
+        }
+
+        WebPkiServerVerifier::builder_with_provider(Arc::new(pinned_roots), self.provider.clone())
+            .build()
+            .map_err(|_| mismatch())?
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+            .map_err(|_| mismatch())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.webpki.supported_verify_schemes()
+    }
+}
+
+/// Accepts any server certificate. Handshake signatures are still checked.
+#[derive(Debug)]
+struct InsecureVerifier {
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for InsecureVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+    use std::path::PathBuf;
+
+    struct Issuer {
+        cert: Certificate,
+        key: KeyPair,
+    }
+
+    fn ca(name: &str) -> Issuer {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.distinguished_name.push(rcgen::DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        Issuer { cert: params.self_signed(&key).unwrap(), key }
+    }
+
+    fn intermediate(name: &str, issuer: &Issuer) -> Issuer {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.distinguished_name.push(rcgen::DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        Issuer { cert: params.signed_by(&key, &issuer.cert, &issuer.key).unwrap(), key }
+    }
+
+    fn leaf(host: &str, issuer: &Issuer) -> CertificateDer<'static> {
+        let key = KeyPair::generate().unwrap();
+        let params = CertificateParams::new(vec![host.to_string()]).unwrap();
+        params.signed_by(&key, &issuer.cert, &issuer.key).unwrap().der().clone()
+    }
+
+    fn ca_file(name: &str, cas: &[&Issuer]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("parietal-tls-{}-{}.pem", std::process::id(), name));
+        std::fs::write(&path, cas.iter().map(|ca| ca.cert.pem()).collect::<String>()).unwrap();
+        path
+    }
+
+    fn pin(cert: &CertificateDer<'_>) -> String {
+        fingerprint(cert).iter().map(|byte| format!("{byte:02X}")).collect::<Vec<_>>().join(":")
+    }
+
+    fn verify(tls: &TlsConfig, chain: &[CertificateDer<'static>]) -> Result<ServerCertVerified, rustls::Error> {
+        let verifier = server_verifier(tls, &Arc::new(rustls::crypto::ring::default_provider())).unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        verifier.verify_server_cert(&chain[0], &chain[1..], &server_name, &[], UnixTime::now())
+    }
+
+    fn pinned(ca_file: PathBuf, pins: &[String]) -> TlsConfig {
+        TlsConfig { ca_file: Some(ca_file), pinned_fingerprints: pins.to_vec(), ..TlsConfig::default() }
+    }
+
+    #[test]
+    fn accepts_a_chain_through_a_pinned_certificate() {
+        let root = ca("root");
+        let issuing = intermediate("issuing", &root);
+        let server = leaf("localhost", &issuing);
+        let chain = [server.clone(), issuing.cert.der().clone()];
+        let ca_file = ca_file("matching", &[&root]);
+
+        for pinned_cert in [&server, issuing.cert.der(), root.cert.der()] {
+            verify(&pinned(ca_file.clone(), &[pin(pinned_cert)]), &chain).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_a_chain_without_a_pinned_certificate() {
+        let root = ca("root");
+        let server = leaf("localhost", &root);
+        let other = ca("other");
+
+        let error = verify(&pinned(ca_file("mismatch", &[&root]), &[pin(other.cert.der())]), &[server]).unwrap_err();
+        assert!(error.to_string().contains("pinned"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_a_pinned_intermediate_next_to_a_foreign_leaf() {
+        let root = ca("root");
+        let issuing = intermediate("issuing", &root);
+        // Trusted, but not the pinned CA
+        let other = ca("other");
+        let foreign = leaf("localhost", &other);
+        let tls = pinned(ca_file("foreign", &[&root, &other]), &[pin(issuing.cert.der())]);
+
+        assert!(verify(&tls, &[foreign.clone(), issuing.cert.der().clone()]).is_err());
+
+        // Nor is a self-signed leaf trusted just for sitting next to a pin
+        let attacker = ca("attacker");
+        let self_signed = leaf("localhost", &attacker);
+        assert!(verify(&tls, &[self_signed, attacker.cert.der().clone(), issuing.cert.der().clone()]).is_err());
+    }
+
+    #[test]
+    fn pins_still_check_the_hostname() {
+        let root = ca("root");
+        let server = leaf("elsewhere.example", &root);
+
+        assert!(verify(&pinned(ca_file("hostname", &[&root]), &[pin(&server)]), &[server]).is_err());
+    }
+
+    #[test]
+    fn invalid_tls_material_fails_at_startup() {
+        let empty = ca_file("empty", &[]);
+        assert!(client_config(&TlsConfig { ca_file: Some(empty.clone()), ..TlsConfig::default() }).is_err());
+        assert!(client_config(&pinned(ca_file("bad-pin", &[&ca("root")]), &["not-a-fingerprint".to_string()])).is_err());
+        assert!(client_config(&TlsConfig { insecure: true, pinned_fingerprints: vec!["00".repeat(32)], ..TlsConfig::default() }).is_err());
+        assert!(client_config(&TlsConfig { client_cert_file: Some(empty), ..TlsConfig::default() }).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use crate::elastic::data::RootInfo;
+
+use juniper::GraphQLEnum;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+#[derive(GraphQLEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, TS)]
+#[serde(rename_all = "lowercase")]
+#[ts(export)]
+pub enum Distribution {
+    Elasticsearch,
+    OpenSearch,
+}
+
+/// Distribution and version reported by `GET /`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerVersion {
+    pub distribution: Distribution,
+    pub number: String,
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl ServerVersion {
+    pub fn from_root(root: &RootInfo) -> Option<Self> {
+        let distribution = match root.version.distribution.as_deref() {
+            Some("opensearch") => Distribution::OpenSearch,
+            _ => Distribution::Elasticsearch,
+        };
+
+        let mut parts = root.version.number.split(['.', '-']);
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next().and_then(|p| p.parse().ok()).unwrap_or(0);
+
+        Some(ServerVersion {
+            distribution,
+            number: root.version.number.clone(),
+            major,
+            minor,
+        })
+    }
+
+    pub fn is_supported(&self) -> bool {
+        match self.distribution {
+            Distribution::Elasticsearch => (6..=8).contains(&self.major),
+            Distribution::OpenSearch => (1..=2).contains(&self.major),
+        }
+    }
+
+    /// Hidden indices only show up in `_cat/indices` with `expand_wildcards`,
+    /// which Elasticsearch 7.7 and OpenSearch understand.
+    fn has_hidden_indices(&self) -> bool {
+        match self.distribution {
+            Distribution::Elasticsearch => (self.major, self.minor) >= (7, 7),
+            Distribution::OpenSearch => true,
+        }
+    }
+
+    /// Endpoints for this version. Hidden indices are only listed when
+    /// asked for, as they can outnumber the regular ones.
+    ///
+    /// That is the only difference between the supported versions. The
+    /// other APIs Cortex calls take the same parameters everywhere, and
+    /// `include_type_name` only matters to mapping and template APIs, which
+    /// it doesn't call. What does differ is handled when parsing:
+    /// - Elasticsearch before 7.2 leaves the health and shard counts of
+    ///   closed indices empty
+    /// - OpenSearch 2 calls the master role `cluster_manager`
+    /// - Elasticsearch 8 adds columns to `_cat` output (e.g. `dataset`),
+    ///   which are ignored like any unknown field
+    pub fn endpoints(&self, include_hidden_indices: bool) -> Endpoints {
+        Endpoints {
+            indices: match include_hidden_indices && self.has_hidden_indices() {
+                true => "_cat/indices?format=json&expand_wildcards=all",
+                false => "_cat/indices?format=json",
+            },
+            ..Endpoints::default()
+        }
+    }
+}
+
+/// Endpoint paths for each resource the client fetches.
+#[derive(Debug, Clone, Copy)]
+pub struct Endpoints {
+    pub health: &'static str,
+    pub indices: &'static str,
+    pub recovery: &'static str,
+    pub shards: &'static str,
+    pub nodes: &'static str,
+    pub nodes_http: &'static str,
+    pub allocation_explain: &'static str,
+    pub allocation: &'static str,
+    pub settings: &'static str,
+}
+
+// Used until the version is known, and in demo mode
+impl Default for Endpoints {
+    fn default() -> Self {
+        Endpoints {
+            health: "_cluster/health",
+            indices: "_cat/indices?format=json",
+            recovery: "_recovery?format=json&active_only=true",
+            shards: "_cat/shards?format=json",
+            nodes: "_nodes/stats/fs,process,os?format=json",
+            nodes_http: "_nodes/http?format=json",
+            allocation_explain: "_cluster/allocation/explain",
+            allocation: "_cat/allocation?format=json&bytes=b",
+            settings: "_cluster/settings?include_defaults=true&flat_settings=true",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn detect(fixture: &str) -> ServerVersion {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures").join(fixture).join("_root.json");
+        let root: RootInfo = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        ServerVersion::from_root(&root).unwrap()
+    }
+
+    #[test]
+    fn detects_the_version_of_each_fixture() {
+        let versions = [
+            ("elasticsearch-6.8", Distribution::Elasticsearch, 6, 8),
+            ("elasticsearch-7.17", Distribution::Elasticsearch, 7, 17),
+            ("elasticsearch-8.13", Distribution::Elasticsearch, 8, 13),
+            ("opensearch-1.3", Distribution::OpenSearch, 1, 3),
+            ("opensearch-2.11", Distribution::OpenSearch, 2, 11),
+        ];
+
+        for (fixture, distribution, major, minor) in versions {
+            let version = detect(fixture);
+            assert_eq!((version.distribution, version.major, version.minor), (distribution, major, minor), "{}", fixture);
+            assert!(version.is_supported(), "{}", fixture);
+        }
+    }
+
+    #[test]
+    fn hidden_indices_are_only_listed_when_asked_for() {
+        let hidden = |fixture, include| detect(fixture).endpoints(include).indices.contains("expand_wildcards");
+
+        assert!(!hidden("elasticsearch-7.17", false));
+        assert!(hidden("elasticsearch-7.17", true));
+        assert!(hidden("elasticsearch-8.13", true));
+        assert!(hidden("opensearch-1.3", true));
+        assert!(hidden("opensearch-2.11", true));
+        // Hidden indices came with 7.7
+        assert!(!hidden("elasticsearch-6.8", true));
+    }
+}
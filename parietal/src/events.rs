@@ -0,0 +1,491 @@
+use crate::config::EventsConfig;
+use crate::data::Snapshot;
+
+use chrono::{DateTime, Utc};
+use juniper::{GraphQLEnum, GraphQLObject};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::Arc;
+
+#[derive(GraphQLEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    ClusterStatusChanged,
+    NodeJoined,
+    NodeLeft,
+    ShardStarted,
+    ShardRelocating,
+    ShardUnassigned,
+    IndexCreated,
+    IndexDeleted,
+    RecoveryStarted,
+    RecoveryFinished,
+    DiskThresholdCrossed,
+}
+
+#[derive(GraphQLObject, Serialize, Debug, Clone)]
+#[graphql(description = "A change in the cluster, found by comparing consecutive snapshots")]
+pub struct ClusterEvent {
+    /// Increases with every event of a cluster
+    pub id: i32,
+    pub timestamp: DateTime<Utc>,
+    #[graphql(name = "type")]
+    #[serde(rename = "type")]
+    pub event_type: EventType,
+    pub message: String,
+    pub index: Option<String>,
+    pub shard: Option<String>,
+    pub node: Option<String>,
+    /// Value before the change, e.g. the previous status or source node
+    pub from: Option<String>,
+    /// Value after the change, e.g. the new status or target node
+    pub to: Option<String>,
+}
+
+impl ClusterEvent {
+    fn new(timestamp: DateTime<Utc>, event_type: EventType, message: String) -> Self {
+        ClusterEvent {
+            id: 0,
+            timestamp,
+            event_type,
+            message,
+            index: None,
+            shard: None,
+            node: None,
+            from: None,
+            to: None,
+        }
+    }
+}
+
+/// The latest events of one cluster, oldest first.
+#[derive(Debug)]
+pub struct EventLog {
+    config: EventsConfig,
+    next_id: i32,
+    events: VecDeque<ClusterEvent>,
+}
+
+impl EventLog {
+    pub fn new(config: &EventsConfig) -> Self {
+        EventLog {
+            config: config.clone(),
+            next_id: 1,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Adds the events between two consecutive snapshots.
+    pub fn record(&mut self, previous: &Snapshot, current: &Snapshot) {
+        for mut event in diff(previous, current, &self.config) {
+            log::debug!("Event: {}", event.message);
+            event.id = self.next_id;
+            self.next_id = self.next_id.saturating_add(1);
+            self.events.push_back(event);
+        }
+
+        while self.events.len() > self.config.max_events {
+            self.events.pop_front();
+        }
+    }
+
+    /// Events after `since`, of the given types when any are given.
+    pub fn since(&self, since: Option<DateTime<Utc>>, types: Option<&[EventType]>) -> Vec<ClusterEvent> {
+        let start = match since {
+            Some(since) => self.events.partition_point(|event| event.timestamp <= since),
+            None => 0,
+        };
+
+        self.filtered(start, types)
+    }
+
+    /// Events with an ID above `id`, and the ID of the latest event so
+    /// the next call can continue from there.
+    pub fn after(&self, id: i32, types: Option<&[EventType]>) -> (Vec<ClusterEvent>, i32) {
+        let events = self.filtered(self.events.partition_point(|event| event.id <= id), types);
+        (events, self.last_id())
+    }
+
+    pub fn last_id(&self) -> i32 {
+        self.next_id - 1
+    }
+
+    fn filtered(&self, start: usize, types: Option<&[EventType]>) -> Vec<ClusterEvent> {
+        self.events.range(start..)
+            .filter(|event| types.is_none_or(|types| types.contains(&event.event_type)))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Events between two consecutive snapshots. Resources carried over
+/// unchanged are skipped, and nothing is reported against the empty
+/// snapshot before the first refresh.
+fn diff(previous: &Snapshot, current: &Snapshot, config: &EventsConfig) -> Vec<ClusterEvent> {
+    let mut events = Vec::new();
+    if previous.generation == 0 {
+        return events;
+    }
+    let timestamp = current.fetched_at.unwrap_or_else(Utc::now);
+
+    if !Arc::ptr_eq(&previous.cluster, &current.cluster) {
+        let (from, to) = (previous.cluster.status(), current.cluster.status());
+        if from != to {
+            events.push(ClusterEvent {
+                from: Some(from.to_string()),
+                to: Some(to.to_string()),
+                ..ClusterEvent::new(timestamp, EventType::ClusterStatusChanged, format!("Cluster status changed from {} to {}", from, to))
+            });
+        }
+    }
+
+    if !Arc::ptr_eq(&previous.nodes, &current.nodes) {
+        diff_nodes(previous, current, config, timestamp, &mut events);
+    }
+
+    if !Arc::ptr_eq(&previous.indices, &current.indices) {
+        let before: BTreeSet<_> = previous.indices.iter().map(|index| index.name()).collect();
+        let after: BTreeSet<_> = current.indices.iter().map(|index| index.name()).collect();
+
+        for index in after.difference(&before) {
+            events.push(ClusterEvent {
+                index: Some(index.to_string()),
+                ..ClusterEvent::new(timestamp, EventType::IndexCreated, format!("Index {} was created", index))
+            });
+        }
+        for index in before.difference(&after) {
+            events.push(ClusterEvent {
+                index: Some(index.to_string()),
+                ..ClusterEvent::new(timestamp, EventType::IndexDeleted, format!("Index {} was deleted", index))
+            });
+        }
+    }
+
+    if !Arc::ptr_eq(&previous.recovery, &current.recovery) {
+        diff_recoveries(previous, current, timestamp, &mut events);
+    }
+
+    if !Arc::ptr_eq(&previous.shards, &current.shards) {
+        diff_shards(previous, current, timestamp, &mut events);
+    }
+
+    events
+}
+
+fn diff_nodes(previous: &Snapshot, current: &Snapshot, config: &EventsConfig, timestamp: DateTime<Utc>, events: &mut Vec<ClusterEvent>) {
+    // Names can repeat, e.g. after a node is replaced, so nodes are told
+    // apart by ID and reported in ID order
+    let before: BTreeMap<_, _> = previous.nodes.nodes.iter().map(|node| (node.id(), node)).collect();
+    let after: BTreeMap<_, _> = current.nodes.nodes.iter().map(|node| (node.id(), node)).collect();
+
+    for (id, node) in &after {
+        let name = node.name();
+        let Some(old) = before.get(id) else {
+            events.push(ClusterEvent {
+                node: Some(name.to_string()),
+                ..ClusterEvent::new(timestamp, EventType::NodeJoined, format!("Node {} joined the cluster", name))
+            });
+            continue;
+        };
+
+        let (Some(from), Some(to)) = (old.disk_used_percent(), node.disk_used_percent()) else {
+            continue;
+        };
+        for threshold in &config.disk_thresholds_percent {
+            let message = match (from < *threshold, to < *threshold) {
+                (true, false) => format!("Disk usage on {} rose above {}% to {:.1}%", name, threshold, to),
+                (false, true) => format!("Disk usage on {} fell below {}% to {:.1}%", name, threshold, to),
+                _ => continue,
+            };
+            events.push(ClusterEvent {
+                node: Some(name.to_string()),
+                from: Some(format!("{:.1}", from)),
+                to: Some(format!("{:.1}", to)),
+                ..ClusterEvent::new(timestamp, EventType::DiskThresholdCrossed, message)
+            });
+        }
+    }
+
+    for (_, node) in before.iter().filter(|(id, _)| !after.contains_key(*id)) {
+        events.push(ClusterEvent {
+            node: Some(node.name().to_string()),
+            ..ClusterEvent::new(timestamp, EventType::NodeLeft, format!("Node {} left the cluster", node.name()))
+        });
+    }
+}
+
+fn diff_recoveries(previous: &Snapshot, current: &Snapshot, timestamp: DateTime<Utc>, events: &mut Vec<ClusterEvent>) {
+    // Only active recoveries are fetched, so finished ones disappear
+    let (before, after) = (recoveries(previous), recoveries(current));
+
+    for (event_type, recoveries, verb) in [
+        (EventType::RecoveryStarted, after.difference(&before), "started"),
+        (EventType::RecoveryFinished, before.difference(&after), "ended"),
+    ] {
+        for (index, recovery_type, primary, source, target) in recoveries {
+            let copy = if *primary { "primary" } else { "replica" };
+            events.push(ClusterEvent {
+                index: Some(index.to_string()),
+                node: Some(target.to_string()),
+                from: (!source.is_empty()).then(|| source.to_string()),
+                to: Some(target.to_string()),
+                ..ClusterEvent::new(timestamp, event_type, format!("{} recovery of a {} of {} on {} {}", recovery_type, copy, index, target, verb))
+            });
+        }
+    }
+}
+
+/// Recoveries by index, type, primary, source and target node.
+fn recoveries(snapshot: &Snapshot) -> BTreeSet<(&str, &str, bool, &str, &str)> {
+    snapshot.recovery.shards()
+        .map(|(index, shard)| (index, shard.recovery_type(), shard.primary(), shard.source_node(), shard.target_node()))
+        .collect()
+}
+
+fn diff_shards(previous: &Snapshot, current: &Snapshot, timestamp: DateTime<Utc>, events: &mut Vec<ClusterEvent>) {
+    // Replicas of a shard can't be told apart, so copies are compared as
+    // a multiset of where they are and in which state
+    let mut before: HashMap<_, usize> = HashMap::new();
+    for shard in previous.shards.iter() {
+        *before.entry((&shard.index, &shard.shard, &shard.prirep, &shard.state, &shard.node)).or_default() += 1;
+    }
+
+    for shard in current.shards.iter() {
+        if let Some(count) = before.get_mut(&(&shard.index, &shard.shard, &shard.prirep, &shard.state, &shard.node)) {
+            if *count > 0 {
+                *count -= 1;
+                continue;
+            }
+        }
+
+        let name = format!("{}[{}][{}]", shard.index, shard.shard, shard.prirep);
+        let node = shard.node.clone().unwrap_or_default();
+        let event = match shard.state.as_str() {
+            "STARTED" => ClusterEvent {
+                node: Some(node.clone()),
+                ..ClusterEvent::new(timestamp, EventType::ShardStarted, format!("Shard {} started on {}", name, node))
+            },
+            "RELOCATING" => {
+                // Relocating shards are listed as "source -> ip id target"
+                let (source, target) = match node.split_once(" -> ") {
+                    Some((source, rest)) => (source.to_string(), rest.rsplit(' ').next().unwrap_or_default().to_string()),
+                    None => (node.clone(), String::new()),
+                };
+                ClusterEvent {
+                    node: Some(source.clone()),
+                    from: Some(source.clone()),
+                    to: Some(target.clone()),
+                    ..ClusterEvent::new(timestamp, EventType::ShardRelocating, format!("Shard {} relocating from {} to {}", name, source, target))
+                }
+            }
+            "UNASSIGNED" => ClusterEvent::new(timestamp, EventType::ShardUnassigned, format!("Shard {} is unassigned", name)),
+            _ => continue,
+        };
+
+        events.push(ClusterEvent {
+            index: Some(shard.index.clone()),
+            shard: Some(shard.shard.clone()),
+            ..event
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elastic::source::Resource;
+    use serde::de::DeserializeOwned;
+    use serde_json::{json, Value};
+    use std::path::Path;
+
+    fn fixture(resource: Resource) -> Value {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/elasticsearch-8.13").join(resource.fixture_file());
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    fn parsed<T: DeserializeOwned>(value: Value) -> Arc<T> {
+        Arc::new(serde_json::from_value(value).unwrap())
+    }
+
+    fn time(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    /// The 8.13 fixtures as the first refreshed snapshot.
+    fn first() -> Snapshot {
+        Snapshot {
+            generation: 1,
+            fetched_at: Some(time(0)),
+            fetched: HashMap::new(),
+            cluster: parsed(fixture(Resource::Health)),
+            indices: parsed(fixture(Resource::Indices)),
+            recovery: parsed(fixture(Resource::Recovery)),
+            shards: parsed(fixture(Resource::Shards)),
+            nodes: parsed(fixture(Resource::Nodes)),
+            disk: Arc::default(),
+        }
+    }
+
+    /// The snapshot a minute after `previous`, everything carried over.
+    fn next(previous: &Snapshot) -> Snapshot {
+        Snapshot {
+            generation: previous.generation + 1,
+            fetched_at: previous.fetched_at.map(|at| at + chrono::TimeDelta::minutes(1)),
+            fetched: previous.fetched.clone(),
+            cluster: previous.cluster.clone(),
+            indices: previous.indices.clone(),
+            recovery: previous.recovery.clone(),
+            shards: previous.shards.clone(),
+            nodes: previous.nodes.clone(),
+            disk: previous.disk.clone(),
+        }
+    }
+
+    fn events(previous: &Snapshot, current: &Snapshot) -> Vec<(EventType, String)> {
+        diff(previous, current, &EventsConfig::default()).into_iter()
+            .map(|event| (event.event_type, event.message))
+            .collect()
+    }
+
+    #[test]
+    fn nodes_are_told_apart_by_id() {
+        let previous = first();
+        let mut nodes = fixture(Resource::Nodes);
+        let map = nodes["nodes"].as_object_mut().unwrap();
+        map.remove("p3Nq8RfVTt2xGmC5yLk0Ew");
+        // A replacement reusing the name of a node that is still there
+        let mut replacement = map["Q1wB3cTvSX6sYJ1mV4fLzg"].clone();
+        replacement["transport_address"] = json!("10.0.1.14:9300");
+        map.insert("Zr4kT0uWQ9iVn2bHc8dXyA".to_string(), replacement);
+        let current = Snapshot { nodes: parsed(nodes), ..next(&previous) };
+
+        assert_eq!(events(&previous, &current), [
+            (EventType::NodeJoined, "Node es8-node-1 joined the cluster".to_string()),
+            (EventType::NodeLeft, "Node es8-node-3 left the cluster".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn disk_thresholds_are_crossed_both_ways() {
+        let previous = first();
+        let mut nodes = fixture(Resource::Nodes);
+        // 92% used, up from 25.1%
+        nodes["nodes"]["hX0b2Yt7QmKz1LwP9sJd4A"]["fs"]["total"]["available_in_bytes"] = json!(8_444_248_064u64);
+        let current = Snapshot { nodes: parsed(nodes), ..next(&previous) };
+
+        assert_eq!(events(&previous, &current), [
+            (EventType::DiskThresholdCrossed, "Disk usage on es8-node-2 rose above 85% to 92.0%".to_string()),
+            (EventType::DiskThresholdCrossed, "Disk usage on es8-node-2 rose above 90% to 92.0%".to_string()),
+        ]);
+        assert_eq!(events(&current, &Snapshot { nodes: previous.nodes.clone(), ..next(&current) }), [
+            (EventType::DiskThresholdCrossed, "Disk usage on es8-node-2 fell below 85% to 25.1%".to_string()),
+            (EventType::DiskThresholdCrossed, "Disk usage on es8-node-2 fell below 90% to 25.1%".to_string()),
+        ]);
+
+        let event = &diff(&previous, &current, &EventsConfig::default())[0];
+        assert_eq!((event.from.as_deref(), event.to.as_deref()), (Some("25.1"), Some("92.0")));
+    }
+
+    #[test]
+    fn indices_created_and_deleted() {
+        let previous = first();
+        let mut indices = fixture(Resource::Indices);
+        let list = indices.as_array_mut().unwrap();
+        let mut template = list.remove(1);
+        for name in ["logs-2024.07.01", "logs-2024.06.15"] {
+            template["index"] = json!(name);
+            list.push(template.clone());
+        }
+        let current = Snapshot { indices: parsed(indices), ..next(&previous) };
+
+        assert_eq!(events(&previous, &current), [
+            (EventType::IndexCreated, "Index logs-2024.06.15 was created".to_string()),
+            (EventType::IndexCreated, "Index logs-2024.07.01 was created".to_string()),
+            (EventType::IndexDeleted, "Index logs-2024.05.01 was deleted".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn shards_started_relocating_and_unassigned() {
+        let previous = first();
+        let mut shards = fixture(Resource::Shards);
+        // Copies of the fixture: [0] 0p on node-1, [1] 0r relocating from
+        // node-2 to node-3, [3] 1r initializing on node-3, [4] 2p on node-3
+        shards[0]["state"] = json!("RELOCATING");
+        shards[0]["node"] = json!("es8-node-1 -> 10.0.1.12 hX0b2Yt7QmKz1LwP9sJd4A es8-node-2");
+        shards[3]["state"] = json!("STARTED");
+        shards[4]["state"] = json!("UNASSIGNED");
+        shards[4]["node"] = Value::Null;
+        let current = Snapshot { shards: parsed(shards), ..next(&previous) };
+
+        assert_eq!(events(&previous, &current), [
+            (EventType::ShardRelocating, "Shard logs-2024.06.01[0][p] relocating from es8-node-1 to es8-node-2".to_string()),
+            (EventType::ShardStarted, "Shard logs-2024.06.01[1][r] started on es8-node-3".to_string()),
+            (EventType::ShardUnassigned, "Shard logs-2024.06.01[2][p] is unassigned".to_string()),
+        ]);
+
+        let relocating = &diff(&previous, &current, &EventsConfig::default())[0];
+        assert_eq!((relocating.from.as_deref(), relocating.to.as_deref()), (Some("es8-node-1"), Some("es8-node-2")));
+        assert_eq!((relocating.index.as_deref(), relocating.shard.as_deref()), (Some("logs-2024.06.01"), Some("0")));
+    }
+
+    #[test]
+    fn recoveries_started_and_finished() {
+        let recovering = first();
+        let done = Snapshot { recovery: Arc::default(), ..next(&recovering) };
+
+        assert_eq!(events(&recovering, &done), [
+            (EventType::RecoveryFinished, "PEER recovery of a replica of logs-2024.06.01 on es8-node-3 ended".to_string()),
+        ]);
+        let restarted = Snapshot { recovery: recovering.recovery.clone(), ..next(&done) };
+        assert_eq!(events(&done, &restarted), [
+            (EventType::RecoveryStarted, "PEER recovery of a replica of logs-2024.06.01 on es8-node-3 started".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn carried_over_resources_are_skipped() {
+        let previous = first();
+        assert!(events(&Snapshot::empty(), &previous).is_empty(), "nothing against the empty snapshot");
+
+        let current = next(&previous);
+        assert!(events(&previous, &current).is_empty());
+
+        let mut health = fixture(Resource::Health);
+        health["status"] = json!("red");
+        let current = Snapshot { cluster: parsed(health), ..next(&previous) };
+        assert_eq!(events(&previous, &current), [
+            (EventType::ClusterStatusChanged, "Cluster status changed from yellow to red".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn since_returns_later_events_in_order() {
+        let mut log = EventLog::new(&EventsConfig::default());
+        let first = first();
+        let mut health = fixture(Resource::Health);
+        health["status"] = json!("red");
+        let second = Snapshot { cluster: parsed(health), recovery: Arc::default(), ..next(&first) };
+        let third = Snapshot { cluster: first.cluster.clone(), ..next(&second) };
+        log.record(&first, &second);
+        log.record(&second, &third);
+
+        let all = log.since(None, None);
+        assert_eq!(all.iter().map(|event| event.id).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(all.iter().map(|event| event.event_type).collect::<Vec<_>>(), [
+            EventType::ClusterStatusChanged,
+            EventType::RecoveryFinished,
+            EventType::ClusterStatusChanged,
+        ]);
+
+        let later = log.since(second.fetched_at, None);
+        assert_eq!(later.len(), 1);
+        assert_eq!(later[0].message, "Cluster status changed from red to yellow");
+        assert!(log.since(third.fetched_at, None).is_empty());
+
+        let recoveries = log.since(None, Some(&[EventType::RecoveryFinished]));
+        assert_eq!(recoveries.iter().map(|event| event.id).collect::<Vec<_>>(), [2]);
+        assert_eq!(log.after(1, None).0.len(), 2);
+        assert_eq!(log.after(1, None).1, 3);
+    }
+}
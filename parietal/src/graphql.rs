@@ -1,26 +1,127 @@
+use chrono::{DateTime, Utc};
+use futures::Stream;
 use juniper::{
-    graphql_object, EmptyMutation, EmptySubscription, FieldResult,
+    graphql_object, graphql_subscription, FieldError, FieldResult, GraphQLObject, IntoFieldError,
 };
 
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 
+use crate::allocation::{unassigned_causes, UnassignedCause};
 use crate::config;
-use crate::elastic::data::{ClusterInfo, IndexInfo, NodeOutput, Recovery, ShallowShard};
+use crate::disk::{disk_view, DiskView};
+use crate::data::{ConnectionStatus, Freshness, SharedWarehouse, Snapshot, SnapshotInfo, Warehouse, Watcher};
+use crate::elastic::data::{ClusterInfo, IndexInfo, IndexRecovery, NodeInfo, NodeOutput, Recovery, ShallowShard};
+use crate::events::{ClusterEvent, EventType};
+use crate::history::{HistoryInfo, SnapshotMetrics};
+use crate::elastic::replay::{ReplayControl, ReplayStatus};
+use crate::elastic::source::Resource;
 
 pub struct Context {
-    warehouse: Arc<RwLock<crate::data::Warehouse>>,
+    warehouses: Vec<(String, SharedWarehouse)>,
+    /// Snapshots already read by this request, by cluster name.
+    snapshots: Mutex<HashMap<String, Arc<Snapshot>>>,
 }
 impl Context {
     pub(crate) fn new() -> Self {
-        Self { 
-            warehouse: crate::data::WAREHOUSE.get().unwrap().clone(),
+        Self {
+            warehouses: crate::data::WAREHOUSES.get().unwrap().clone(),
+            snapshots: Mutex::new(HashMap::new()),
         }
     }
+
+    /// Resolves the `cluster` argument, defaulting to the first cluster.
+    fn find(&self, cluster: Option<String>) -> FieldResult<&(String, SharedWarehouse)> {
+        let found = match cluster {
+            Some(name) => self.warehouses.iter().find(|(n, _)| *n == name),
+            None => self.warehouses.first(),
+        };
+
+        found.ok_or_else(|| FieldError::from("Unknown cluster"))
+    }
+
+    fn warehouse(&self, cluster: Option<String>) -> FieldResult<&SharedWarehouse> {
+        self.find(cluster).map(|(_, warehouse)| warehouse)
+    }
+
+    /// The cluster's snapshot as first seen by this request, so all fields
+    /// of one query come from the same refresh.
+    async fn snapshot(&self, cluster: Option<String>) -> FieldResult<Arc<Snapshot>> {
+        let (name, warehouse) = self.find(cluster)?;
+
+        if let Some(snapshot) = self.snapshots.lock().unwrap().get(name) {
+            return Ok(snapshot.clone());
+        }
+
+        let snapshot = warehouse.read().await.snapshot().await;
+        Ok(self.snapshots.lock().unwrap().entry(name.clone()).or_insert(snapshot).clone())
+    }
+
+    /// The snapshot served at `at`, or the request's snapshot when no time
+    /// is given.
+    async fn snapshot_at(&self, cluster: Option<String>, at: Option<DateTime<Utc>>) -> FieldResult<Arc<Snapshot>> {
+        match at {
+            Some(at) => Ok(self.warehouse(cluster)?.read().await.snapshot_at(at).await?),
+            None => self.snapshot(cluster).await,
+        }
+    }
+
+    /// Freshness of the given resources (all when omitted) in the
+    /// request's snapshot.
+    async fn freshness(&self, resource: Option<Resource>, cluster: Option<String>) -> FieldResult<Vec<Freshness>> {
+        let snapshot = self.snapshot(cluster.clone()).await?;
+        let warehouse = self.warehouse(cluster)?.read().await;
+
+        let resources = match resource {
+            Some(resource) => vec![resource],
+            None => Resource::ALL.to_vec(),
+        };
+
+        let mut freshness = Vec::new();
+        for resource in resources {
+            freshness.push(warehouse.freshness(&snapshot, resource).await);
+        }
+
+        Ok(freshness)
+    }
 }
 
 impl juniper::Context for Context {}
 
+fn replay_control(warehouse: &Warehouse) -> FieldResult<&ReplayControl> {
+    warehouse.source.replay().ok_or_else(|| FieldError::from("Cluster is not replaying a recording"))
+}
+
+pub struct Cluster {
+    name: String,
+    warehouse: SharedWarehouse,
+}
+
+#[graphql_object(context = Context)]
+impl Cluster {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn health(&self, context: &Context) -> FieldResult<ClusterInfo> {
+        Ok(context.snapshot(Some(self.name.clone())).await?.cluster.as_ref().clone())
+    }
+
+    async fn snapshot(&self, context: &Context) -> FieldResult<SnapshotInfo> {
+        Ok(context.snapshot(Some(self.name.clone())).await?.info())
+    }
+
+    async fn status(&self) -> FieldResult<ConnectionStatus> {
+        Ok(self.warehouse.read().await.connection_status().await)
+    }
+
+    async fn freshness(&self, resource: Option<Resource>, context: &Context) -> FieldResult<Vec<Freshness>> {
+        context.freshness(resource, Some(self.name.clone())).await
+    }
+}
+
 pub struct Query;
 
 #[graphql_object]
@@ -34,61 +135,514 @@ impl Query {
         &crate::data::CONFIG.get().unwrap().ui
     }
 
+    fn clusters(
+        context: &Context,
+    ) -> Vec<Cluster> {
+        context.warehouses.iter()
+            .map(|(name, warehouse)| Cluster { name: name.clone(), warehouse: warehouse.clone() })
+            .collect()
+    }
+
     async fn health(
+        cluster: Option<String>,
+        at: Option<DateTime<Utc>>,
         context: &Context,
     ) -> FieldResult<ClusterInfo> {
-        Ok(context.warehouse.read().await.cluster.read().await.clone())
+        Ok(context.snapshot_at(cluster, at).await?.cluster.as_ref().clone())
+    }
+
+    async fn status(
+        cluster: Option<String>,
+        context: &Context,
+    ) -> FieldResult<ConnectionStatus> {
+        Ok(context.warehouse(cluster)?.read().await.connection_status().await)
     }
 
     async fn indices(
+        cluster: Option<String>,
+        at: Option<DateTime<Utc>>,
         context: &Context,
     ) -> FieldResult<Vec<IndexInfo>> {
-        Ok(context.warehouse.read().await.indices.read().await.clone())
+        Ok(context.snapshot_at(cluster, at).await?.indices.as_ref().clone())
     }
 
     async fn recovery(
+        cluster: Option<String>,
+        at: Option<DateTime<Utc>>,
         context: &Context,
     ) -> FieldResult<Recovery> {
-        Ok(context.warehouse.read().await.recovery.read().await.clone())
+        Ok(context.snapshot_at(cluster, at).await?.recovery.as_ref().clone())
     }
 
     async fn relocating(
+        cluster: Option<String>,
+        at: Option<DateTime<Utc>>,
         context: &Context,
     ) -> FieldResult<Vec<ShallowShard>> {
-        let shards = context.warehouse.read().await.shards.read().await.clone();
-        let unassigned = shards.into_iter().filter(|s| s.state != "STARTED").collect();
+        let snapshot = context.snapshot_at(cluster, at).await?;
+        let unassigned = snapshot.shards.iter().filter(|s| s.state != "STARTED").cloned().collect();
         Ok(unassigned)
     }
 
+    /// Disk usage per node against the disk watermarks.
+    async fn disk(
+        cluster: Option<String>,
+        at: Option<DateTime<Utc>>,
+        context: &Context,
+    ) -> FieldResult<DiskView> {
+        let snapshot = context.snapshot_at(cluster, at).await?;
+        Ok(disk_view(&snapshot))
+    }
+
+    /// Unassigned shards grouped by the root cause in their allocation
+    /// explanation.
+    async fn unassigned_causes(
+        cluster: Option<String>,
+        at: Option<DateTime<Utc>>,
+        context: &Context,
+    ) -> FieldResult<Vec<UnassignedCause>> {
+        let snapshot = context.snapshot_at(cluster, at).await?;
+        Ok(unassigned_causes(&snapshot.shards))
+    }
+
     async fn nodes(
+        cluster: Option<String>,
+        at: Option<DateTime<Utc>>,
         context: &Context,
     ) -> FieldResult<NodeOutput> {
-        Ok(context.warehouse.read().await.nodes.read().await.clone())
+        Ok(context.snapshot_at(cluster, at).await?.nodes.as_ref().clone())
     }
 
     async fn shards(
         index: Option<String>,
+        cluster: Option<String>,
+        at: Option<DateTime<Utc>>,
         context: &Context,
     ) -> FieldResult<Vec<ShallowShard>> {
-        let collected: Vec<ShallowShard>;
+        let snapshot = context.snapshot_at(cluster, at).await?;
+
+        let collected = match index {
+            Some(index) => snapshot.shards.iter().filter(|s| s.index == index).cloned().collect(),
+            None => snapshot.shards.to_vec(),
+        };
+
+        Ok(collected)
+    }
+
+    async fn snapshot(
+        cluster: Option<String>,
+        at: Option<DateTime<Utc>>,
+        context: &Context,
+    ) -> FieldResult<SnapshotInfo> {
+        Ok(context.snapshot_at(cluster, at).await?.info())
+    }
+
+    /// How old the served data is, per resource.
+    async fn freshness(
+        resource: Option<Resource>,
+        cluster: Option<String>,
+        context: &Context,
+    ) -> FieldResult<Vec<Freshness>> {
+        context.freshness(resource, cluster).await
+    }
+
+    /// Time range available to the `at` arguments.
+    async fn history(
+        cluster: Option<String>,
+        context: &Context,
+    ) -> FieldResult<HistoryInfo> {
+        Ok(context.warehouse(cluster)?.read().await.history_info().await)
+    }
+
+    /// Changes seen in the cluster after `since`, oldest first.
+    async fn events(
+        since: Option<DateTime<Utc>>,
+        types: Option<Vec<EventType>>,
+        cluster: Option<String>,
+        context: &Context,
+    ) -> FieldResult<Vec<ClusterEvent>> {
+        Ok(context.warehouse(cluster)?.read().await.events(since, types.as_deref()).await)
+    }
+
+    /// Metrics derived from past snapshots, the last hour by default.
+    async fn metrics(
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+        cluster: Option<String>,
+        context: &Context,
+    ) -> FieldResult<Vec<SnapshotMetrics>> {
+        let to = to.unwrap_or_else(Utc::now);
+        let from = from.unwrap_or(to - chrono::TimeDelta::hours(1));
+        Ok(context.warehouse(cluster)?.read().await.metrics(from, to).await?)
+    }
+
+    async fn replay(
+        cluster: Option<String>,
+        context: &Context,
+    ) -> FieldResult<Option<ReplayStatus>> {
+        Ok(context.warehouse(cluster)?.read().await.source.replay().map(ReplayControl::status))
+    }
+}
+
+pub struct Mutation;
+
+#[graphql_object(context = Context)]
+impl Mutation {
+    /// Refreshes the given resources (all when omitted) right away and
+    /// returns the snapshot that includes them. Concurrent requests share
+    /// one refresh.
+    async fn refresh(
+        resources: Option<Vec<Resource>>,
+        cluster: Option<String>,
+        context: &Context,
+    ) -> FieldResult<SnapshotInfo> {
+        let resources = resources.unwrap_or_else(|| Resource::ALL.to_vec());
+        let warehouse = context.warehouse(cluster)?.read().await;
+        warehouse.request_refresh(&resources).await.map_err(IntoFieldError::into_field_error)
+    }
+
+    async fn replay_pause(
+        cluster: Option<String>,
+        context: &Context,
+    ) -> FieldResult<ReplayStatus> {
+        let warehouse = context.warehouse(cluster)?.read().await;
+        let control = replay_control(&warehouse)?;
+        control.pause();
+        Ok(control.status())
+    }
+
+    async fn replay_resume(
+        cluster: Option<String>,
+        context: &Context,
+    ) -> FieldResult<ReplayStatus> {
+        let warehouse = context.warehouse(cluster)?.read().await;
+        let control = replay_control(&warehouse)?;
+        control.resume();
+        Ok(control.status())
+    }
+
+    /// Jumps to a snapshot by position, or to a point in recorded time.
+    /// The data follows on the next refresh.
+    async fn replay_seek(
+        position: Option<i32>,
+        at: Option<DateTime<Utc>>,
+        cluster: Option<String>,
+        context: &Context,
+    ) -> FieldResult<ReplayStatus> {
+        let warehouse = context.warehouse(cluster)?.read().await;
+        let control = replay_control(&warehouse)?;
+
+        match (position, at) {
+            (Some(position), None) => {
+                let position = usize::try_from(position).map_err(|_| "Position must not be negative")?;
+                control.seek_position(position)?;
+            }
+            (None, Some(at)) => control.seek_time(at),
+            _ => return Err(FieldError::from("Exactly one of position and at is required")),
+        }
+
+        Ok(control.status())
+    }
+
+    async fn replay_speed(
+        speed: f64,
+        cluster: Option<String>,
+        context: &Context,
+    ) -> FieldResult<ReplayStatus> {
+        let warehouse = context.warehouse(cluster)?.read().await;
+        let control = replay_control(&warehouse)?;
+        control.set_speed(speed)?;
+        Ok(control.status())
+    }
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "Relocating shards that changed since the previous update")]
+pub struct RelocatingUpdate {
+    pub snapshot: SnapshotInfo,
+    /// `changed` holds all shards, as in the first update
+    pub full: bool,
+    pub changed: Vec<ShallowShard>,
+    pub removed: Vec<ShallowShard>,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "Index recoveries that changed since the previous update")]
+pub struct RecoveryUpdate {
+    pub snapshot: SnapshotInfo,
+    /// `changed` holds all indices, as in the first update
+    pub full: bool,
+    pub changed: Vec<IndexRecovery>,
+    /// Indices no longer recovering
+    pub removed: Vec<String>,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "Nodes that changed since the previous update")]
+pub struct NodesUpdate {
+    pub snapshot: SnapshotInfo,
+    /// `changed` holds all nodes, as in the first update
+    pub full: bool,
+    pub changed: Vec<NodeInfo>,
+    /// IDs of nodes that left
+    pub removed: Vec<String>,
+}
+
+type Updates<T> = Pin<Box<dyn Stream<Item = T> + Send>>;
+
+/// Calls `update` with every snapshot the cluster publishes, starting with
+/// the current one, and sends what it returns. `update` gets the previous
+/// snapshot, none for the first.
+fn updates<T: Send + 'static>(
+    warehouse: &Warehouse,
+    update: impl Fn(Option<&Snapshot>, &Snapshot) -> Option<T> + Send + 'static,
+) -> Updates<T> {
+    let mut receiver = warehouse.subscribe();
+    receiver.mark_changed();
 
-        match index {
-            Some(index) => {
-                let shards = context.warehouse.read().await.shards.read().await.clone();
-                collected = shards.into_iter().filter(|s| s.index == index).collect();
+    let state = (receiver, None::<Arc<Snapshot>>, update, Watcher::start());
+    Box::pin(futures::stream::unfold(state, |(mut receiver, mut previous, update, watcher)| async move {
+        loop {
+            receiver.changed().await.ok()?;
+            let current = receiver.borrow_and_update().clone();
+            if current.generation == 0 {
+                continue;
             }
-            None => {
-                let shards = context.warehouse.read().await.shards.read().await.clone();
-                collected = shards.into_iter().collect();
+
+            let item = update(previous.as_deref(), &current);
+            previous = Some(current);
+            if let Some(item) = item {
+                return Some((item, (receiver, previous, update, watcher)));
             }
         }
+    }))
+}
 
-        Ok(collected)
+fn relocating(shards: &[ShallowShard]) -> impl Iterator<Item = &ShallowShard> {
+    shards.iter().filter(|shard| shard.state != "STARTED")
+}
+
+/// Items only in `after` and only in `before`, counting duplicates, each in
+/// the order of its side.
+fn multiset_diff<'a, T: Eq + Hash + Clone + 'a>(
+    before: impl Iterator<Item = &'a T>,
+    after: impl Iterator<Item = &'a T>,
+) -> (Vec<T>, Vec<T>) {
+    let before: Vec<&T> = before.collect();
+    let mut remaining: HashMap<&T, usize> = HashMap::new();
+    for item in &before {
+        *remaining.entry(item).or_default() += 1;
+    }
+
+    let mut added = Vec::new();
+    for item in after {
+        match remaining.get_mut(item) {
+            Some(count) if *count > 0 => *count -= 1,
+            _ => added.push(item.clone()),
+        }
+    }
+
+    let removed = before.into_iter()
+        .filter(|item| match remaining.get_mut(item) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                true
+            }
+            _ => false,
+        })
+        .cloned()
+        .collect();
+    (added, removed)
+}
+
+/// Items that are new or differ from every one with the same key, and the
+/// keys that are gone, in the order of `before`. Keys may repeat.
+fn keyed_diff<T: PartialEq + Clone>(before: &[T], after: &[T], key: impl Fn(&T) -> &str) -> (Vec<T>, Vec<String>) {
+    let mut previous: HashMap<&str, Vec<&T>> = HashMap::new();
+    for item in before {
+        previous.entry(key(item)).or_default().push(item);
+    }
+    let current: HashSet<&str> = after.iter().map(&key).collect();
+
+    let changed = after.iter()
+        .filter(|item| !previous.get(key(item)).is_some_and(|same_key| same_key.contains(item)))
+        .cloned()
+        .collect();
+    let mut seen = HashSet::new();
+    let removed = before.iter()
+        .map(&key)
+        .filter(|key| !current.contains(key) && seen.insert(*key))
+        .map(str::to_string)
+        .collect();
+    (changed, removed)
+}
+
+pub struct Subscription;
+
+#[graphql_subscription(context = Context)]
+impl Subscription {
+    /// Cluster health, sent when it changes.
+    async fn health(
+        cluster: Option<String>,
+        context: &Context,
+    ) -> FieldResult<Updates<ClusterInfo>> {
+        let warehouse = context.warehouse(cluster)?.read().await;
+
+        Ok(updates(&warehouse, |previous, current| match previous {
+            Some(previous) if previous.cluster == current.cluster => None,
+            _ => Some(current.cluster.as_ref().clone()),
+        }))
+    }
+
+    /// Shards that aren't started, sent as changes.
+    async fn relocating(
+        cluster: Option<String>,
+        context: &Context,
+    ) -> FieldResult<Updates<RelocatingUpdate>> {
+        let warehouse = context.warehouse(cluster)?.read().await;
+
+        Ok(updates(&warehouse, |previous, current| {
+            let before = previous.map(|previous| previous.shards.clone()).unwrap_or_default();
+            if Arc::ptr_eq(&before, &current.shards) {
+                return None;
+            }
+
+            let (changed, removed) = multiset_diff(relocating(&before), relocating(&current.shards));
+            let full = previous.is_none();
+            (full || !changed.is_empty() || !removed.is_empty()).then(|| RelocatingUpdate {
+                snapshot: current.info(),
+                full,
+                changed,
+                removed,
+            })
+        }))
+    }
+
+    /// Ongoing recoveries, sent as changes.
+    async fn recovery(
+        cluster: Option<String>,
+        context: &Context,
+    ) -> FieldResult<Updates<RecoveryUpdate>> {
+        let warehouse = context.warehouse(cluster)?.read().await;
+
+        Ok(updates(&warehouse, |previous, current| {
+            let before = previous.map(|previous| previous.recovery.clone()).unwrap_or_default();
+            if Arc::ptr_eq(&before, &current.recovery) {
+                return None;
+            }
+
+            let (changed, removed) = keyed_diff(before.indices(), current.recovery.indices(), IndexRecovery::id);
+            let full = previous.is_none();
+            (full || !changed.is_empty() || !removed.is_empty()).then(|| RecoveryUpdate {
+                snapshot: current.info(),
+                full,
+                changed,
+                removed,
+            })
+        }))
+    }
+
+    /// Node stats, sent as changes.
+    async fn nodes(
+        cluster: Option<String>,
+        context: &Context,
+    ) -> FieldResult<Updates<NodesUpdate>> {
+        let warehouse = context.warehouse(cluster)?.read().await;
+
+        Ok(updates(&warehouse, |previous, current| {
+            let before = previous.map(|previous| previous.nodes.clone()).unwrap_or_default();
+            if Arc::ptr_eq(&before, &current.nodes) {
+                return None;
+            }
+
+            let (changed, removed) = keyed_diff(&before.nodes, &current.nodes.nodes, NodeInfo::id);
+            let full = previous.is_none();
+            (full || !changed.is_empty() || !removed.is_empty()).then(|| NodesUpdate {
+                snapshot: current.info(),
+                full,
+                changed,
+                removed,
+            })
+        }))
+    }
+
+    /// Change events as they happen, starting with the ones after `since`
+    /// when given.
+    async fn events(
+        since: Option<DateTime<Utc>>,
+        types: Option<Vec<EventType>>,
+        cluster: Option<String>,
+        context: &Context,
+    ) -> FieldResult<Updates<ClusterEvent>> {
+        let shared = context.warehouse(cluster)?.clone();
+        let (receiver, last_id, backlog) = {
+            let warehouse = shared.read().await;
+            let receiver = warehouse.subscribe();
+            let last_id = warehouse.last_event_id().await;
+            let backlog = match since {
+                Some(since) => warehouse.events(Some(since), types.as_deref()).await,
+                None => Vec::new(),
+            };
+            (receiver, last_id, backlog.into_iter().filter(|event| event.id <= last_id).collect::<VecDeque<_>>())
+        };
+
+        let state = (receiver, shared, types, last_id, backlog, Watcher::start());
+        Ok(Box::pin(futures::stream::unfold(state, |(mut receiver, shared, types, mut last_id, mut pending, watcher)| async move {
+            loop {
+                if let Some(event) = pending.pop_front() {
+                    return Some((event, (receiver, shared, types, last_id, pending, watcher)));
+                }
+
+                // Events are recorded before the snapshot is published
+                receiver.changed().await.ok()?;
+                let (events, latest) = shared.read().await.events_after(last_id, types.as_deref()).await;
+                last_id = latest;
+                pending.extend(events);
+            }
+        })))
     }
 }
 
-type Schema = juniper::RootNode<'static, Query, EmptyMutation<Context>, EmptySubscription<Context>>;
+type Schema = juniper::RootNode<'static, Query, Mutation, Subscription>;
 
 pub fn schema() -> Schema {
-    Schema::new(Query, EmptyMutation::new(), EmptySubscription::new())
+    Schema::new(Query, Mutation, Subscription)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multiset_diff_counts_duplicates() {
+        let before = ["a", "b", "b", "c"];
+        let after = ["b", "d", "a", "d"];
+
+        let (added, removed) = multiset_diff(before.iter(), after.iter());
+        assert_eq!(added, ["d", "d"]);
+        assert_eq!(removed, ["b", "c"]);
+
+        let (added, removed) = multiset_diff(before.iter(), before.iter());
+        assert!(added.is_empty() && removed.is_empty());
+    }
+
+    #[test]
+    fn keyed_diff_reports_added_changed_and_removed() {
+        let before = [("a", 1), ("b", 2), ("c", 3)];
+        let after = [("a", 1), ("b", 5), ("d", 4)];
+
+        let (changed, removed) = keyed_diff(&before, &after, |item| item.0);
+        assert_eq!(changed, [("b", 5), ("d", 4)]);
+        assert_eq!(removed, ["c"]);
+    }
+
+    #[test]
+    fn keyed_diff_tolerates_duplicate_keys() {
+        let before = [("a", 1), ("a", 2), ("b", 3), ("b", 4)];
+        let after = [("a", 2), ("a", 1), ("a", 7)];
+
+        let (changed, removed) = keyed_diff(&before, &after, |item| item.0);
+        assert_eq!(changed, [("a", 7)]);
+        assert_eq!(removed, ["b"]);
+    }
 }
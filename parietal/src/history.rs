@@ -0,0 +1,235 @@
+use crate::config::HistoryConfig;
+use crate::data::Snapshot;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use juniper::GraphQLObject;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+#[derive(GraphQLObject, Serialize, Debug, Clone)]
+#[graphql(description = "Time range that can be queried with `at`")]
+pub struct HistoryInfo {
+    pub snapshots: i32,
+    pub oldest: Option<DateTime<Utc>>,
+    pub newest: Option<DateTime<Utc>>,
+    pub retention_secs: i32,
+    /// Oldest snapshot in `data_dir`, when history is persisted
+    pub persisted_oldest: Option<DateTime<Utc>>,
+}
+
+#[derive(GraphQLObject, Serialize, Deserialize, Debug, Clone)]
+#[graphql(description = "Figures derived from one snapshot, kept longer than the snapshots")]
+pub struct SnapshotMetrics {
+    pub timestamp: DateTime<Utc>,
+    pub status: String,
+    pub nodes: i32,
+    pub indices: i32,
+    pub shards: i32,
+    pub relocating_shards: i32,
+    pub initializing_shards: i32,
+    pub unassigned_shards: i32,
+    pub recovering_shards: i32,
+}
+
+impl SnapshotMetrics {
+    pub fn of(snapshot: &Snapshot) -> Self {
+        let count = |value: usize| i32::try_from(value).unwrap_or(i32::MAX);
+        let in_state = |state: &str| count(snapshot.shards.iter().filter(|shard| shard.state == state).count());
+
+        SnapshotMetrics {
+            timestamp: snapshot.fetched_at.unwrap_or_default(),
+            status: snapshot.cluster.status().to_string(),
+            nodes: count(snapshot.nodes.nodes.len()),
+            indices: count(snapshot.indices.len()),
+            shards: count(snapshot.shards.len()),
+            relocating_shards: in_state("RELOCATING"),
+            initializing_shards: in_state("INITIALIZING"),
+            unassigned_shards: in_state("UNASSIGNED"),
+            recovering_shards: count(snapshot.recovery.shard_count()),
+        }
+    }
+}
+
+/// Converts a config value, saturating instead of overflowing.
+pub fn seconds(secs: u64) -> TimeDelta {
+    TimeDelta::try_seconds(i64::try_from(secs).unwrap_or(i64::MAX)).unwrap_or_else(TimeDelta::max_value)
+}
+
+/// Past snapshots of one cluster, oldest first, at most one per
+/// `interval_secs`.
+#[derive(Debug)]
+pub struct History {
+    retention: TimeDelta,
+    interval: TimeDelta,
+    snapshots: VecDeque<Arc<Snapshot>>,
+}
+
+impl History {
+    pub fn new(config: &HistoryConfig) -> Self {
+        History {
+            retention: seconds(config.retention_secs),
+            interval: seconds(config.interval_secs),
+            snapshots: VecDeque::new(),
+        }
+    }
+
+    /// Keeps `snapshot` if it is at least `interval` newer than the last
+    /// kept one, and forgets snapshots older than the retention.
+    pub fn record(&mut self, snapshot: &Arc<Snapshot>) {
+        let Some(fetched_at) = snapshot.fetched_at else {
+            return;
+        };
+        if self.retention.is_zero() {
+            return;
+        }
+
+        let due = match self.snapshots.back().and_then(|last| last.fetched_at) {
+            Some(last) => fetched_at - last >= self.interval,
+            None => true,
+        };
+        if due {
+            self.snapshots.push_back(snapshot.clone());
+        }
+
+        let cutoff = fetched_at.checked_sub_signed(self.retention).unwrap_or(DateTime::<Utc>::MIN_UTC);
+        while self.snapshots.front().is_some_and(|oldest| oldest.fetched_at.is_some_and(|at| at < cutoff)) {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// The last kept snapshot taken at or before `at`.
+    pub fn at(&self, at: DateTime<Utc>) -> Option<Arc<Snapshot>> {
+        let index = self.snapshots.partition_point(|snapshot| snapshot.fetched_at.is_some_and(|ts| ts <= at));
+        index.checked_sub(1).map(|index| self.snapshots[index].clone())
+    }
+
+    /// Metrics of the kept snapshots taken between `from` and `to`.
+    pub fn metrics(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<SnapshotMetrics> {
+        self.snapshots.iter()
+            .filter(|snapshot| snapshot.fetched_at.is_some_and(|at| from <= at && at <= to))
+            .map(|snapshot| SnapshotMetrics::of(snapshot))
+            .collect()
+    }
+
+    pub fn info(&self) -> HistoryInfo {
+        HistoryInfo {
+            snapshots: i32::try_from(self.snapshots.len()).unwrap_or(i32::MAX),
+            oldest: self.snapshots.front().and_then(|snapshot| snapshot.fetched_at),
+            newest: self.snapshots.back().and_then(|snapshot| snapshot.fetched_at),
+            retention_secs: i32::try_from(self.retention.num_seconds()).unwrap_or(i32::MAX),
+            persisted_oldest: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elastic::data::ShallowShard;
+
+    fn time(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    /// The snapshot after `previous`, taken at `secs` with `shards` fetched
+    /// and everything else carried over.
+    fn next(previous: &Snapshot, secs: i64, shards: usize) -> Arc<Snapshot> {
+        let shard = |shard: usize| ShallowShard {
+            index: "logs".to_string(),
+            shard: shard.to_string(),
+            prirep: "p".to_string(),
+            state: "STARTED".to_string(),
+            docs: None,
+            store: None,
+            ip: None,
+            node: Some("node-1".to_string()),
+            allocation_explanation: None,
+        };
+
+        Arc::new(Snapshot {
+            generation: previous.generation + 1,
+            fetched_at: Some(time(secs)),
+            fetched: previous.fetched.clone(),
+            cluster: previous.cluster.clone(),
+            indices: previous.indices.clone(),
+            recovery: previous.recovery.clone(),
+            shards: Arc::new((0..shards).map(shard).collect()),
+            nodes: previous.nodes.clone(),
+            disk: previous.disk.clone(),
+        })
+    }
+
+    fn history(retention_secs: u64, interval_secs: u64) -> History {
+        History::new(&HistoryConfig { retention_secs, interval_secs, ..HistoryConfig::default() })
+    }
+
+    #[test]
+    fn keeps_one_snapshot_per_interval() {
+        let mut history = history(3_600, 60);
+        let mut previous = Arc::new(Snapshot::empty());
+        history.record(&previous);
+        assert_eq!(history.info().snapshots, 0, "never fetched");
+
+        for secs in [0, 30, 60, 100, 120] {
+            previous = next(&previous, secs, 1);
+            history.record(&previous);
+        }
+
+        let info = history.info();
+        assert_eq!(info.snapshots, 3);
+        assert_eq!((info.oldest, info.newest), (Some(time(0)), Some(time(120))));
+    }
+
+    #[test]
+    fn finds_the_snapshot_in_effect_at_a_time() {
+        let mut history = history(3_600, 60);
+        let first = next(&Snapshot::empty(), 0, 1);
+        let second = next(&first, 60, 2);
+        history.record(&first);
+        history.record(&second);
+
+        assert!(history.at(time(-1)).is_none());
+        assert!(Arc::ptr_eq(&history.at(time(0)).unwrap(), &first));
+        assert!(Arc::ptr_eq(&history.at(time(59)).unwrap(), &first));
+        assert!(Arc::ptr_eq(&history.at(time(60)).unwrap(), &second));
+        assert!(Arc::ptr_eq(&history.at(time(10_000)).unwrap(), &second));
+
+        let metrics = history.metrics(time(30), time(90));
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].shards, 2);
+    }
+
+    #[test]
+    fn forgets_snapshots_past_the_retention() {
+        let mut kept = history(300, 60);
+        let mut previous = Arc::new(Snapshot::empty());
+        for secs in (0..=600).step_by(60) {
+            previous = next(&previous, secs, 1);
+            kept.record(&previous);
+        }
+
+        let info = kept.info();
+        assert_eq!(info.snapshots, 6);
+        assert_eq!(info.oldest, Some(time(300)));
+        assert!(kept.at(time(299)).is_none());
+
+        let mut disabled = history(0, 60);
+        disabled.record(&previous);
+        assert_eq!(disabled.info().snapshots, 0);
+    }
+
+    #[test]
+    fn kept_snapshots_share_unchanged_resources() {
+        let mut history = history(3_600, 60);
+        let first = next(&Snapshot::empty(), 0, 1);
+        history.record(&first);
+        history.record(&next(&first, 60, 2));
+
+        let (first, second) = (history.at(time(0)).unwrap(), history.at(time(60)).unwrap());
+        assert!(Arc::ptr_eq(&first.nodes, &second.nodes));
+        assert!(Arc::ptr_eq(&first.indices, &second.indices));
+        // A changed resource is held in full by each snapshot
+        assert_eq!((first.shards.len(), second.shards.len()), (1, 2));
+    }
+}
@@ -4,11 +4,16 @@ use log::debug;
 use warp::Filter;
 #[cfg(not(debug_assertions))]
 use rust_embed::RustEmbed;
-use data::{WAREHOUSE, CONFIG};
+use data::{WAREHOUSES, CONFIG};
 
 
 mod elastic;
 mod data;
+mod allocation;
+mod disk;
+mod events;
+mod history;
+mod store;
 mod graphql;
 mod rest;
 mod config;
@@ -45,35 +50,65 @@ async fn main() {
     let graphql =
     warp::post()
         .and(warp::path("graphql"))
-        .and(juniper_warp::make_graphql_filter(schema.clone(), warp::any().map(|| Context::new()))
+        .and(juniper_warp::make_graphql_filter(schema.clone(), warp::any().map(Context::new))
+        )
+        .or(warp::path("graphql")
+            .and(juniper_warp::subscriptions::make_ws_filter(schema.clone(), |_: juniper::Variables| async {
+                Ok::<_, std::convert::Infallible>(juniper_graphql_ws::ConnectionConfig::new(Context::new()))
+            }))
         )
         .or(warp::get()
             .and(warp::path("graphql"))
-            .and(juniper_warp::make_graphql_filter(schema.clone(), warp::any().map(|| Context::new()))
+            .and(juniper_warp::make_graphql_filter(schema.clone(), warp::any().map(Context::new))
         )
         )
         .or(warp::get()
             .and(warp::path("playground"))
-            .and(juniper_warp::playground_filter("/graphql", None))
+            .and(juniper_warp::playground_filter("/graphql", Some("/graphql")))
         )
         .or(warp::get()
             .and(warp::path("graphiql"))
-            .and(juniper_warp::graphiql_filter("/graphql", None))
+            .and(juniper_warp::graphiql_filter("/graphql", Some("/graphql")))
         );
 
 
-    debug!("Building initial ES state...");
-
-    let warehouse = Warehouse::new(&config.elastic.url).await;
+    if config.mock.enabled {
+        let server = elastic::mock::MockServer::new(&config.mock)
+            .and_then(|server| server.spawn());
+        if let Err(e) = server {
+            log::error!("Failed to start the mock Elasticsearch server: {:#}", e);
+            std::process::exit(1);
+        }
+    }
 
-    debug!("Starting refresh loop...");
-    let warehouse = std::sync::Arc::new(tokio::sync::RwLock::new(warehouse));
-    Warehouse::start_refresh(warehouse.clone()).await;
-    WAREHOUSE.set(warehouse).unwrap();
+    // Poll at full speed until the first clients had a chance to connect
+    data::client_seen();
+
+    let mut warehouses = Vec::new();
+    for cluster in config.clusters() {
+        debug!("Setting up warehouse for {}...", cluster.name);
+
+        let warehouse = match Warehouse::new(&cluster.name, &cluster.elastic) {
+            Ok(warehouse) => warehouse,
+            Err(e) => {
+                log::error!("Failed to set up Elasticsearch connection for {}: {:#}", cluster.name, e);
+                std::process::exit(1);
+            }
+        };
+
+        let warehouse = std::sync::Arc::new(tokio::sync::RwLock::new(warehouse));
+        Warehouse::start_refresh(cluster.name.clone(), warehouse.clone()).await;
+        Warehouse::start_explainer(cluster.name.clone(), warehouse.clone()).await;
+        warehouses.push((cluster.name, warehouse));
+    }
+    WAREHOUSES.set(warehouses).unwrap();
 
 
     debug!("Starting server...");
 
+    // Every API request counts as a client watching, see `PollingConfig`
+    let api = warp::any().map(data::client_seen).untuple_one().and(routes.or(graphql));
+
     let cors = warp::cors()
         .allow_any_origin()
         .allow_methods(vec!["GET", "POST", "DELETE", "PUT", "OPTIONS"])
@@ -82,7 +117,7 @@ async fn main() {
 
     #[cfg(not(debug_assertions))]
     {
-        warp::serve(routes.or(graphql).or(
+        warp::serve(api.or(
             warp_embed::embed(&App)
         ).with(cors))
             .run(([0, 0, 0, 0], config.port))
@@ -91,7 +126,7 @@ async fn main() {
 
     #[cfg(debug_assertions)]
     {
-        warp::serve(routes.or(graphql).with(cors))
+        warp::serve(api.with(cors))
             .run(([0, 0, 0, 0], config.port))
             .await;
     }
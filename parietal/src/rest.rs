@@ -1,54 +1,673 @@
-use crate::data::WAREHOUSE;
+use crate::allocation::unassigned_causes;
+use crate::disk::disk_view;
+use crate::data::{self, SharedWarehouse, Snapshot, Warehouse, Watcher, WAREHOUSES};
+use crate::elastic::replay::ReplayControl;
+use crate::events::EventType;
+use crate::elastic::source::Resource;
+use chrono::{DateTime, Utc};
 use log::debug;
-use warp::Filter;
- 
+use percent_encoding::percent_decode_str;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::Arc;
+use warp::http::{HeaderValue, StatusCode};
+use warp::{Filter, Reply};
+
 async fn hello() -> Result<impl warp::Reply, warp::Rejection> {
     Ok("Hello, World!")
 }
 
-async fn elastic_health() -> Result<impl warp::Reply, warp::Rejection> {
-    let warehouse = WAREHOUSE.get().unwrap().read().await;
-    let health = warehouse.cluster.read().await;
-    debug!("{:?}", &*health);
-    let health = serde_json::to_value(&*health).unwrap();
-    Ok(warp::reply::json(&health))
+fn find_warehouse(cluster: Option<String>) -> Result<SharedWarehouse, warp::Rejection> {
+    data::warehouse(cluster.as_deref()).ok_or_else(warp::reject::not_found)
+}
+
+async fn clusters() -> Result<impl warp::Reply, warp::Rejection> {
+    let names = WAREHOUSES.get().unwrap().iter().map(|(name, _)| name).collect::<Vec<_>>();
+    Ok(warp::reply::json(&names))
+}
+
+/// Adds the freshness of `resource` as `X-Cortex-*` headers, see
+/// `/elastic/freshness` for the full error. A resource that has never been
+/// fetched is answered with that error instead, as a 502.
+async fn with_freshness(reply: impl warp::Reply, warehouse: &Warehouse, snapshot: &Snapshot, resource: Resource) -> warp::reply::Response {
+    let freshness = warehouse.freshness(snapshot, resource).await;
+    let mut response = match (freshness.fetched_at, &freshness.last_error) {
+        (None, Some(error)) => warp::reply::with_status(warp::reply::json(error), StatusCode::BAD_GATEWAY).into_response(),
+        _ => reply.into_response(),
+    };
+    let headers = response.headers_mut();
+
+    if let Some(fetched_at) = freshness.fetched_at {
+        headers.insert("x-cortex-fetched-at", HeaderValue::from_str(&fetched_at.to_rfc3339()).unwrap());
+    }
+    if let Some(age) = freshness.age_seconds {
+        headers.insert("x-cortex-age-seconds", HeaderValue::from_str(&age.to_string()).unwrap());
+    }
+    if let Some(kind) = freshness.last_error.and_then(|error| serde_json::to_value(error.kind).ok()) {
+        headers.insert("x-cortex-last-error", HeaderValue::from_str(kind.as_str().unwrap_or_default()).unwrap());
+    }
+    headers.insert("x-cortex-stale", HeaderValue::from_static(if freshness.is_stale { "true" } else { "false" }));
+
+    response
+}
+
+async fn elastic_health(cluster: Option<String>) -> Result<impl warp::Reply, warp::Rejection> {
+    let warehouse = find_warehouse(cluster)?;
+    let warehouse = warehouse.read().await;
+    let snapshot = warehouse.snapshot().await;
+    let health = &*snapshot.cluster;
+    debug!("{:?}", health);
+    let health = serde_json::to_value(health).unwrap();
+    Ok(with_freshness(warp::reply::json(&health), &warehouse, &snapshot, Resource::Health).await)
 }
 
-async fn elastic_indices() -> Result<impl warp::Reply, warp::Rejection> {
-    let warehouse = WAREHOUSE.get().unwrap().read().await;
-    let indices = warehouse.indices.read().await;
-    debug!("{:?}", &*indices);
-    let indices = serde_json::to_value(&*indices).unwrap();
-    Ok(warp::reply::json(&indices))
+async fn elastic_indices(cluster: Option<String>) -> Result<impl warp::Reply, warp::Rejection> {
+    let warehouse = find_warehouse(cluster)?;
+    let warehouse = warehouse.read().await;
+    let snapshot = warehouse.snapshot().await;
+    let indices = &*snapshot.indices;
+    debug!("{:?}", indices);
+    let indices = serde_json::to_value(indices).unwrap();
+    Ok(with_freshness(warp::reply::json(&indices), &warehouse, &snapshot, Resource::Indices).await)
 }
 
-async fn elastic_recovery() -> Result<impl warp::Reply, warp::Rejection> {
-    let warehouse = WAREHOUSE.get().unwrap().read().await;
-    let recovery = warehouse.recovery.read().await;
-    debug!("{:?}", &*recovery);
-    let recovery = serde_json::to_value(&*recovery).unwrap();
-    Ok(warp::reply::json(&recovery))
+async fn elastic_recovery(cluster: Option<String>) -> Result<impl warp::Reply, warp::Rejection> {
+    let warehouse = find_warehouse(cluster)?;
+    let warehouse = warehouse.read().await;
+    let snapshot = warehouse.snapshot().await;
+    let recovery = &*snapshot.recovery;
+    debug!("{:?}", recovery);
+    let recovery = serde_json::to_value(recovery).unwrap();
+    Ok(with_freshness(warp::reply::json(&recovery), &warehouse, &snapshot, Resource::Recovery).await)
 }
 
-async fn elastic_relocating() -> Result<impl warp::Reply, warp::Rejection> {
-    let warehouse = WAREHOUSE.get().unwrap().read().await;
-    let shards = warehouse.shards.read().await;
-    let unassigned = shards.iter().filter(|s| s.state != "STARTED").collect::<Vec<_>>();
+async fn elastic_relocating(cluster: Option<String>) -> Result<impl warp::Reply, warp::Rejection> {
+    let warehouse = find_warehouse(cluster)?;
+    let warehouse = warehouse.read().await;
+    let snapshot = warehouse.snapshot().await;
+    let unassigned = snapshot.shards.iter().filter(|s| s.state != "STARTED").collect::<Vec<_>>();
     let shards = serde_json::to_value(&unassigned).unwrap();
 
-    Ok(warp::reply::json(&shards))
+    Ok(with_freshness(warp::reply::json(&shards), &warehouse, &snapshot, Resource::Shards).await)
+}
+
+async fn elastic_unassigned(cluster: Option<String>) -> Result<impl warp::Reply, warp::Rejection> {
+    let warehouse = find_warehouse(cluster)?;
+    let warehouse = warehouse.read().await;
+    let snapshot = warehouse.snapshot().await;
+    let causes = unassigned_causes(&snapshot.shards);
+
+    Ok(with_freshness(warp::reply::json(&causes), &warehouse, &snapshot, Resource::Shards).await)
+}
+
+async fn elastic_disk(cluster: Option<String>) -> Result<impl warp::Reply, warp::Rejection> {
+    let warehouse = find_warehouse(cluster)?;
+    let warehouse = warehouse.read().await;
+    let snapshot = warehouse.snapshot().await;
+    let disk = disk_view(&snapshot);
+
+    Ok(with_freshness(warp::reply::json(&disk), &warehouse, &snapshot, Resource::Nodes).await)
+}
+
+async fn elastic_status(cluster: Option<String>) -> Result<impl warp::Reply, warp::Rejection> {
+    let warehouse = find_warehouse(cluster)?;
+    let warehouse = warehouse.read().await;
+    let status = warehouse.connection_status().await;
+    let status = serde_json::to_value(&status).unwrap();
+    Ok(warp::reply::json(&status))
+}
+
+#[derive(Deserialize)]
+struct SeekRequest {
+    position: Option<usize>,
+    at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct SpeedRequest {
+    speed: f64,
+}
+
+enum ReplayAction {
+    Status,
+    Pause,
+    Resume,
+    Seek(SeekRequest),
+    Speed(SpeedRequest),
+}
+
+fn bad_request(message: impl ToString) -> warp::reply::Response {
+    let body = serde_json::json!({ "error": message.to_string() });
+    warp::reply::with_status(warp::reply::json(&body), StatusCode::BAD_REQUEST).into_response()
+}
+
+fn apply_replay_action(control: &ReplayControl, action: ReplayAction) -> anyhow::Result<()> {
+    match action {
+        ReplayAction::Status => {}
+        ReplayAction::Pause => control.pause(),
+        ReplayAction::Resume => control.resume(),
+        ReplayAction::Seek(SeekRequest { position: Some(position), at: None }) => control.seek_position(position)?,
+        ReplayAction::Seek(SeekRequest { position: None, at: Some(at) }) => control.seek_time(at),
+        ReplayAction::Seek(_) => anyhow::bail!("Exactly one of position and at is required"),
+        ReplayAction::Speed(SpeedRequest { speed }) => control.set_speed(speed)?,
+    }
+
+    Ok(())
+}
+
+async fn elastic_replay(cluster: Option<String>, action: ReplayAction) -> Result<warp::reply::Response, warp::Rejection> {
+    let warehouse = find_warehouse(cluster)?;
+    let warehouse = warehouse.read().await;
+    let Some(control) = warehouse.source.replay() else {
+        return Err(warp::reject::not_found());
+    };
+
+    if let Err(e) = apply_replay_action(control, action) {
+        return Ok(bad_request(e));
+    }
+
+    Ok(warp::reply::json(&control.status()).into_response())
+}
+
+async fn elastic_snapshot(cluster: Option<String>) -> Result<impl warp::Reply, warp::Rejection> {
+    let snapshot = find_warehouse(cluster)?.read().await.snapshot().await;
+    Ok(warp::reply::json(&snapshot.info()))
+}
+
+async fn elastic_freshness(cluster: Option<String>) -> Result<impl warp::Reply, warp::Rejection> {
+    let warehouse = find_warehouse(cluster)?;
+    let warehouse = warehouse.read().await;
+    let snapshot = warehouse.snapshot().await;
+
+    let mut freshness = Vec::new();
+    for resource in Resource::ALL {
+        freshness.push(warehouse.freshness(&snapshot, resource).await);
+    }
+
+    Ok(warp::reply::json(&freshness))
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    since: Option<DateTime<Utc>>,
+    /// Comma separated, e.g. `node_joined,node_left`
+    types: Option<String>,
+}
+
+/// Parses a comma separated list of names, e.g. of event types.
+fn parse_list<T: DeserializeOwned>(list: &str) -> Result<Vec<T>, serde_json::Error> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| serde_json::from_value(Value::from(name)))
+        .collect()
+}
+
+async fn elastic_events(cluster: Option<String>, query: EventsQuery) -> Result<warp::reply::Response, warp::Rejection> {
+    let types = match query.types.as_deref().map(parse_list::<EventType>).transpose() {
+        Ok(types) => types,
+        Err(e) => return Ok(bad_request(e)),
+    };
+
+    let warehouse = find_warehouse(cluster)?;
+    let events = warehouse.read().await.events(query.since, types.as_deref()).await;
+    Ok(warp::reply::json(&events).into_response())
+}
+
+#[derive(Deserialize)]
+struct StreamQuery {
+    /// Comma separated resources to send on refresh, all by default
+    resources: Option<String>,
+    /// Comma separated event types to send, all by default
+    types: Option<String>,
+    /// Comma separated index names, where `*` matches anything and a
+    /// leading `-` excludes
+    index: Option<String>,
+}
+
+/// Index names or patterns like `logs-*,-logs-old-*`, as in the index
+/// parameter of Elasticsearch. Only exclusions match every other index.
+struct IndexPattern {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl IndexPattern {
+    fn new(patterns: &str) -> Self {
+        let (mut include, mut exclude) = (Vec::new(), Vec::new());
+        for pattern in patterns.split(',').map(str::trim).filter(|pattern| !pattern.is_empty()) {
+            match pattern.strip_prefix('-') {
+                Some(excluded) => exclude.push(excluded.to_string()),
+                None => include.push(pattern.to_string()),
+            }
+        }
+        IndexPattern { include, exclude }
+    }
+
+    fn matches(&self, index: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|pattern| wildcard_match(pattern, index)))
+            && !self.exclude.iter().any(|pattern| wildcard_match(pattern, index))
+    }
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = name.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+    let parts: Vec<_> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// What one stream has sent so far.
+struct StreamState {
+    receiver: tokio::sync::watch::Receiver<Arc<Snapshot>>,
+    warehouse: SharedWarehouse,
+    resources: Vec<Resource>,
+    types: Option<Vec<EventType>>,
+    indices: Option<IndexPattern>,
+    last_event_id: i32,
+    /// Snapshot the views in `sent` were taken from
+    previous: Option<Arc<Snapshot>>,
+    sent: HashMap<Resource, Value>,
+    pending: VecDeque<warp::sse::Event>,
+    /// An open stream counts as a watching client, however rarely it
+    /// receives.
+    _watcher: Watcher,
+}
+
+impl StreamState {
+    /// Selected resources of `snapshot`, narrowed down to the matching
+    /// indices, in the format of their REST endpoints. Recovery is keyed
+    /// by index as in `_recovery`.
+    fn view(&self, snapshot: &Snapshot, resource: Resource) -> Value {
+        let keep = |index: &str| self.indices.as_ref().is_none_or(|pattern| pattern.matches(index));
+        match resource {
+            Resource::Health => json!(*snapshot.cluster),
+            Resource::Indices => json!(snapshot.indices.iter().filter(|index| keep(index.name())).collect::<Vec<_>>()),
+            Resource::Recovery => snapshot.recovery.filter(keep).to_response(),
+            Resource::Shards => json!(snapshot.shards.iter().filter(|shard| keep(&shard.index)).collect::<Vec<_>>()),
+            Resource::Nodes => json!(*snapshot.nodes),
+        }
+    }
+
+    /// Queues a `snapshot` message with the resources that look different
+    /// since the last one, followed by an `event` message per new event.
+    /// Resources carried over from the previous snapshot aren't looked at.
+    async fn update(&mut self, snapshot: Arc<Snapshot>) {
+        let mut message = serde_json::Map::new();
+        for resource in self.resources.clone() {
+            if self.previous.as_deref().is_some_and(|previous| carried_over(previous, &snapshot, resource)) {
+                continue;
+            }
+            let view = self.view(&snapshot, resource);
+            if self.sent.get(&resource) != Some(&view) {
+                message.insert(resource.name().to_string(), view.clone());
+                self.sent.insert(resource, view);
+            }
+        }
+        if !message.is_empty() {
+            message.insert("snapshot".to_string(), json!(snapshot.info()));
+            self.pending.push_back(warp::sse::Event::default().event("snapshot").data(Value::Object(message).to_string()));
+        }
+        self.previous = Some(snapshot);
+
+        let (events, last_id) = self.warehouse.read().await.events_after(self.last_event_id, self.types.as_deref()).await;
+        self.last_event_id = last_id;
+        for event in events {
+            // Events about the whole cluster or a node pass the index filter
+            if event.index.as_deref().is_some_and(|index| self.indices.as_ref().is_some_and(|pattern| !pattern.matches(index))) {
+                continue;
+            }
+            self.pending.push_back(warp::sse::Event::default()
+                .event("event")
+                .id(event.id.to_string())
+                .data(json!(event).to_string()));
+        }
+    }
+}
+
+/// Whether `current` shares `resource` with `previous`, as it does when it
+/// wasn't refetched or came back the same.
+fn carried_over(previous: &Snapshot, current: &Snapshot, resource: Resource) -> bool {
+    match resource {
+        Resource::Health => Arc::ptr_eq(&previous.cluster, &current.cluster),
+        Resource::Indices => Arc::ptr_eq(&previous.indices, &current.indices),
+        Resource::Recovery => Arc::ptr_eq(&previous.recovery, &current.recovery),
+        Resource::Shards => Arc::ptr_eq(&previous.shards, &current.shards),
+        Resource::Nodes => Arc::ptr_eq(&previous.nodes, &current.nodes),
+    }
+}
+
+async fn elastic_stream(cluster: Option<String>, query: StreamQuery) -> Result<warp::reply::Response, warp::Rejection> {
+    let resources = match query.resources.as_deref().map(parse_list::<Resource>).transpose() {
+        Ok(resources) => resources.unwrap_or_else(|| Resource::ALL.to_vec()),
+        Err(e) => return Ok(bad_request(e)),
+    };
+    let types = match query.types.as_deref().map(parse_list::<EventType>).transpose() {
+        Ok(types) => types,
+        Err(e) => return Ok(bad_request(e)),
+    };
+
+    let warehouse = find_warehouse(cluster)?;
+    let (mut receiver, last_event_id) = {
+        let warehouse = warehouse.read().await;
+        (warehouse.subscribe(), warehouse.last_event_id().await)
+    };
+    receiver.mark_changed();
+
+    let state = StreamState {
+        receiver,
+        warehouse,
+        resources,
+        types,
+        indices: query.index.as_deref().map(IndexPattern::new),
+        last_event_id,
+        previous: None,
+        sent: HashMap::new(),
+        pending: VecDeque::new(),
+        _watcher: Watcher::start(),
+    };
+
+    let stream = futures::stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(event) = state.pending.pop_front() {
+                return Some((Ok::<_, Infallible>(event), state));
+            }
+
+            state.receiver.changed().await.ok()?;
+            let snapshot = state.receiver.borrow_and_update().clone();
+            if snapshot.generation > 0 {
+                state.update(snapshot).await;
+            }
+        }
+    });
+
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)).into_response())
+}
+
+#[derive(Deserialize, Default)]
+struct RefreshRequest {
+    resources: Option<Vec<Resource>>,
+}
+
+async fn elastic_refresh(cluster: Option<String>, body: warp::hyper::body::Bytes) -> Result<warp::reply::Response, warp::Rejection> {
+    let request = match body.is_empty() {
+        true => RefreshRequest::default(),
+        false => match serde_json::from_slice::<RefreshRequest>(&body) {
+            Ok(request) => request,
+            Err(e) => return Ok(bad_request(e)),
+        },
+    };
+    let resources = request.resources.unwrap_or_else(|| Resource::ALL.to_vec());
+
+    let warehouse = find_warehouse(cluster)?;
+    let result = warehouse.read().await.request_refresh(&resources).await;
+
+    match result {
+        Ok(info) => Ok(warp::reply::json(&info).into_response()),
+        Err(error) => Ok(warp::reply::with_status(warp::reply::json(&error), StatusCode::BAD_GATEWAY).into_response()),
+    }
+}
+
+// Serves `/elastic/<resource>` for the default cluster and
+// `/<cluster>/elastic/<resource>` for a named one.
+macro_rules! cluster_route {
+    ($($segment:literal)/+) => {
+        warp::path!("elastic" / $($segment)/+).map(|| None)
+            .or(warp::path!(String / "elastic" / $($segment)/+).map(cluster_name))
+            .unify()
+    };
+}
+
+/// Cluster names may contain spaces and other characters that arrive
+/// percent-encoded in the path, which warp leaves as is.
+fn cluster_name(segment: String) -> Option<String> {
+    Some(percent_decode_str(&segment).decode_utf8_lossy().into_owned())
 }
 
 pub fn build_routes() -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     let hello = warp::path!("hello").and_then(hello);
-    let elastic_health = warp::path!("elastic" / "health").and_then(elastic_health);
-    let elastic_indices = warp::path!("elastic" / "indices").and_then(elastic_indices);
-    let elastic_recovery = warp::path!("elastic" / "recovery").and_then(elastic_recovery);
-    let elastic_relocating = warp::path!("elastic" / "relocating").and_then(elastic_relocating);
+    let clusters = warp::path!("elastic" / "clusters").and_then(clusters);
+    let elastic_health = cluster_route!("health").and_then(elastic_health);
+    let elastic_indices = cluster_route!("indices").and_then(elastic_indices);
+    let elastic_recovery = cluster_route!("recovery").and_then(elastic_recovery);
+    let elastic_relocating = cluster_route!("relocating").and_then(elastic_relocating);
+    let elastic_unassigned = cluster_route!("unassigned").and_then(elastic_unassigned);
+    let elastic_disk = cluster_route!("disk").and_then(elastic_disk);
+    let elastic_status = cluster_route!("status").and_then(elastic_status);
+    let elastic_snapshot = cluster_route!("snapshot").and_then(elastic_snapshot);
+    let elastic_freshness = cluster_route!("freshness").and_then(elastic_freshness);
+    let elastic_events = cluster_route!("events").and(warp::query()).and_then(elastic_events);
+    let elastic_stream = cluster_route!("stream").and(warp::query()).and_then(elastic_stream);
+    let elastic_refresh = warp::post().and(cluster_route!("refresh")).and(warp::body::bytes()).and_then(elastic_refresh);
+
+    let replay_status = warp::get().and(cluster_route!("replay")).map(|cluster| (cluster, ReplayAction::Status));
+    let replay_pause = warp::post().and(cluster_route!("replay" / "pause")).map(|cluster| (cluster, ReplayAction::Pause));
+    let replay_resume = warp::post().and(cluster_route!("replay" / "resume")).map(|cluster| (cluster, ReplayAction::Resume));
+    let replay_seek = warp::post().and(cluster_route!("replay" / "seek")).and(warp::body::json())
+        .map(|cluster, seek| (cluster, ReplayAction::Seek(seek)));
+    let replay_speed = warp::post().and(cluster_route!("replay" / "speed")).and(warp::body::json())
+        .map(|cluster, speed| (cluster, ReplayAction::Speed(speed)));
+    let elastic_replay = replay_status
+        .or(replay_pause).unify()
+        .or(replay_resume).unify()
+        .or(replay_seek).unify()
+        .or(replay_speed).unify()
+        .untuple_one()
+        .and_then(elastic_replay);
 
     hello
+        .or(clusters)
         .or(elastic_health)
         .or(elastic_indices)
         .or(elastic_recovery)
         .or(elastic_relocating)
+        .or(elastic_unassigned)
+        .or(elastic_disk)
+        .or(elastic_status)
+        .or(elastic_snapshot)
+        .or(elastic_freshness)
+        .or(elastic_events)
+        .or(elastic_stream)
+        .or(elastic_refresh)
+        .or(elastic_replay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ElasticConfig;
+    use crate::elastic::data::{ClusterInfo, IndexInfo, NodeOutput, Recovery, ShallowShard};
+    use crate::elastic::error::ElasticError;
+    use crate::elastic::source::ClusterSource;
+    use async_trait::async_trait;
+
+    /// A cluster that can't be reached.
+    #[derive(Debug)]
+    struct Unreachable;
+
+    #[async_trait]
+    impl ClusterSource for Unreachable {
+        async fn health(&self) -> Result<ClusterInfo, ElasticError> {
+            unreachable!("refresh stops at prepare")
+        }
+
+        async fn indices(&self) -> Result<Vec<IndexInfo>, ElasticError> {
+            unreachable!("refresh stops at prepare")
+        }
+
+        async fn recovery(&self) -> Result<Recovery, ElasticError> {
+            unreachable!("refresh stops at prepare")
+        }
+
+        async fn shards(&self) -> Result<Vec<ShallowShard>, ElasticError> {
+            unreachable!("refresh stops at prepare")
+        }
+
+        async fn nodes(&self) -> Result<NodeOutput, ElasticError> {
+            unreachable!("refresh stops at prepare")
+        }
+
+        async fn prepare(&self) -> Result<(), ElasticError> {
+            Err(ElasticError::Timeout { url: "http://es:9200/".to_string() })
+        }
+    }
+
+    #[tokio::test]
+    async fn never_fetched_resources_answer_with_the_error() {
+        let elastic: ElasticConfig = serde_json::from_value(json!({})).unwrap();
+        let warehouse = Warehouse::with_source(Box::new(Unreachable), &elastic, None);
+        assert!(warehouse.refresh(&Resource::ALL).await.is_err());
+        let snapshot = warehouse.snapshot().await;
+
+        let response = with_freshness(warp::reply::json(&json!([])), &warehouse, &snapshot, Resource::Health).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(response.headers()["x-cortex-last-error"], "timeout");
+        let body = warp::hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let error: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error["kind"], "timeout");
+        assert_eq!(error["url"], "http://es:9200/");
+    }
+
+    #[tokio::test]
+    async fn decodes_cluster_names_in_paths() {
+        let route = cluster_route!("health");
+        let cluster = |path: &'static str| warp::test::request().path(path).filter(&route);
+
+        assert_eq!(cluster("/elastic/health").await.unwrap(), None);
+        assert_eq!(cluster("/prod/elastic/health").await.unwrap().as_deref(), Some("prod"));
+        assert_eq!(cluster("/eu%20west/elastic/health").await.unwrap().as_deref(), Some("eu west"));
+        assert_eq!(cluster("/m%C3%BCnchen/elastic/health").await.unwrap().as_deref(), Some("münchen"));
+    }
+
+    #[test]
+    fn wildcards_match_anywhere_in_the_name() {
+        assert!(wildcard_match("*", "logs-2024.06.01"));
+        assert!(wildcard_match("logs-*", "logs-2024.06.01"));
+        assert!(!wildcard_match("logs-*", "metrics-2024.06.01"));
+        assert!(wildcard_match("*-2024.06.01", "logs-2024.06.01"));
+        assert!(!wildcard_match("*-2024.06.01", "logs-2024.06.02"));
+        assert!(wildcard_match("logs-*.06.*", "logs-2024.06.01"));
+        assert!(wildcard_match("logs", "logs"));
+        assert!(!wildcard_match("logs", "logs-2024.06.01"));
+    }
+
+    #[test]
+    fn index_patterns_take_lists_and_exclusions() {
+        let pattern = IndexPattern::new("logs-*, metrics");
+        assert!(pattern.matches("logs-2024.06.01"));
+        assert!(pattern.matches("metrics"));
+        assert!(!pattern.matches("metrics-2024.06.01"));
+
+        let pattern = IndexPattern::new("logs-*,-logs-2024.05.*");
+        assert!(pattern.matches("logs-2024.06.01"));
+        assert!(!pattern.matches("logs-2024.05.01"));
+
+        let pattern = IndexPattern::new("-.*");
+        assert!(pattern.matches("logs-2024.06.01"));
+        assert!(!pattern.matches(".security"));
+    }
+
+    fn shard(index: &str, state: &str) -> ShallowShard {
+        ShallowShard {
+            index: index.to_string(),
+            shard: "0".to_string(),
+            prirep: "p".to_string(),
+            state: state.to_string(),
+            docs: None,
+            store: None,
+            ip: None,
+            node: Some("node-1".to_string()),
+            allocation_explanation: None,
+        }
+    }
+
+    fn next(previous: &Snapshot) -> Snapshot {
+        Snapshot {
+            generation: previous.generation + 1,
+            fetched_at: previous.fetched_at,
+            fetched: previous.fetched.clone(),
+            cluster: previous.cluster.clone(),
+            indices: previous.indices.clone(),
+            recovery: previous.recovery.clone(),
+            shards: previous.shards.clone(),
+            nodes: previous.nodes.clone(),
+            disk: previous.disk.clone(),
+        }
+    }
+
+    /// Names of the resources in each queued `snapshot` message.
+    fn sent_resources(state: &mut StreamState) -> Vec<Vec<String>> {
+        state.pending.drain(..)
+            .map(|event| {
+                let text = format!("{event}");
+                let data = text.lines().find_map(|line| line.strip_prefix("data:")).unwrap();
+                let message: serde_json::Map<String, Value> = serde_json::from_str(data).unwrap();
+                message.keys().filter(|key| *key != "snapshot").cloned().collect()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn streams_only_resources_that_were_replaced() {
+        let elastic: ElasticConfig = serde_json::from_value(json!({})).unwrap();
+        let warehouse = Warehouse::with_source(Box::new(Unreachable), &elastic, None);
+        let mut state = StreamState {
+            receiver: warehouse.subscribe(),
+            warehouse: Arc::new(tokio::sync::RwLock::new(warehouse)),
+            resources: vec![Resource::Shards, Resource::Nodes],
+            types: None,
+            indices: Some(IndexPattern::new("logs-*")),
+            last_event_id: 0,
+            previous: None,
+            sent: HashMap::new(),
+            pending: VecDeque::new(),
+            _watcher: Watcher::start(),
+        };
+
+        let first = Arc::new(Snapshot {
+            generation: 1,
+            shards: Arc::new(vec![shard("logs-1", "STARTED"), shard("metrics-1", "STARTED")]),
+            ..Snapshot::empty()
+        });
+        state.update(first.clone()).await;
+        assert_eq!(sent_resources(&mut state), [["nodes", "shards"]]);
+        assert_eq!(state.sent[&Resource::Shards].as_array().unwrap().len(), 1);
+
+        // Carried over: not looked at, even if what was sent looks different
+        state.sent.insert(Resource::Shards, Value::Null);
+        let second = Arc::new(next(&first));
+        state.update(second.clone()).await;
+        assert!(state.pending.is_empty());
+
+        // Replaced, but the same outside of the selected indices
+        let third = Arc::new(Snapshot {
+            shards: Arc::new(vec![shard("logs-1", "STARTED"), shard("metrics-1", "RELOCATING")]),
+            ..next(&second)
+        });
+        state.sent.insert(Resource::Shards, json!([shard("logs-1", "STARTED")]));
+        state.update(third.clone()).await;
+        assert!(state.pending.is_empty());
+
+        let fourth = Arc::new(Snapshot {
+            shards: Arc::new(vec![shard("logs-1", "RELOCATING"), shard("metrics-1", "RELOCATING")]),
+            ..next(&third)
+        });
+        state.update(fourth).await;
+        assert_eq!(sent_resources(&mut state), [["shards"]]);
+    }
 }
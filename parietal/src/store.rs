@@ -0,0 +1,650 @@
+use crate::config::PersistConfig;
+use crate::data::Snapshot;
+use crate::elastic::source::Resource;
+use crate::history::{seconds, SnapshotMetrics};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Bumped when records change in a way older versions can't read. Files
+/// with a newer version are skipped.
+const FORMAT_VERSION: u32 = 1;
+
+const FILE_SUFFIX: &str = ".jsonl.gz";
+const DOWNSAMPLED_SUFFIX: &str = ".downsampled.jsonl.gz";
+
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Snapshots waiting for the writer. More than this means the disk can't
+/// keep up, and further snapshots are skipped until it does.
+const WRITE_QUEUE: usize = 64;
+
+/// One line of a history file. Resources are only stored when they changed
+/// since the previous snapshot of the same file, so the first snapshot of
+/// every file holds all of them. Recovery and nodes are stored in the
+/// response format, as their API format can't be parsed back.
+#[derive(Serialize, Deserialize, Debug)]
+struct Record {
+    version: u32,
+    generation: u64,
+    fetched_at: DateTime<Utc>,
+    metrics: SnapshotMetrics,
+    /// Records between snapshots only carry metrics
+    #[serde(default)]
+    metrics_only: bool,
+    #[serde(default)]
+    fetched: HashMap<Resource, DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    health: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    indices: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    recovery: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    shards: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nodes: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    disk: Option<Value>,
+}
+
+/// The part of a record needed to list metrics, skipping the resources.
+#[derive(Deserialize)]
+struct MetricsRecord {
+    version: u32,
+    metrics: SnapshotMetrics,
+}
+
+impl Record {
+    fn metrics_only(snapshot: &Snapshot, fetched_at: DateTime<Utc>) -> Self {
+        Record {
+            version: FORMAT_VERSION,
+            generation: snapshot.generation,
+            fetched_at,
+            metrics: SnapshotMetrics::of(snapshot),
+            metrics_only: true,
+            fetched: HashMap::new(),
+            health: None,
+            indices: None,
+            recovery: None,
+            shards: None,
+            nodes: None,
+            disk: None,
+        }
+    }
+
+    /// The resources of `snapshot` that differ from `previous`.
+    fn delta(snapshot: &Snapshot, fetched_at: DateTime<Utc>, previous: Option<&Snapshot>) -> Self {
+        Record {
+            metrics_only: false,
+            fetched: snapshot.fetched.clone(),
+            health: delta(&snapshot.cluster, previous.map(|p| &p.cluster), to_value),
+            indices: delta(&snapshot.indices, previous.map(|p| &p.indices), to_value),
+            recovery: delta(&snapshot.recovery, previous.map(|p| &p.recovery), |recovery| recovery.to_response()),
+            shards: delta(&snapshot.shards, previous.map(|p| &p.shards), to_value),
+            nodes: delta(&snapshot.nodes, previous.map(|p| &p.nodes), |nodes| nodes.to_response()),
+            disk: delta(&snapshot.disk, previous.map(|p| &p.disk), to_value),
+            ..Record::metrics_only(snapshot, fetched_at)
+        }
+    }
+
+    /// Applies the record on top of the previous snapshot of its file.
+    fn apply(self, previous: &Snapshot) -> Result<Snapshot> {
+        Ok(Snapshot {
+            generation: self.generation,
+            fetched_at: Some(self.fetched_at),
+            fetched: self.fetched,
+            cluster: parse(self.health, &previous.cluster).context("health")?,
+            indices: parse(self.indices, &previous.indices).context("indices")?,
+            recovery: parse(self.recovery, &previous.recovery).context("recovery")?,
+            shards: parse(self.shards, &previous.shards).context("shards")?,
+            nodes: parse(self.nodes, &previous.nodes).context("nodes")?,
+            disk: parse(self.disk, &previous.disk).context("disk")?,
+        })
+    }
+}
+
+fn to_value<T: Serialize>(value: &T) -> Value {
+    serde_json::to_value(value).unwrap_or_default()
+}
+
+fn delta<T: PartialEq>(current: &Arc<T>, previous: Option<&Arc<T>>, to_value: impl Fn(&T) -> Value) -> Option<Value> {
+    match previous {
+        Some(previous) if Arc::ptr_eq(current, previous) || current == previous => None,
+        _ => Some(to_value(current)),
+    }
+}
+
+fn parse<T: DeserializeOwned>(value: Option<Value>, previous: &Arc<T>) -> Result<Arc<T>> {
+    match value {
+        Some(value) => Ok(Arc::new(serde_json::from_value(value)?)),
+        None => Ok(previous.clone()),
+    }
+}
+
+/// Where the next snapshot record goes, and what it is compared with.
+#[derive(Debug, Default)]
+struct Writer {
+    day: Option<NaiveDate>,
+    /// Last snapshot written to the file of `day`
+    last: Option<Arc<Snapshot>>,
+    last_metrics: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+struct HistoryFile {
+    day: NaiveDate,
+    path: PathBuf,
+    downsampled: bool,
+}
+
+impl HistoryFile {
+    /// Start of the day after the one the file holds.
+    fn end(&self) -> DateTime<Utc> {
+        self.day.succ_opt().unwrap_or(self.day).and_hms_opt(0, 0, 0).unwrap_or_default().and_utc()
+    }
+}
+
+/// Snapshot history of one cluster on disk, in a gzipped JSON lines file
+/// per day. Records are appended as separate gzip members, so a file stays
+/// readable up to the last complete record after a crash.
+#[derive(Debug)]
+pub struct HistoryStore {
+    dir: PathBuf,
+    retention: TimeDelta,
+    interval: TimeDelta,
+    metrics_interval: TimeDelta,
+    downsample_after: TimeDelta,
+    downsample_interval: TimeDelta,
+    /// Only locked by the writer thread, and by tests writing directly
+    writer: Mutex<Writer>,
+    queue: mpsc::Sender<Arc<Snapshot>>,
+}
+
+impl HistoryStore {
+    /// Opens the store in `dir`, starts its writer thread, and starts
+    /// removing and thinning out old days in the background.
+    pub fn open(dir: &Path, config: &PersistConfig) -> Result<Arc<Self>> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("data_dir: failed to create {}", dir.display()))?;
+        log::info!("Persisting snapshot history to {}", dir.display());
+
+        let (queue, receiver) = mpsc::channel(WRITE_QUEUE);
+        let store = Arc::new(HistoryStore {
+            dir: dir.to_path_buf(),
+            retention: seconds(config.retention_secs),
+            interval: seconds(config.interval_secs),
+            metrics_interval: seconds(config.metrics_interval_secs),
+            downsample_after: seconds(config.downsample_after_secs),
+            downsample_interval: seconds(config.downsample_interval_secs),
+            writer: Mutex::new(Writer::default()),
+            queue,
+        });
+
+        // Holds a weak reference, so the thread ends once the store is dropped
+        let writer = Arc::downgrade(&store);
+        std::thread::Builder::new()
+            .name("history-writer".to_string())
+            .spawn(move || write_queued(writer, receiver))
+            .context("Failed to start the history writer")?;
+
+        // Weak as well, so maintenance stops with the store
+        let maintained = Arc::downgrade(&store);
+        tokio::spawn(async move {
+            while let Some(store) = maintained.upgrade() {
+                let dir = store.dir.clone();
+                if let Ok(Err(e)) = tokio::task::spawn_blocking(move || store.maintain(Utc::now())).await {
+                    log::warn!("History maintenance in {} failed: {:#}", dir.display(), e);
+                }
+                tokio::time::sleep(MAINTENANCE_INTERVAL).await;
+            }
+        });
+
+        Ok(store)
+    }
+
+    /// Queues the snapshot for the writer thread, which writes it if it is
+    /// due, or just its metrics. Never blocks, so the disk can't hold up
+    /// refreshing; snapshots are skipped while the queue is full.
+    pub fn record(&self, snapshot: &Arc<Snapshot>) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.queue.try_send(snapshot.clone()) {
+            log::warn!("History writer for {} is behind, skipping a snapshot", self.dir.display());
+        }
+    }
+
+    /// Writes the snapshot if it is due, or just its metrics. Failures are
+    /// only logged, persisting must never break refreshing.
+    fn write(&self, snapshot: &Arc<Snapshot>) {
+        let Some(fetched_at) = snapshot.fetched_at else {
+            return;
+        };
+
+        let mut writer = self.writer.lock().unwrap();
+        let day = fetched_at.date_naive();
+        if writer.day != Some(day) {
+            *writer = Writer { day: Some(day), ..Writer::default() };
+        }
+
+        let snapshot_due = match writer.last.as_ref().and_then(|last| last.fetched_at) {
+            Some(last) => fetched_at - last >= self.interval,
+            None => true,
+        };
+        let metrics_due = match writer.last_metrics {
+            Some(last) => fetched_at - last >= self.metrics_interval,
+            None => true,
+        };
+
+        let record = match (snapshot_due, metrics_due) {
+            (true, _) => Record::delta(snapshot, fetched_at, writer.last.as_deref()),
+            (false, true) => Record::metrics_only(snapshot, fetched_at),
+            (false, false) => return,
+        };
+
+        match self.append(day, &record) {
+            Ok(()) => {
+                writer.last_metrics = Some(fetched_at);
+                if snapshot_due {
+                    writer.last = Some(snapshot.clone());
+                }
+            }
+            Err(e) => log::warn!("Failed to persist snapshot to {}: {:#}", self.dir.display(), e),
+        }
+    }
+
+    fn append(&self, day: NaiveDate, record: &Record) -> Result<()> {
+        let path = self.dir.join(format!("{}{}", day, FILE_SUFFIX));
+        let file = OpenOptions::new().create(true).append(true).open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        serde_json::to_writer(&mut encoder, record)?;
+        encoder.write_all(b"\n")?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    /// History files by day, oldest first.
+    fn files(&self) -> Result<Vec<HistoryFile>> {
+        let mut files = BTreeMap::new();
+
+        for entry in std::fs::read_dir(&self.dir).with_context(|| format!("Failed to read {}", self.dir.display()))? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+
+            let (day, downsampled) = match (name.strip_suffix(DOWNSAMPLED_SUFFIX), name.strip_suffix(FILE_SUFFIX)) {
+                (Some(day), _) => (day, true),
+                (None, Some(day)) => (day, false),
+                (None, None) => continue,
+            };
+            let Ok(day) = day.parse::<NaiveDate>() else {
+                continue;
+            };
+
+            // A crash while downsampling can leave both, the downsampled one is complete
+            let file = HistoryFile { day, path: path.clone(), downsampled };
+            match files.get(&day) {
+                Some(HistoryFile { downsampled: true, .. }) => {}
+                _ => {
+                    files.insert(day, file);
+                }
+            }
+        }
+
+        Ok(files.into_values().collect())
+    }
+
+    /// Reads the records of a file. Reading stops at the first broken
+    /// record, which is usually one cut short by a crash.
+    fn read<T: DeserializeOwned>(&self, path: &Path, version: impl Fn(&T) -> u32, mut visit: impl FnMut(T) -> Result<bool>) -> Result<()> {
+        let reader = BufReader::new(MultiGzDecoder::new(File::open(path)?));
+
+        for line in reader.lines() {
+            let record = match line.map_err(anyhow::Error::from).and_then(|line| Ok(serde_json::from_str::<T>(&line)?)) {
+                Ok(record) => record,
+                Err(e) => {
+                    log::warn!("Stopped reading {} at a broken record: {}", path.display(), e);
+                    break;
+                }
+            };
+
+            if version(&record) > FORMAT_VERSION {
+                log::warn!("Skipping {}, it was written by a newer version", path.display());
+                break;
+            }
+            if !visit(record)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The snapshots of a file, oldest first, until `visit` returns false.
+    fn snapshots(&self, path: &Path, mut visit: impl FnMut(&Arc<Snapshot>) -> bool) -> Result<()> {
+        let mut current = Arc::new(Snapshot::empty());
+
+        self.read(path, |record: &Record| record.version, |record| {
+            if record.metrics_only {
+                return Ok(true);
+            }
+            let fetched_at = record.fetched_at;
+            current = Arc::new(record.apply(&current).with_context(|| format!("Broken record from {}", fetched_at))?);
+            Ok(visit(&current))
+        })
+    }
+
+    /// The last snapshot taken at or before `at`.
+    pub fn snapshot_at(&self, at: DateTime<Utc>) -> Result<Option<Arc<Snapshot>>> {
+        let files = self.files()?;
+
+        for file in files.iter().rev().filter(|file| file.day <= at.date_naive()) {
+            let mut found = None;
+            self.snapshots(&file.path, |snapshot| {
+                if snapshot.fetched_at.is_some_and(|fetched_at| fetched_at > at) {
+                    return false;
+                }
+                found = Some(snapshot.clone());
+                true
+            })?;
+
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Metrics recorded between `from` and `to`.
+    pub fn metrics(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<SnapshotMetrics>> {
+        let mut metrics = Vec::new();
+
+        for file in self.files()?.iter().filter(|file| from.date_naive() <= file.day && file.day <= to.date_naive()) {
+            self.read(&file.path, |record: &MetricsRecord| record.version, |record| {
+                let timestamp = record.metrics.timestamp;
+                if from <= timestamp && timestamp <= to {
+                    metrics.push(record.metrics);
+                }
+                Ok(timestamp <= to)
+            })?;
+        }
+
+        Ok(metrics)
+    }
+
+    /// Time of the oldest stored snapshot.
+    pub fn oldest(&self) -> Result<Option<DateTime<Utc>>> {
+        for file in self.files()? {
+            let mut oldest = None;
+            self.read(&file.path, |record: &MetricsRecord| record.version, |record| {
+                oldest = Some(record.metrics.timestamp);
+                Ok(false)
+            })?;
+
+            if oldest.is_some() {
+                return Ok(oldest);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Removes days past retention and thins out days past
+    /// `downsample_after`.
+    fn maintain(&self, now: DateTime<Utc>) -> Result<()> {
+        for file in self.files()? {
+            let age = now - file.end();
+
+            if age > self.retention {
+                log::info!("Removing expired history {}", file.path.display());
+                std::fs::remove_file(&file.path)
+                    .with_context(|| format!("Failed to remove {}", file.path.display()))?;
+            } else if age > self.downsample_after && !file.downsampled {
+                self.downsample(&file).with_context(|| format!("Failed to downsample {}", file.path.display()))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites a day keeping one snapshot per `downsample_interval`, and
+    /// the metrics of all the others.
+    fn downsample(&self, file: &HistoryFile) -> Result<()> {
+        let mut records = Vec::new();
+        let mut kept: Option<Arc<Snapshot>> = None;
+        let mut current = Arc::new(Snapshot::empty());
+
+        self.read(&file.path, |record: &Record| record.version, |record| {
+            if record.metrics_only {
+                records.push(record);
+                return Ok(true);
+            }
+
+            let fetched_at = record.fetched_at;
+            current = Arc::new(record.apply(&current).with_context(|| format!("Broken record from {}", fetched_at))?);
+
+            let due = match kept.as_ref().and_then(|kept| kept.fetched_at) {
+                Some(last) => fetched_at - last >= self.downsample_interval,
+                None => true,
+            };
+            match due {
+                true => {
+                    records.push(Record::delta(&current, fetched_at, kept.as_deref()));
+                    kept = Some(current.clone());
+                }
+                false => records.push(Record::metrics_only(&current, fetched_at)),
+            }
+            Ok(true)
+        })?;
+
+        if records.is_empty() {
+            bail!("no readable records");
+        }
+
+        let path = self.dir.join(format!("{}{}", file.day, DOWNSAMPLED_SUFFIX));
+        let temporary = path.with_extension("tmp");
+        let mut encoder = GzEncoder::new(File::create(&temporary)?, Compression::default());
+        for record in &records {
+            serde_json::to_writer(&mut encoder, record)?;
+            encoder.write_all(b"\n")?;
+        }
+        encoder.finish()?.sync_all()?;
+
+        std::fs::rename(&temporary, &path)?;
+        std::fs::remove_file(&file.path)?;
+        log::info!("Downsampled history {}", path.display());
+        Ok(())
+    }
+}
+
+fn write_queued(store: Weak<HistoryStore>, mut receiver: mpsc::Receiver<Arc<Snapshot>>) {
+    while let Some(snapshot) = receiver.blocking_recv() {
+        match store.upgrade() {
+            Some(store) => store.write(&snapshot),
+            None => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ElasticConfig, SimulatorConfig};
+    use crate::data::Warehouse;
+    use crate::elastic::data::NodeOutput;
+    use crate::elastic::simulator::SimulatorSource;
+    use chrono::TimeZone;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("parietal-store-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn persist_config() -> PersistConfig {
+        PersistConfig {
+            // Nothing is removed or thinned out behind the tests' back
+            retention_secs: u64::MAX / 2,
+            interval_secs: 0,
+            metrics_interval_secs: 0,
+            downsample_after_secs: u64::MAX / 2,
+            downsample_interval_secs: 30,
+        }
+    }
+
+    /// A simulated snapshot with recoveries going on.
+    async fn simulated() -> Arc<Snapshot> {
+        let config = SimulatorConfig { nodes: 6, indices: 10, seed: Some(7), ..SimulatorConfig::default() };
+        let elastic: ElasticConfig = serde_json::from_value(serde_json::json!({})).unwrap();
+        let warehouse = Warehouse::with_source(Box::new(SimulatorSource::new(&config).unwrap()), &elastic, None);
+        for _ in 0..3 {
+            warehouse.refresh(&Resource::ALL).await.unwrap();
+        }
+        warehouse.snapshot().await
+    }
+
+    fn taken_at(snapshot: &Snapshot, generation: u64, fetched_at: DateTime<Utc>) -> Arc<Snapshot> {
+        Arc::new(Snapshot {
+            generation,
+            fetched_at: Some(fetched_at),
+            fetched: snapshot.fetched.clone(),
+            cluster: snapshot.cluster.clone(),
+            indices: snapshot.indices.clone(),
+            recovery: snapshot.recovery.clone(),
+            shards: snapshot.shards.clone(),
+            nodes: snapshot.nodes.clone(),
+            disk: snapshot.disk.clone(),
+        })
+    }
+
+    #[tokio::test]
+    async fn snapshots_read_back_as_written() {
+        let dir = temp_dir("round-trip");
+        let store = HistoryStore::open(&dir, &persist_config()).unwrap();
+        let snapshot = simulated().await;
+        assert!(!snapshot.recovery.is_empty());
+        assert!(!snapshot.disk.nodes.is_empty());
+
+        let fetched_at = Utc::now();
+        store.write(&taken_at(&snapshot, 1, fetched_at));
+        // Nothing changed, so the second record only holds the metrics
+        store.write(&taken_at(&snapshot, 2, fetched_at + TimeDelta::seconds(1)));
+
+        let read = store.snapshot_at(fetched_at + TimeDelta::seconds(1)).unwrap().unwrap();
+        assert_eq!(read.generation, 2);
+        assert_eq!(read.cluster, snapshot.cluster);
+        assert_eq!(read.indices, snapshot.indices);
+        assert_eq!(read.recovery, snapshot.recovery);
+        assert_eq!(read.shards, snapshot.shards);
+        assert_eq!(read.nodes, snapshot.nodes);
+        assert_eq!(read.disk, snapshot.disk);
+
+        assert_eq!(store.snapshot_at(fetched_at).unwrap().unwrap().generation, 1);
+        assert!(store.snapshot_at(fetched_at - TimeDelta::seconds(1)).unwrap().is_none());
+        assert_eq!(store.oldest().unwrap(), Some(fetched_at));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recorded_snapshots_are_written_in_the_background() {
+        let dir = temp_dir("queue");
+        let store = HistoryStore::open(&dir, &persist_config()).unwrap();
+        let fetched_at = Utc::now();
+        let snapshot = simulated().await;
+        store.record(&taken_at(&snapshot, 1, fetched_at));
+
+        let mut written = None;
+        for _ in 0..100 {
+            written = store.snapshot_at(fetched_at).unwrap();
+            if written.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(written.map(|snapshot| snapshot.generation), Some(1));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn background_tasks_let_go_of_a_dropped_store() {
+        let dir = temp_dir("dropped");
+        let store = HistoryStore::open(&dir, &persist_config()).unwrap();
+        let weak = Arc::downgrade(&store);
+        drop(store);
+
+        // The first maintenance pass may still be running
+        for _ in 0..100 {
+            if weak.upgrade().is_none() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(weak.upgrade().is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn nodes_sharing_a_name_are_all_kept() {
+        let snapshot = simulated().await;
+        let mut response = snapshot.nodes.to_response();
+        for node in response["nodes"].as_object_mut().unwrap().values_mut() {
+            node["name"] = "same".into();
+        }
+
+        let nodes: NodeOutput = serde_json::from_value(response).unwrap();
+        let read: NodeOutput = serde_json::from_value(nodes.to_response()).unwrap();
+        assert_eq!(read.nodes.len(), snapshot.nodes.nodes.len());
+        assert_eq!(read, nodes);
+    }
+
+    #[tokio::test]
+    async fn downsampling_keeps_one_snapshot_per_interval_and_all_metrics() {
+        let dir = temp_dir("downsample");
+        let store = HistoryStore::open(&dir, &persist_config()).unwrap();
+        let snapshot = simulated().await;
+
+        let start = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        for i in 0..5 {
+            store.write(&taken_at(&snapshot, i + 1, start + TimeDelta::seconds(10 * i as i64)));
+        }
+
+        let files = store.files().unwrap();
+        assert_eq!(files.len(), 1);
+        store.downsample(&files[0]).unwrap();
+
+        let files = store.files().unwrap();
+        assert_eq!(files.len(), 1);
+        assert!(files[0].downsampled);
+        assert!(!dir.join(format!("2024-03-01{}", FILE_SUFFIX)).exists());
+
+        // Kept at 0s and 30s, the others only left their metrics
+        let generation_at = |seconds| store.snapshot_at(start + TimeDelta::seconds(seconds)).unwrap().unwrap().generation;
+        assert_eq!(generation_at(20), 1);
+        assert_eq!(generation_at(40), 4);
+        assert_eq!(store.metrics(start, start + TimeDelta::seconds(40)).unwrap().len(), 5);
+
+        let read = store.snapshot_at(start + TimeDelta::seconds(40)).unwrap().unwrap();
+        assert_eq!(read.shards, snapshot.shards);
+        assert_eq!(read.nodes, snapshot.nodes);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}